@@ -1,5 +1,5 @@
 use actix_web::{http::StatusCode, HttpResponse};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::to_string_pretty;
 use std::fmt::{Display, Formatter};
 
@@ -11,6 +11,14 @@ pub struct BibResponseBody {
     pub reason: String,
 }
 
+/// A problem found in a row of an imported file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImportError {
+    pub row: usize,
+    pub field: String,
+    pub message: String,
+}
+
 #[derive(Debug, Serialize)]
 pub enum BibErrorResponse {
     NotImplemented,
@@ -34,6 +42,9 @@ pub enum BibErrorResponse {
     InputLengthTooLong(),
     ItemAlreadyExists(u32),
     BarcodeDigitsOutOfRange,
+    BookDuplicated(Vec<(u32, u32, String)>),
+    ImportFailed(Vec<ImportError>),
+    CheckDigitMismatch(String),
 }
 
 impl Display for BibErrorResponse {
@@ -203,6 +214,43 @@ impl actix_web::error::ResponseError for BibErrorResponse {
                     message: String::from("バーコード桁数が設定範囲外です"),
                     reason: String::new(),
                 }),
+            BibErrorResponse::BookDuplicated(duplicates) => {
+                let reason: Vec<String> = duplicates
+                    .iter()
+                    .map(|(id, registered_id, title)| {
+                        format!("ID{} -> ID{}「{}」", id, registered_id, title)
+                    })
+                    .collect();
+                HttpResponse::build(self.status_code()).json(BibResponseBody {
+                    success: false,
+                    errcode: 120,
+                    message: String::from("同じ図書が既に登録されています"),
+                    reason: reason.join("\n"),
+                })
+            }
+            BibErrorResponse::ImportFailed(errors) => {
+                let reason: Vec<String> = errors
+                    .iter()
+                    .map(|error| match error.field.as_str() {
+                        "" => format!("{}行目: {}", error.row, error.message),
+                        field => format!("{}行目 {}: {}", error.row, field, error.message),
+                    })
+                    .collect();
+                HttpResponse::build(self.status_code()).json(BibResponseBody {
+                    success: false,
+                    errcode: 121,
+                    message: String::from("取込データに誤りがあります"),
+                    reason: reason.join("\n"),
+                })
+            }
+            BibErrorResponse::CheckDigitMismatch(barcode) => {
+                HttpResponse::build(self.status_code()).json(BibResponseBody {
+                    success: false,
+                    errcode: 122,
+                    message: String::from("バーコードのチェックディジットが正しくありません"),
+                    reason: barcode.to_string(),
+                })
+            }
             BibErrorResponse::SystemError(reason) => {
                 HttpResponse::build(self.status_code()).json(BibResponseBody {
                     success: false,
@@ -192,3 +192,85 @@ function constructUrlFromForm(formId, baseUrl) {
 
   return baseUrl + queryString;
 }
+
+// Fetches the custom field definitions of the tenant
+// callback: Function called with {user_fields, book_fields}
+function fetchCustomFields(callback) {
+  fetch("/setting/all", {
+    method: "GET",
+  })
+    .then((response) => response.json())
+    .then((data) => {
+      if (data["success"] == true) {
+        callback(data["custom_field_setting"]);
+      }
+    })
+    .catch((error) => {
+      console.error("There was a problem with the fetch operation:", error);
+    });
+}
+
+// Appends the inputs named "custom_<field name>" to a container
+function appendCustomFieldInputs(containerId, fields) {
+  const container = document.getElementById(containerId);
+  fields.forEach((field, i) => {
+    const id = `${containerId}_${i}`;
+    const label = document.createElement("label");
+    label.htmlFor = id;
+    label.innerHTML = "<b></b>";
+    label.firstChild.textContent = field.name;
+
+    let input;
+    if (field.field_type == "choice") {
+      input = document.createElement("select");
+      ["", ...field.choices].forEach((choice) => {
+        const option = document.createElement("option");
+        option.value = choice;
+        option.textContent = choice;
+        input.appendChild(option);
+      });
+    } else {
+      input = document.createElement("input");
+      input.type = "text";
+      if (field.field_type == "number") {
+        input.placeholder = "半角数字";
+      } else if (field.field_type == "date") {
+        input.placeholder = "半角 yyyy/mm/dd";
+      }
+    }
+    input.id = id;
+    input.name = "custom_" + field.name;
+
+    const td = document.createElement("td");
+    td.appendChild(label);
+    td.appendChild(input);
+    container.appendChild(td);
+  });
+}
+
+// Sets the values of a record to the inputs made by appendCustomFieldInputs
+function setCustomFieldValues(containerId, fields, values) {
+  fields.forEach((field, i) => {
+    const value = values[field.name];
+    document.getElementById(`${containerId}_${i}`).value =
+      value === undefined ? "" : value;
+  });
+}
+
+// Appends the custom fields to a select to choose a search target
+function appendCustomFieldOptions(selectId, fields) {
+  const select = document.getElementById(selectId);
+  fields.forEach((field) => {
+    const option = document.createElement("option");
+    option.value = field.name;
+    option.textContent = field.name;
+    select.appendChild(option);
+  });
+}
+
+// Formats the custom field values of a record as "name: value, ..."
+function formatCustomFields(values) {
+  return Object.entries(values)
+    .map(([name, value]) => `${name}: ${value}`)
+    .join(", ");
+}
@@ -1,15 +1,17 @@
+use crate::error::ImportError;
 use async_trait::async_trait;
 use bson::Document;
-use chrono::{DateTime, Duration};
+use chrono::{DateTime, Duration, NaiveDate};
 use chrono_tz::Tz;
 use futures::stream::TryStreamExt;
 use log::info;
 use mongodb::bson::doc;
 use mongodb::options::*;
 use mongodb::Database;
-use mongodb::{Collection, IndexModel};
+use mongodb::{Collection, Cursor, IndexModel};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::error;
 use std::io::{Error, ErrorKind};
 use std::str::FromStr;
@@ -73,6 +75,30 @@ pub async fn update_item<T: Entity>(db: &Database, item: &T) -> Result<(), Box<d
     item.update(db).await
 }
 
+/// Applies the update operators to the item with the ID, so that only the
+/// fields they name are changed instead of the whole item being written.
+pub async fn update_item_fields<T>(
+    db: &Database,
+    item: &T,
+    id: u32,
+    update: Document,
+) -> Result<(), Box<dyn error::Error>>
+where
+    T: Entity,
+{
+    let collection = db.collection::<Document>(item.get_collection_name());
+    let result = collection
+        .update_one(doc! { "id": id }, update, None)
+        .await?;
+    if result.matched_count == 0 {
+        return Err(Box::new(Error::new(
+            ErrorKind::NotFound,
+            format!("ID{} is not registered", id),
+        )));
+    }
+    Ok(())
+}
+
 pub async fn delete_item<T: Entity>(db: &Database, item: &T) -> Result<(), Box<dyn error::Error>> {
     item.delete(db).await
 }
@@ -91,6 +117,41 @@ pub async fn search_items<T: Entity>(
     item.search(db).await
 }
 
+/// Returns no items instead of an error when none are found, so that the
+/// errors of the DB are told apart from an empty collection.
+pub async fn search_items_or_empty<T: Entity>(
+    db: &Database,
+    item: &T,
+) -> Result<Vec<T>, Box<dyn error::Error>> {
+    match item.search(db).await {
+        Err(e) if is_not_found(e.as_ref()) => Ok(vec![]),
+        res => res,
+    }
+}
+
+/// Tells whether the error is of a search that found no items.
+pub fn is_not_found(e: &(dyn error::Error + 'static)) -> bool {
+    e.downcast_ref::<Error>()
+        .is_some_and(|e| e.kind() == ErrorKind::NotFound)
+}
+
+/// Searches the items with the IDs in the order of the IDs. The IDs that are
+/// not registered are left out.
+pub async fn search_items_by_ids<T>(
+    db: &Database,
+    item: &T,
+    ids: &[u32],
+) -> Result<Vec<T>, Box<dyn error::Error>>
+where
+    T: Entity + DeserializeOwned + Unpin + Send + Sync + Serialize + std::fmt::Debug,
+{
+    let collection = item.get_collection(db);
+    match collection.search(doc! { "id": { "$in": ids } }).await {
+        Err(e) if is_not_found(e.as_ref()) => Ok(vec![]),
+        res => res,
+    }
+}
+
 pub async fn search_items_range<T: Entity>(
     db: &Database,
     item: &T,
@@ -100,6 +161,47 @@ pub async fn search_items_range<T: Entity>(
     item.search_range(db, start_id, end_id).await
 }
 
+/// Inserts the items into the collection, which may not be the one of the
+/// items, such as a staging collection.
+pub async fn insert_many_items_into<T>(
+    collection: &Collection<T>,
+    items: &[T],
+) -> Result<(), Box<dyn error::Error>>
+where
+    T: Serialize + Send + Sync,
+{
+    if !items.is_empty() {
+        collection.insert_many(items, None).await?;
+    }
+    Ok(())
+}
+
+/// Opens a cursor over all the items in the order of the IDs, so that they
+/// are read as they are used instead of at once.
+pub async fn stream_items<T>(db: &Database, item: &T) -> Result<Cursor<T>, Box<dyn error::Error>>
+where
+    T: Entity + DeserializeOwned + Unpin + Send + Sync,
+{
+    stream_items_by(db, item, doc! {}).await
+}
+
+/// Opens a cursor over the items matching the query in the order of the IDs.
+pub async fn stream_items_by<T>(
+    db: &Database,
+    item: &T,
+    mut query: Document,
+) -> Result<Cursor<T>, Box<dyn error::Error>>
+where
+    T: Entity + DeserializeOwned + Unpin + Send + Sync,
+{
+    if !query.contains_key("id") {
+        query.insert("id", doc! { "$gt": 0 });
+    }
+    let options = FindOptions::builder().sort(doc! { "id": 1 }).build();
+    let collection = item.get_collection(db);
+    Ok(collection.find(query, options).await?)
+}
+
 pub async fn search_item<T: Entity>(db: &Database, item: &T) -> Result<T, Box<dyn error::Error>> {
     let mut items = item.search(db).await?;
     if items.len() == 1 {
@@ -125,6 +227,178 @@ pub fn atoi(a: &str) -> Result<u32, Box<dyn error::Error>> {
     Ok(i)
 }
 
+/// Converts an ISBN-10 or ISBN-13, with or without hyphens, into the canonical
+/// hyphen-less ISBN-13. An empty string is accepted as "no ISBN".
+pub fn normalize_isbn(isbn: &str) -> Result<String, Box<dyn error::Error>> {
+    let digits: Vec<char> = isbn
+        .chars()
+        .filter_map(|c| match c {
+            '0'..='9' => Some(c),
+            '０'..='９' => char::from_u32(c as u32 - '０' as u32 + '0' as u32),
+            'X' | 'x' | 'Ｘ' | 'ｘ' => Some('X'),
+            '-' | ' ' | '－' | 'ー' | '　' => None,
+            _ => Some('?'),
+        })
+        .collect();
+    if digits.is_empty() {
+        return Ok(String::new());
+    }
+
+    let invalid = || -> Box<dyn error::Error> { format!("Invalid ISBN: {}", isbn).into() };
+    let value = |c: &char| c.to_digit(10).ok_or_else(invalid);
+
+    match digits.len() {
+        10 => {
+            let mut sum = 0;
+            for (i, c) in digits.iter().enumerate() {
+                let v = if i == 9 && *c == 'X' { 10 } else { value(c)? };
+                sum += v * (10 - i as u32);
+            }
+            if sum % 11 != 0 {
+                return Err(invalid());
+            }
+            let mut isbn13: String = "978".to_string();
+            isbn13.extend(&digits[..9]);
+            let check = isbn13_check_digit(&isbn13).ok_or_else(invalid)?;
+            isbn13.push(check);
+            Ok(isbn13)
+        }
+        13 => {
+            let isbn13: String = digits.iter().collect();
+            if !isbn13.starts_with("978") && !isbn13.starts_with("979") {
+                return Err(invalid());
+            }
+            let check = isbn13_check_digit(&isbn13[..12]).ok_or_else(invalid)?;
+            if digits[12] != check {
+                return Err(invalid());
+            }
+            Ok(isbn13)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn isbn13_check_digit(first12: &str) -> Option<char> {
+    let mut sum = 0;
+    for (i, c) in first12.chars().enumerate() {
+        let v = c.to_digit(10)?;
+        sum += if i % 2 == 0 { v } else { v * 3 };
+    }
+    char::from_digit((10 - sum % 10) % 10, 10)
+}
+
+/// Converts the full-width alphanumerics and spaces to half-width ones.
+fn to_half_width(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '！'..='～' => char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+            '　' => ' ',
+            _ => c,
+        })
+        .collect()
+}
+
+/// Normalizes a series name for grouping, so that e.g. "ＯＮＥ ＰＩＥＣＥ"
+/// and "One  Piece" fall into the same series.
+pub fn normalize_series(series: &str) -> String {
+    to_half_width(series)
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Parses the volume number from strings such as "3", "第３巻" or "十二".
+pub fn parse_volume(volume: &str) -> Option<u32> {
+    let volume = to_half_width(volume);
+    let digits: String = volume
+        .chars()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    if !digits.is_empty() {
+        return digits.parse().ok();
+    }
+
+    let kanji: Vec<u32> = volume
+        .chars()
+        .skip_while(|c| kanji_digit(*c).is_none())
+        .map_while(kanji_digit)
+        .collect();
+    match kanji.as_slice() {
+        [] => None,
+        [10] => Some(10),
+        [10, n] => Some(10 + n),
+        [n, 10] => Some(n * 10),
+        [n, 10, m] => Some(n * 10 + m),
+        [n] => Some(*n),
+        _ => None,
+    }
+}
+
+fn kanji_digit(c: char) -> Option<u32> {
+    "〇一二三四五六七八九十"
+        .chars()
+        .position(|k| k == c)
+        .map(|n| n as u32)
+}
+
+/// Normalizes an ISBN typed into a search field. A complete ISBN is turned
+/// into the canonical ISBN-13, and a part of one is kept as its digits.
+pub fn normalize_isbn_query(isbn: &str) -> Result<String, Box<dyn error::Error>> {
+    if let Ok(isbn13) = normalize_isbn(isbn) {
+        return Ok(isbn13);
+    }
+    let fragment: String = to_half_width(isbn)
+        .chars()
+        .filter(|c| !matches!(c, '-' | ' ' | 'ー'))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if !fragment.chars().all(|c| c.is_ascii_digit() || c == 'X') {
+        return Err(format!("Invalid ISBN: {}", isbn).into());
+    }
+    Ok(fragment)
+}
+
+/// Returns the regex patterns matching the stored forms of a canonical ISBN-13,
+/// i.e. the ISBN-13 and, for the 978 prefix, the ISBN-10 with any hyphenation.
+/// A part of an ISBN matches the stored ISBNs that contain it.
+fn isbn_search_patterns(isbn13: &str) -> Vec<String> {
+    let join = |digits: &str| {
+        let body: Vec<String> = digits
+            .chars()
+            .map(|c| match c {
+                'X' => "[Xx]".to_string(),
+                c => c.to_string(),
+            })
+            .collect();
+        body.join("[- ]?")
+    };
+    let pattern = |digits: &str| format!("^{}$", join(digits));
+    if normalize_isbn(isbn13).ok().as_deref() != Some(isbn13) {
+        return vec![join(isbn13)];
+    }
+
+    let mut patterns = vec![pattern(isbn13)];
+    if isbn13.len() == 13 && isbn13.starts_with("978") {
+        let first9 = &isbn13[3..12];
+        let mut sum = 0;
+        for (i, c) in first9.chars().enumerate() {
+            sum += c.to_digit(10).unwrap_or(0) * (10 - i as u32);
+        }
+        let check = match (11 - sum % 11) % 11 {
+            10 => "[Xx]".to_string(),
+            n => n.to_string(),
+        };
+        patterns.push(format!(
+            "{}[- ]?{}$",
+            pattern(first9).trim_end_matches('$'),
+            check
+        ));
+    }
+    patterns
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct User {
     pub id: u32,
@@ -137,6 +411,8 @@ pub struct User {
     pub borrowed_count: u32,
     pub reserved: String,
     pub borrowed_books: Vec<BorrowedBook>,
+    #[serde(default)]
+    pub custom_fields: BTreeMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -191,6 +467,9 @@ pub struct SystemUser {
     pub dbname: String,
     pub plan: MonthlyPlan,
     pub subscription_id: String,
+    // Printed on the library cards
+    #[serde(default)]
+    pub library_name: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -203,7 +482,7 @@ pub struct BorrowedBook {
     pub location: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Book {
     pub id: u32,
     pub title: String,
@@ -229,6 +508,8 @@ pub struct Book {
     pub borrowed_count: u32,
     pub owner_id: Option<u32>,
     pub return_deadline: Option<String>,
+    #[serde(default)]
+    pub custom_fields: BTreeMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -238,6 +519,19 @@ pub struct RentalSetting {
     pub num_days: u32,
 }
 
+/// The check digit appended to the IDs in the barcodes. The number of digits
+/// in `BarcodeSetting` does not include it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckDigit {
+    #[default]
+    None,
+    // Weights 3 and 1 from the right as in JAN codes
+    Mod10,
+    // Weights 2 to 7 from the right, a remainder of 0 or 1 gives 0
+    Mod11,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BarcodeSetting {
     pub id: u32,
@@ -245,6 +539,26 @@ pub struct BarcodeSetting {
     pub user_keta_max: u32,
     pub book_keta_min: u32,
     pub book_keta_max: u32,
+    #[serde(default)]
+    pub check_digit: CheckDigit,
+    // The prefixes and the ID range tell user cards from book labels so
+    // that they can be scanned into a single field
+    #[serde(default)]
+    pub user_prefix: String,
+    #[serde(default)]
+    pub book_prefix: String,
+    // The range is not used if the maximum is 0
+    #[serde(default)]
+    pub user_id_min: u32,
+    #[serde(default)]
+    pub user_id_max: u32,
+}
+
+/// Whether a scanned barcode is a user card or a book label.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BarcodeKind {
+    User,
+    Book,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -258,6 +572,94 @@ pub struct SystemSetting {
     pub max_parallel_registrations: u32,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CustomFieldType {
+    Text,
+    Number,
+    Date,
+    Choice,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CustomField {
+    pub name: String,
+    pub field_type: CustomFieldType,
+    pub choices: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CustomFieldSetting {
+    pub id: u32,
+    pub user_fields: Vec<CustomField>,
+    pub book_fields: Vec<CustomField>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: serde_json::Value,
+    pub after: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ChangeLog {
+    pub id: i64,
+    pub target: String,
+    pub target_id: u32,
+    pub operation: String,
+    pub operator: String,
+    pub changed_at: String,
+    pub changes: Vec<FieldChange>,
+}
+
+/// A saved mapping from the columns of an imported file to the column names
+/// of the export layout.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ImportMapping {
+    pub target: String,
+    pub name: String,
+    pub has_header: bool,
+    pub columns: BTreeMap<String, String>,
+}
+
+/// An import running in the background. `num_processed` counts the rows
+/// written so far including the `num_failed` ones.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ImportJob {
+    pub id: i64,
+    pub target: String,
+    // "checking", "checked", "running", "completed", "cancelled" or "failed"
+    pub status: String,
+    pub operator: String,
+    pub started_at: String,
+    pub finished_at: String,
+    pub num_rows: u32,
+    pub num_processed: u32,
+    pub num_failed: u32,
+    pub errors: Vec<ImportError>,
+    // Set by the check of the file before the rows are written
+    #[serde(default)]
+    pub num_inserted: u32,
+    #[serde(default)]
+    pub num_updated: u32,
+    #[serde(default)]
+    pub assigned_ids: Vec<u32>,
+    // Why the job failed as a whole
+    #[serde(default)]
+    pub message: String,
+    // The server running the job
+    #[serde(default)]
+    pub instance: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CatalogDraft {
+    pub id: u32,
+    pub resolved: bool,
+    pub book: Book,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct TransactionItem {
     pub id: u32,
@@ -290,6 +692,7 @@ impl User {
             borrowed_count: 0,
             reserved: String::new(),
             borrowed_books: vec![],
+            custom_fields: BTreeMap::new(),
         };
         Ok(r)
     }
@@ -364,11 +767,80 @@ impl Book {
             borrowed_count: 0,
             owner_id: None,
             return_deadline: None,
+            custom_fields: BTreeMap::new(),
         };
         Ok(r)
     }
 }
 
+/// Looks up registered books by normalized ISBN and by title, author and
+/// volume, so that many new books can be checked for other copies without
+/// comparing each of them with every registered book. A book looks like
+/// another copy if it has the same ISBN or the same title, author and volume.
+#[derive(Default)]
+pub struct DuplicateIndex {
+    by_isbn: HashMap<String, Vec<(u32, String)>>,
+    by_title: HashMap<(String, String, String), Vec<(u32, String)>>,
+}
+
+impl DuplicateIndex {
+    pub fn new(books: &[Book]) -> Self {
+        let mut index = Self::default();
+        for book in books {
+            let isbn = normalize_isbn(&book.isbn).unwrap_or_default();
+            index.insert(&isbn, book);
+        }
+        index
+    }
+
+    /// Adds a book whose ISBN is normalized already.
+    pub fn add(&mut self, book: &Book) {
+        self.insert(&book.isbn, book);
+    }
+
+    fn insert(&mut self, isbn: &str, book: &Book) {
+        let entry = (book.id, book.title.clone());
+        if !isbn.is_empty() {
+            self.by_isbn
+                .entry(isbn.to_string())
+                .or_default()
+                .push(entry.clone());
+        }
+        if let Some(key) = Self::title_key(book) {
+            self.by_title.entry(key).or_default().push(entry);
+        }
+    }
+
+    fn title_key(book: &Book) -> Option<(String, String, String)> {
+        if book.title.trim() == "" {
+            return None;
+        }
+        Some((
+            book.title.trim().to_string(),
+            book.author.trim().to_string(),
+            book.volume.trim().to_string(),
+        ))
+    }
+
+    /// Returns the ID and title of the books `book` looks like another copy
+    /// of. The ISBN of `book` is expected to be normalized already.
+    pub fn find(&self, book: &Book) -> Vec<(u32, String)> {
+        let mut found: Vec<(u32, String)> = vec![];
+        let by_isbn = match book.isbn.as_str() {
+            "" => None,
+            isbn => self.by_isbn.get(isbn),
+        };
+        let by_title = Self::title_key(book).and_then(|key| self.by_title.get(&key));
+        for entry in by_isbn.into_iter().chain(by_title).flatten() {
+            if !found.iter().any(|(id, _)| *id == entry.0) {
+                found.push(entry.clone());
+            }
+        }
+        found.sort_by_key(|(id, _)| *id);
+        found
+    }
+}
+
 impl Default for RentalSetting {
     fn default() -> Self {
         Self {
@@ -398,6 +870,11 @@ impl Default for BarcodeSetting {
             user_keta_max: 9999,
             book_keta_min: 0,
             book_keta_max: 9999,
+            check_digit: CheckDigit::None,
+            user_prefix: String::new(),
+            book_prefix: String::new(),
+            user_id_min: 0,
+            user_id_max: 0,
         }
     }
 }
@@ -408,6 +885,7 @@ impl BarcodeSetting {
         user_keta_max: &str,
         book_keta_min: &str,
         book_keta_max: &str,
+        check_digit: &str,
     ) -> Result<Self, Box<dyn error::Error>> {
         let r = Self {
             id: 1,
@@ -415,9 +893,156 @@ impl BarcodeSetting {
             user_keta_max: atoi(user_keta_max)?,
             book_keta_min: atoi(book_keta_min)?,
             book_keta_max: atoi(book_keta_max)?,
+            check_digit: CheckDigit::from_str(check_digit)
+                .map_err(|_| format!("Unknown check digit {}", check_digit))?,
+            user_prefix: String::new(),
+            book_prefix: String::new(),
+            user_id_min: 0,
+            user_id_max: 0,
         };
         Ok(r)
     }
+
+    /// Sets the prefixes and the user ID range. The range is left unused if
+    /// both ends are empty.
+    pub fn set_scan_rules(
+        &mut self,
+        user_prefix: &str,
+        book_prefix: &str,
+        user_id_min: &str,
+        user_id_max: &str,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let user_prefix = user_prefix.trim();
+        let book_prefix = book_prefix.trim();
+        if !user_prefix.is_empty()
+            && !book_prefix.is_empty()
+            && (user_prefix.starts_with(book_prefix) || book_prefix.starts_with(user_prefix))
+        {
+            return Err(format!(
+                "The prefixes {} and {} cannot be told apart",
+                user_prefix, book_prefix
+            )
+            .into());
+        }
+        let (user_id_min, user_id_max) = match (user_id_min.trim(), user_id_max.trim()) {
+            ("", "") => (0, 0),
+            (min, max) => (atoi(min)?, atoi(max)?),
+        };
+        if user_id_min > user_id_max {
+            return Err(
+                format!("The user ID range {}-{} is empty", user_id_min, user_id_max).into(),
+            );
+        }
+        self.user_prefix = user_prefix.to_string();
+        self.book_prefix = book_prefix.to_string();
+        self.user_id_min = user_id_min;
+        self.user_id_max = user_id_max;
+        Ok(())
+    }
+
+    /// Removes the prefix of a scanned barcode, and returns the kind if the
+    /// prefix tells it.
+    pub fn split_prefix<'a>(&self, barcode: &'a str) -> (Option<BarcodeKind>, &'a str) {
+        let prefixes = [
+            (BarcodeKind::User, &self.user_prefix),
+            (BarcodeKind::Book, &self.book_prefix),
+        ];
+        for (kind, prefix) in prefixes {
+            if prefix.is_empty() {
+                continue;
+            }
+            if let Some(rest) = barcode.strip_prefix(prefix.as_str()) {
+                return (Some(kind), rest);
+            }
+        }
+        (None, barcode)
+    }
+
+    /// Tells the kind of a barcode without a prefix by the user ID range.
+    /// Without the range, the barcode is of the kind that has no prefix if
+    /// only the other kind has one, and None otherwise.
+    pub fn kind_of_id(&self, id: u32) -> Option<BarcodeKind> {
+        if self.user_id_max != 0 {
+            if (self.user_id_min..=self.user_id_max).contains(&id) {
+                return Some(BarcodeKind::User);
+            } else {
+                return Some(BarcodeKind::Book);
+            }
+        }
+        match (self.user_prefix.is_empty(), self.book_prefix.is_empty()) {
+            (false, true) => Some(BarcodeKind::Book),
+            (true, false) => Some(BarcodeKind::User),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for CheckDigit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "none" => Ok(CheckDigit::None),
+            "mod10" => Ok(CheckDigit::Mod10),
+            "mod11" => Ok(CheckDigit::Mod11),
+            _ => Err(()),
+        }
+    }
+}
+
+impl CheckDigit {
+    /// Returns the check digit of the digits, or None if there is no scheme
+    /// or the value is not a number.
+    pub fn compute(&self, digits: &str) -> Option<char> {
+        if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let weighted_sum = |weight: &dyn Fn(usize) -> u32| -> u32 {
+            digits
+                .bytes()
+                .rev()
+                .enumerate()
+                .map(|(i, c)| (c - b'0') as u32 * weight(i))
+                .sum()
+        };
+        let digit = match self {
+            CheckDigit::None => return None,
+            CheckDigit::Mod10 => {
+                let sum = weighted_sum(&|i| if i % 2 == 0 { 3 } else { 1 });
+                (10 - sum % 10) % 10
+            }
+            CheckDigit::Mod11 => {
+                let sum = weighted_sum(&|i| (i % 6) as u32 + 2);
+                match sum % 11 {
+                    0 | 1 => 0,
+                    remainder => 11 - remainder,
+                }
+            }
+        };
+        char::from_digit(digit, 10)
+    }
+
+    /// Appends the check digit to the digits of a barcode.
+    pub fn append(&self, digits: &str) -> String {
+        match self.compute(digits) {
+            Some(check_digit) => format!("{}{}", digits, check_digit),
+            None => digits.to_string(),
+        }
+    }
+
+    /// Verifies the last digit of a scanned barcode and returns the digits
+    /// without it. An empty barcode is returned as it is.
+    pub fn strip<'a>(&self, barcode: &'a str) -> Result<&'a str, Box<dyn error::Error>> {
+        if *self == CheckDigit::None || barcode.is_empty() {
+            return Ok(barcode);
+        }
+        let (last, check_digit) = barcode.char_indices().last().unwrap();
+        let digits = &barcode[..last];
+        match self.compute(digits) {
+            Some(expected) if check_digit == expected => Ok(digits),
+            _ => Err(format!("{} has an invalid check digit", barcode).into()),
+        }
+    }
 }
 
 impl Default for SystemSetting {
@@ -434,6 +1059,138 @@ impl Default for SystemSetting {
     }
 }
 
+impl FromStr for CustomFieldType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(CustomFieldType::Text),
+            "number" => Ok(CustomFieldType::Number),
+            "date" => Ok(CustomFieldType::Date),
+            "choice" => Ok(CustomFieldType::Choice),
+            _ => Err(()),
+        }
+    }
+}
+
+impl CustomField {
+    /// Parses a definition line in the form of `name,type[,choice1|choice2|...]`.
+    pub fn new(line: &str) -> Result<Self, Box<dyn error::Error>> {
+        let invalid = || -> Box<dyn error::Error> { format!("Invalid field: {}", line).into() };
+        let mut columns = line.split(',').map(|column| column.trim());
+
+        let name = columns.next().unwrap_or("").to_string();
+        if name.is_empty() || name.contains('.') || name.starts_with('$') {
+            return Err(invalid());
+        }
+        let field_type =
+            CustomFieldType::from_str(columns.next().unwrap_or("text")).map_err(|_| invalid())?;
+        let choices: Vec<String> = columns
+            .next()
+            .unwrap_or("")
+            .split('|')
+            .map(|choice| choice.trim().to_string())
+            .filter(|choice| !choice.is_empty())
+            .collect();
+        if field_type == CustomFieldType::Choice && choices.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            name,
+            field_type,
+            choices,
+        })
+    }
+
+    /// Checks a value against the type and returns it in the stored form.
+    pub fn validate(&self, value: &str) -> Result<String, Box<dyn error::Error>> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(String::new());
+        }
+        let invalid =
+            || -> Box<dyn error::Error> { format!("Invalid {}: {}", self.name, value).into() };
+
+        match self.field_type {
+            CustomFieldType::Text => Ok(value.to_string()),
+            CustomFieldType::Number => {
+                let value = to_half_width(value).replace(',', "");
+                value.parse::<f64>().map_err(|_| invalid())?;
+                Ok(value)
+            }
+            CustomFieldType::Date => {
+                let value = to_half_width(value).replace('-', "/");
+                let date = NaiveDate::parse_from_str(&value, "%Y/%m/%d").map_err(|_| invalid())?;
+                Ok(date.format("%Y/%m/%d").to_string())
+            }
+            CustomFieldType::Choice => match self.choices.iter().find(|choice| *choice == value) {
+                Some(choice) => Ok(choice.clone()),
+                None => Err(invalid()),
+            },
+        }
+    }
+}
+
+/// Validates the values given by field name against the field definitions.
+/// The empty values are not stored.
+pub fn validate_custom_fields<'a>(
+    fields: &[CustomField],
+    values: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Result<BTreeMap<String, String>, Box<dyn error::Error>> {
+    let mut custom_fields = BTreeMap::new();
+    for (name, value) in values {
+        let field = fields.iter().find(|field| field.name == name).ok_or_else(
+            || -> Box<dyn error::Error> { format!("Unknown field: {}", name).into() },
+        )?;
+        let value = field.validate(value)?;
+        if !value.is_empty() {
+            custom_fields.insert(field.name.clone(), value);
+        }
+    }
+    Ok(custom_fields)
+}
+
+impl CustomFieldSetting {
+    pub fn new(user_fields: &str, book_fields: &str) -> Result<Self, Box<dyn error::Error>> {
+        let parse = |lines: &str| -> Result<Vec<CustomField>, Box<dyn error::Error>> {
+            let mut fields: Vec<CustomField> = vec![];
+            for line in lines.lines().filter(|line| !line.trim().is_empty()) {
+                let field = CustomField::new(line)?;
+                if fields.iter().any(|other| other.name == field.name) {
+                    return Err(format!("Duplicated field: {}", field.name).into());
+                }
+                fields.push(field);
+            }
+            Ok(fields)
+        };
+
+        let r = Self {
+            id: 1,
+            user_fields: parse(user_fields)?,
+            book_fields: parse(book_fields)?,
+        };
+        Ok(r)
+    }
+}
+
+impl Default for CustomFieldSetting {
+    fn default() -> Self {
+        Self {
+            id: 1,
+            user_fields: vec![],
+            book_fields: vec![],
+        }
+    }
+}
+
+impl CatalogDraft {
+    pub fn new(id: u32, mut book: Book, resolved: bool) -> Self {
+        book.id = id;
+        Self { id, resolved, book }
+    }
+}
+
 impl TransactionItem {
     pub fn new(user_id: u32, user_name: &str, book_id: u32, book_title: &str) -> Self {
         Self {
@@ -486,6 +1243,8 @@ impl Entity for User {
             query = doc! { "kana": {"$regex": &self.kana} };
         } else if self.category != "" {
             query = doc! { "category": {"$regex": &self.category} };
+        } else if let Some((name, value)) = self.custom_fields.iter().next() {
+            query = doc! { format!("custom_fields.{}", name): {"$regex": value} };
         }
 
         let collection = self.get_collection(db);
@@ -593,12 +1352,20 @@ impl Entity for Book {
 
         if self.id != 0 {
             query = doc! { "id": self.id };
+        } else if !self.isbn.is_empty() {
+            let patterns: Vec<Document> = isbn_search_patterns(&self.isbn)
+                .iter()
+                .map(|pattern| doc! { "isbn": {"$regex": pattern} })
+                .collect();
+            query = doc! { "$or": patterns };
         } else if self.title != "" {
             query = doc! { "title": {"$regex": &self.title} };
         } else if self.kana != "" {
             query = doc! { "kana": {"$regex": &self.kana} };
         } else if self.author != "" {
             query = doc! { "author": {"$regex": &self.author} };
+        } else if let Some((name, value)) = self.custom_fields.iter().next() {
+            query = doc! { format!("custom_fields.{}", name): {"$regex": value} };
         }
 
         let collection = self.get_collection(db);
@@ -754,6 +1521,190 @@ impl Entity for SystemSetting {
     }
 }
 
+#[async_trait]
+impl Entity for CustomFieldSetting {
+    async fn insert(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let collection = self.get_collection(db);
+        collection.insert_one(self, None).await?;
+        Ok(())
+    }
+
+    async fn update(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        // The tenants created before the custom fields do not have the setting yet
+        let query = doc! { "id" : self.id };
+        let update = bson::to_bson(self).unwrap();
+        let update = doc! { "$set" : update };
+        let collection = self.get_collection(db);
+        collection.update(query, update, true).await
+    }
+
+    async fn delete(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
+        panic!("Not implemented")
+    }
+
+    async fn delete_all(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
+        panic!("Not implemented")
+    }
+
+    async fn search(&self, db: &Database) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        let query = doc! { "$or" : [{"id": self.id}] };
+        let collection = self.get_collection(db);
+        collection.search(query).await
+    }
+
+    async fn search_range(
+        &self,
+        _db: &Database,
+        _start_id: u32,
+        _end_id: u32,
+    ) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        panic!("Not implemented")
+    }
+
+    fn get_collection_name(&self) -> &str {
+        "custom-field-setting"
+    }
+}
+
+#[async_trait]
+impl Entity for ChangeLog {
+    async fn insert(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let collection = self.get_collection(db);
+        collection.insert_one(self, None).await?;
+        Ok(())
+    }
+
+    async fn update(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
+        panic!("Not implemented")
+    }
+
+    async fn delete(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
+        panic!("Not implemented")
+    }
+
+    async fn delete_all(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let collection = self.get_collection(db);
+        collection.delete_all().await
+    }
+
+    async fn search(&self, db: &Database) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        let mut query = doc! { "target": &self.target, "target_id": self.target_id };
+        if self.id != 0 {
+            query = doc! { "id": self.id };
+        }
+
+        let collection = self.get_collection(db);
+        collection.search(query).await
+    }
+
+    async fn search_range(
+        &self,
+        _db: &Database,
+        _start_id: u32,
+        _end_id: u32,
+    ) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        panic!("Not implemented")
+    }
+
+    fn get_collection_name(&self) -> &str {
+        "change-logs"
+    }
+}
+
+#[async_trait]
+impl Entity for ImportJob {
+    async fn insert(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let collection = self.get_collection(db);
+        collection.insert_one(self, None).await?;
+        Ok(())
+    }
+
+    async fn update(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let query = doc! { "id": self.id };
+        let update = bson::to_bson(self).unwrap();
+        let update = doc! { "$set" : update };
+        let collection = self.get_collection(db);
+        collection.update(query, update, false).await
+    }
+
+    async fn delete(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
+        panic!("Not implemented")
+    }
+
+    async fn delete_all(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let collection = self.get_collection(db);
+        collection.delete_all().await
+    }
+
+    async fn search(&self, db: &Database) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        let query = doc! { "id": self.id };
+        let collection = self.get_collection(db);
+        collection.search(query).await
+    }
+
+    async fn search_range(
+        &self,
+        _db: &Database,
+        _start_id: u32,
+        _end_id: u32,
+    ) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        panic!("Not implemented")
+    }
+
+    fn get_collection_name(&self) -> &str {
+        "import-jobs"
+    }
+}
+
+#[async_trait]
+impl Entity for ImportMapping {
+    async fn insert(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
+        panic!("Not implemented")
+    }
+
+    async fn update(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let query = doc! { "target": &self.target, "name": &self.name };
+        let update = bson::to_bson(self).unwrap();
+        let update = doc! { "$set" : update };
+        let collection = self.get_collection(db);
+        collection.update(query, update, true).await
+    }
+
+    async fn delete(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let query = doc! { "target": &self.target, "name": &self.name };
+        let collection = self.get_collection(db);
+        collection.delete(query).await
+    }
+
+    async fn delete_all(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let collection = self.get_collection(db);
+        collection.delete_all().await
+    }
+
+    async fn search(&self, db: &Database) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        let mut query = doc! { "target": &self.target };
+        if !self.name.is_empty() {
+            query = doc! { "target": &self.target, "name": &self.name };
+        }
+
+        let collection = self.get_collection(db);
+        collection.search(query).await
+    }
+
+    async fn search_range(
+        &self,
+        _db: &Database,
+        _start_id: u32,
+        _end_id: u32,
+    ) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        panic!("Not implemented")
+    }
+
+    fn get_collection_name(&self) -> &str {
+        "import-mappings"
+    }
+}
+
 #[async_trait]
 impl Entity for TransactionItem {
     async fn insert(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
@@ -807,6 +1758,57 @@ impl Entity for TransactionItem {
     }
 }
 
+#[async_trait]
+impl Entity for CatalogDraft {
+    async fn insert(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let collection = self.get_collection(db);
+        collection.insert_one(self, None).await?;
+        Ok(())
+    }
+
+    async fn update(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let query = doc! { "id" : self.id };
+        let update = bson::to_bson(self).unwrap();
+        let update = doc! { "$set" : update };
+        let collection = self.get_collection(db);
+        collection.update(query, update, false).await
+    }
+
+    async fn delete(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let query = doc! { "id" : self.id };
+        let collection = self.get_collection(db);
+        collection.delete(query).await
+    }
+
+    async fn delete_all(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
+        let collection = self.get_collection(db);
+        collection.delete_all().await
+    }
+
+    async fn search(&self, db: &Database) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        let mut query = doc! { "id": { "$gt": 0 }};
+        if self.id != 0 {
+            query = doc! { "id": self.id };
+        }
+
+        let collection = self.get_collection(db);
+        collection.search(query).await
+    }
+
+    async fn search_range(
+        &self,
+        _db: &Database,
+        _start_id: u32,
+        _end_id: u32,
+    ) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        panic!("Not implemented")
+    }
+
+    fn get_collection_name(&self) -> &str {
+        "catalog-drafts"
+    }
+}
+
 #[async_trait]
 pub trait HelperCollection<T> {
     async fn update(
@@ -867,7 +1869,7 @@ where
         }
         if items.len() == 0 {
             Err(Box::new(Error::new(
-                ErrorKind::Other,
+                ErrorKind::NotFound,
                 "Item not found".to_string(),
             )))
         } else {
@@ -875,3 +1877,292 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mod10_check_digit() {
+        // 5*3 + 4 + 3*3 + 2 + 1*3 = 33
+        assert_eq!(CheckDigit::Mod10.compute("12345"), Some('7'));
+        assert_eq!(CheckDigit::Mod10.compute("978020137962"), Some('4'));
+        assert_eq!(CheckDigit::Mod10.compute("0"), Some('0'));
+        assert_eq!(CheckDigit::Mod10.compute(""), None);
+        assert_eq!(CheckDigit::Mod10.compute("12a"), None);
+    }
+
+    #[test]
+    fn test_mod11_check_digit() {
+        // 5*2 + 4*3 + 3*4 + 2*5 + 1*6 = 50, remainder 6
+        assert_eq!(CheckDigit::Mod11.compute("12345"), Some('5'));
+        // The weights go round from 2 after 7: 1*2 + 1*2 = 4
+        assert_eq!(CheckDigit::Mod11.compute("1000001"), Some('7'));
+        // 6*2 = 12, remainder 1
+        assert_eq!(CheckDigit::Mod11.compute("6"), Some('0'));
+        assert_eq!(CheckDigit::Mod11.compute("0"), Some('0'));
+        assert_eq!(CheckDigit::None.compute("12345"), None);
+    }
+
+    #[test]
+    fn test_strip_check_digit() {
+        assert_eq!(CheckDigit::Mod10.append("12345"), "123457");
+        assert_eq!(CheckDigit::Mod10.strip("123457").unwrap(), "12345");
+        assert!(CheckDigit::Mod10.strip("123456").is_err());
+        assert_eq!(CheckDigit::Mod11.strip("60").unwrap(), "6");
+        assert_eq!(CheckDigit::None.strip("12345").unwrap(), "12345");
+        assert_eq!(CheckDigit::Mod11.strip("").unwrap(), "");
+    }
+
+    #[test]
+    fn test_normalize_isbn() {
+        assert_eq!(normalize_isbn("4-10-109205-2").unwrap(), "9784101092058");
+        assert_eq!(
+            normalize_isbn("978-4-10-109205-8").unwrap(),
+            "9784101092058"
+        );
+        assert_eq!(
+            normalize_isbn("９７８４１０１０９２０５８").unwrap(),
+            "9784101092058"
+        );
+        assert_eq!(normalize_isbn("4-8399-0054-X").unwrap(), "9784839900540");
+        assert_eq!(normalize_isbn("").unwrap(), "");
+        assert!(normalize_isbn("4-10-109205-3").is_err());
+        assert!(normalize_isbn("9784101092059").is_err());
+        assert!(normalize_isbn("1234567890123").is_err());
+        assert!(normalize_isbn("97841010").is_err());
+        assert!(normalize_isbn("978410109205a").is_err());
+    }
+
+    #[test]
+    fn test_normalize_isbn_query() {
+        assert_eq!(
+            normalize_isbn_query("4-10-109205-2").unwrap(),
+            "9784101092058"
+        );
+        assert_eq!(normalize_isbn_query("978-4-10").unwrap(), "978410");
+        assert_eq!(normalize_isbn_query("０５０x").unwrap(), "050X");
+        assert!(normalize_isbn_query("abc").is_err());
+    }
+
+    #[test]
+    fn test_isbn_search_patterns() {
+        let patterns = isbn_search_patterns("9784101092058");
+        assert_eq!(patterns.len(), 2);
+        let pattern = |digits: &str, tail: &str| {
+            let body: Vec<String> = digits.chars().map(|c| c.to_string()).collect();
+            format!("^{}{}", body.join("[- ]?"), tail)
+        };
+        assert_eq!(patterns[0], pattern("9784101092058", "$"));
+        assert_eq!(patterns[1], pattern("410109205", "[- ]?2$"));
+        // The ISBN-10 of a check digit 10 is written with X
+        let patterns = isbn_search_patterns("9784839900540");
+        assert!(patterns[1].ends_with("[- ]?[Xx]$"));
+        // No ISBN-10 for the 979 prefix
+        assert_eq!(isbn_search_patterns("9791032305690").len(), 1);
+        // A part is not anchored
+        assert_eq!(isbn_search_patterns("4101"), vec!["4[- ]?1[- ]?0[- ]?1"]);
+        assert_eq!(isbn_search_patterns("50X"), vec!["5[- ]?0[- ]?[Xx]"]);
+    }
+
+    #[test]
+    fn test_parse_volume() {
+        assert_eq!(parse_volume("3"), Some(3));
+        assert_eq!(parse_volume("第３巻"), Some(3));
+        assert_eq!(parse_volume("vol.12 上"), Some(12));
+        assert_eq!(parse_volume("十"), Some(10));
+        assert_eq!(parse_volume("第十二巻"), Some(12));
+        assert_eq!(parse_volume("二十"), Some(20));
+        assert_eq!(parse_volume("三十五"), Some(35));
+        assert_eq!(parse_volume("上"), None);
+        assert_eq!(parse_volume(""), None);
+        // A number too large for u32 is not a volume
+        assert_eq!(parse_volume("99999999999"), None);
+    }
+
+    #[test]
+    fn test_normalize_series() {
+        assert_eq!(normalize_series("ＯＮＥ　ＰＩＥＣＥ"), "one piece");
+        assert_eq!(normalize_series(" One  Piece "), "one piece");
+        assert_eq!(normalize_series(""), "");
+    }
+
+    fn custom_field(line: &str) -> CustomField {
+        CustomField::new(line).unwrap()
+    }
+
+    #[test]
+    fn test_custom_field_new() {
+        let field = custom_field("棚, choice, A | B |");
+        assert_eq!(field.name, "棚");
+        assert_eq!(field.field_type, CustomFieldType::Choice);
+        assert_eq!(field.choices, vec!["A", "B"]);
+        assert_eq!(custom_field("メモ").field_type, CustomFieldType::Text);
+
+        // The names must be usable as MongoDB keys
+        assert!(CustomField::new("a.b,text").is_err());
+        assert!(CustomField::new("$a,text").is_err());
+        assert!(CustomField::new(",text").is_err());
+        assert!(CustomField::new("a,unknown").is_err());
+        assert!(CustomField::new("a,choice").is_err());
+    }
+
+    #[test]
+    fn test_custom_field_validate() {
+        let number = custom_field("価格,number");
+        assert_eq!(number.validate("１,２００").unwrap(), "1200");
+        assert_eq!(number.validate("-1.5").unwrap(), "-1.5");
+        assert!(number.validate("abc").is_err());
+
+        let date = custom_field("受入日,date");
+        assert_eq!(date.validate("2024-1-5").unwrap(), "2024/01/05");
+        assert_eq!(date.validate("２０２４／０２／２９").unwrap(), "2024/02/29");
+        assert!(date.validate("2023/02/29").is_err());
+        assert!(date.validate("2024/13/01").is_err());
+
+        let choice = custom_field("棚,choice,A|B");
+        assert_eq!(choice.validate(" B ").unwrap(), "B");
+        assert!(choice.validate("C").is_err());
+
+        // An empty value clears the field whatever the type is
+        assert_eq!(number.validate("  ").unwrap(), "");
+        assert_eq!(choice.validate("").unwrap(), "");
+    }
+
+    #[test]
+    fn test_validate_custom_fields() {
+        let fields = vec![custom_field("価格,number"), custom_field("メモ,text")];
+        let values =
+            validate_custom_fields(&fields, vec![("価格", "100"), ("メモ", "")].into_iter())
+                .unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values["価格"], "100");
+        assert!(validate_custom_fields(&fields, vec![("棚", "A")].into_iter()).is_err());
+        assert!(validate_custom_fields(&fields, vec![("価格", "a")].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_custom_field_setting() {
+        let setting = CustomFieldSetting::new("学年,number\n\n組,text", "").unwrap();
+        assert_eq!(setting.user_fields.len(), 2);
+        assert!(setting.book_fields.is_empty());
+        assert!(CustomFieldSetting::new("", "棚,text\n棚,number").is_err());
+    }
+
+    fn book(id: u32, title: &str, author: &str, volume: &str, isbn: &str) -> Book {
+        Book {
+            id,
+            title: title.to_string(),
+            author: author.to_string(),
+            volume: volume.to_string(),
+            isbn: isbn.to_string(),
+            ..Book::default()
+        }
+    }
+
+    #[test]
+    fn test_duplicate_index_isbn() {
+        // The registered ISBNs are normalized, the legacy ones are left out
+        let index = DuplicateIndex::new(&[
+            book(1, "A", "", "", "4-10-109205-2"),
+            book(2, "B", "", "", "invalid"),
+        ]);
+        let found = index.find(&book(10, "C", "", "", "9784101092058"));
+        assert_eq!(found, vec![(1, "A".to_string())]);
+        assert!(index.find(&book(10, "C", "", "", "invalid")).is_empty());
+        // An empty ISBN is not a match
+        let index = DuplicateIndex::new(&[book(1, "A", "", "", "")]);
+        assert!(index.find(&book(10, "C", "", "", "")).is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_index_title() {
+        let index = DuplicateIndex::new(&[
+            book(1, "Title", "Author", "1", ""),
+            book(2, "Title", "Author", "2", ""),
+            book(3, "", "Author", "", ""),
+        ]);
+        // The spaces around the values are ignored
+        let found = index.find(&book(10, " Title ", "Author ", "1", ""));
+        assert_eq!(found, vec![(1, "Title".to_string())]);
+        // Another volume or author is not a copy
+        assert!(index.find(&book(10, "Title", "Author", "3", "")).is_empty());
+        assert!(index.find(&book(10, "Title", "Other", "1", "")).is_empty());
+        // Nor is a book without a title
+        assert!(index.find(&book(10, "", "Author", "", "")).is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_index_add() {
+        let mut index = DuplicateIndex::new(&[book(2, "Title", "", "", "9784101092058")]);
+        index.add(&book(1, "Title", "", "", "9784101092058"));
+        // Found by both the ISBN and the title only once, sorted by ID
+        let found = index.find(&book(10, "Title", "", "", "9784101092058"));
+        assert_eq!(
+            found,
+            vec![(1, "Title".to_string()), (2, "Title".to_string())]
+        );
+    }
+
+    fn barcode_setting(
+        user_prefix: &str,
+        book_prefix: &str,
+        min: &str,
+        max: &str,
+    ) -> BarcodeSetting {
+        let mut setting = BarcodeSetting::new("1", "8", "1", "8", "none").unwrap();
+        setting
+            .set_scan_rules(user_prefix, book_prefix, min, max)
+            .unwrap();
+        setting
+    }
+
+    #[test]
+    fn test_set_scan_rules() {
+        let mut setting = BarcodeSetting::new("1", "8", "1", "8", "none").unwrap();
+        assert!(setting.set_scan_rules("U", "UB", "", "").is_err());
+        assert!(setting.set_scan_rules("U", "B", "10", "1").is_err());
+        assert!(setting.set_scan_rules("U", "B", "1", "").is_err());
+        assert!(setting.set_scan_rules(" U ", "", "", "").is_ok());
+        assert_eq!(setting.user_prefix, "U");
+    }
+
+    #[test]
+    fn test_split_prefix() {
+        let setting = barcode_setting("U", "B", "", "");
+        assert_eq!(
+            setting.split_prefix("U123"),
+            (Some(BarcodeKind::User), "123")
+        );
+        assert_eq!(
+            setting.split_prefix("B123"),
+            (Some(BarcodeKind::Book), "123")
+        );
+        assert_eq!(setting.split_prefix("123"), (None, "123"));
+        assert_eq!(setting.split_prefix("X123"), (None, "X123"));
+
+        let setting = barcode_setting("", "", "", "");
+        assert_eq!(setting.split_prefix("U123"), (None, "U123"));
+    }
+
+    #[test]
+    fn test_kind_of_id() {
+        let setting = barcode_setting("", "", "", "");
+        assert_eq!(setting.kind_of_id(1), None);
+        let setting = barcode_setting("U", "B", "", "");
+        assert_eq!(setting.kind_of_id(1), None);
+
+        // Only one kind has a prefix
+        let setting = barcode_setting("U", "", "", "");
+        assert_eq!(setting.kind_of_id(1), Some(BarcodeKind::Book));
+        let setting = barcode_setting("", "B", "", "");
+        assert_eq!(setting.kind_of_id(1), Some(BarcodeKind::User));
+
+        // The range is used before the prefixes
+        let setting = barcode_setting("U", "", "100", "199");
+        assert_eq!(setting.kind_of_id(99), Some(BarcodeKind::Book));
+        assert_eq!(setting.kind_of_id(100), Some(BarcodeKind::User));
+        assert_eq!(setting.kind_of_id(199), Some(BarcodeKind::User));
+        assert_eq!(setting.kind_of_id(200), Some(BarcodeKind::Book));
+    }
+}
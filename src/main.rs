@@ -3,12 +3,14 @@ use crate::item::search_items;
 use crate::item::SystemSetting;
 use crate::item::TransactionItem;
 use crate::views::cache::Cache;
+use crate::views::import_job::{fail_interrupted_jobs, instance_id, ImportJobs};
+use crate::views::pdf::check_font;
 use crate::views::reset_token::ResetToken;
 use crate::views::transaction::*;
 use actix_session::CookieSession;
 use actix_web::{web, App, HttpServer};
 use item::SystemUser;
-use log::info;
+use log::{info, warn};
 use mongodb::options::{ClientOptions, Tls, TlsOptions};
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
@@ -27,6 +29,9 @@ async fn main() -> std::io::Result<()> {
 
     // Get some environ vars
     let port = env::var("PORT").unwrap_or("5000".to_string());
+    if let Err(e) = check_font() {
+        panic!("The PDFs need a Japanese font given by BIB_PDF_FONT: {}", e);
+    }
 
     let client_uri =
         env::var("BIB_MONGODB_URI").expect("You must set the BIB_MONGODB_URI environment var!");
@@ -68,6 +73,8 @@ async fn main() -> std::io::Result<()> {
     let mut cache_map: HashMap<String, Cache> = HashMap::new();
     let mut setting_map: HashMap<String, SystemSetting> = HashMap::new();
 
+    let instance = instance_id();
+    info!("Instance: {}", instance);
     for db_name in db_names_vec {
         let db = database::get(&client_holder.clone(), &db_name)
             .await
@@ -83,16 +90,17 @@ async fn main() -> std::io::Result<()> {
         let setting = search_items(&db, &setting).await;
         let setting = setting.unwrap().pop().unwrap();
         let max_num_transactions = setting.max_num_transactions;
+
+        // The import jobs of the previous process are never going to finish
+        match fail_interrupted_jobs(&db, &instance, &setting.time_zone).await {
+            Ok(0) => {}
+            Ok(n) => warn!("{} import jobs of {} were interrupted", n, db_name),
+            Err(e) => warn!("{:?}", e),
+        }
         setting_map.insert(db_name.to_string(), setting);
 
         // Create a Transaction
-        let mut last_counter = 0;
-        let item = TransactionItem::default();
-        let mut transaction_items = Transaction::search(&db, &item).await;
-        if transaction_items.len() > 0 {
-            let last_transaction = transaction_items.pop();
-            last_counter = last_transaction.unwrap().id;
-        }
+        let last_counter = Transaction::last_counter(&db).await;
         info!(
             "last_counter/max_num_transactions = {}/{}",
             last_counter, max_num_transactions
@@ -109,6 +117,7 @@ async fn main() -> std::io::Result<()> {
     let cache_map = web::Data::new(Mutex::new(cache_map));
     let setting_map = web::Data::new(Mutex::new(setting_map));
     let token_map = web::Data::new(ResetToken::new());
+    let import_jobs = web::Data::new(ImportJobs::new(&instance));
 
     HttpServer::new(move || {
         let app = App::new()
@@ -118,6 +127,7 @@ async fn main() -> std::io::Result<()> {
             .app_data(cache_map.clone())
             .app_data(setting_map.clone())
             .app_data(token_map.clone())
+            .app_data(import_jobs.clone())
             .app_data(client_holder.clone());
         return app;
     })
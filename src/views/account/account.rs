@@ -40,6 +40,8 @@ pub struct CreateAccountForm {
 pub struct UpdateProfileForm {
     pub uname: String,
     pub email: String,
+    #[serde(default)]
+    pub library_name: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -171,6 +173,7 @@ pub async fn update(
 
     let mut system_user = get_system_user(&data, Some(uname), None).await?;
     system_user.email = form.email.to_owned();
+    system_user.library_name = form.library_name.trim().to_owned();
 
     match update_item(&db, &system_user).await {
         Ok(setting) => setting,
@@ -263,6 +266,7 @@ pub async fn get(
     let mut reply = Reply::default();
     reply.uname = system_user.uname;
     reply.email = system_user.email;
+    reply.library_name = system_user.library_name;
     reply.plan = system_user.plan.get_str();
 
     Ok(HttpResponse::Ok().json(reply))
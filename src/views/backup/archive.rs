@@ -0,0 +1,447 @@
+use crate::error::BibErrorResponse;
+use crate::item::ChangeLog;
+use crate::item::CustomFieldSetting;
+use crate::item::{create_unique_index, insert_many_items_into, search_item, stream_items};
+use crate::item::{update_item, Entity};
+use crate::item::{BarcodeSetting, Book, RentalSetting, SystemSetting, TransactionItem, User};
+use crate::views::cache::Cache;
+use crate::views::custom_field::get_custom_field_setting;
+use crate::views::db_helper::{get_db, get_db_with_name};
+use crate::views::reply::Reply;
+use crate::views::session::check_operator_session;
+use crate::views::transaction::Transaction;
+use crate::views::utils::{get_nowtime, get_setting};
+use actix_multipart::Multipart;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use futures::{StreamExt, TryStreamExt};
+use log::{error, info};
+use mongodb::bson::{doc, Document};
+use mongodb::Database;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use shared_mongodb::ClientHolder;
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::io::{Cursor, Write};
+use std::sync::Mutex;
+use zip::write::SimpleFileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+const BACKUP_FORMAT: &str = "cloud-bib-backup";
+// Raised when the layout of the archive changes, version 2 adds the change
+// logs
+const BACKUP_VERSION: u32 = 2;
+// The archive is read into memory to be checked before the restore
+const MAX_BACKUP_SIZE: usize = 256 * 1024 * 1024;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const USERS_FILE: &str = "users.json";
+const BOOKS_FILE: &str = "books.json";
+const TRANSACTIONS_FILE: &str = "transactions.json";
+const CHANGE_LOGS_FILE: &str = "change_logs.json";
+const RENTAL_SETTING_FILE: &str = "rental_setting.json";
+const BARCODE_SETTING_FILE: &str = "barcode_setting.json";
+const CUSTOM_FIELD_SETTING_FILE: &str = "custom_field_setting.json";
+const SYSTEM_SETTING_FILE: &str = "system_setting.json";
+
+/// Describes the archive so that it can be checked before the restore.
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    format: String,
+    version: u32,
+    created_at: String,
+    num_users: usize,
+    num_books: usize,
+    num_transactions: usize,
+    #[serde(default)]
+    num_change_logs: usize,
+}
+
+/// The whole data of a tenant.
+struct Backup {
+    manifest: Manifest,
+    users: Vec<User>,
+    books: Vec<Book>,
+    transactions: Vec<TransactionItem>,
+    change_logs: Vec<ChangeLog>,
+    rental_setting: RentalSetting,
+    barcode_setting: BarcodeSetting,
+    custom_field_setting: CustomFieldSetting,
+    system_setting: SystemSetting,
+}
+
+fn write_entry<T: Serialize + ?Sized>(
+    zip: &mut ZipWriter<Cursor<Vec<u8>>>,
+    name: &str,
+    value: &T,
+) -> Result<(), Box<dyn error::Error>> {
+    zip.start_file(name, SimpleFileOptions::default())?;
+    serde_json::to_writer(&mut *zip, value)?;
+    zip.flush()?;
+    Ok(())
+}
+
+fn read_entry<T: DeserializeOwned>(
+    zip: &mut ZipArchive<Cursor<Vec<u8>>>,
+    name: &str,
+) -> Result<T, Box<dyn error::Error>> {
+    let file = zip.by_name(name).map_err(|e| format!("{}: {}", name, e))?;
+    serde_json::from_reader(file).map_err(|e| format!("{}: {}", name, e).into())
+}
+
+impl Backup {
+    fn write(&self) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        write_entry(&mut zip, MANIFEST_FILE, &self.manifest)?;
+        write_entry(&mut zip, USERS_FILE, &self.users)?;
+        write_entry(&mut zip, BOOKS_FILE, &self.books)?;
+        write_entry(&mut zip, TRANSACTIONS_FILE, &self.transactions)?;
+        write_entry(&mut zip, CHANGE_LOGS_FILE, &self.change_logs)?;
+        write_entry(&mut zip, RENTAL_SETTING_FILE, &self.rental_setting)?;
+        write_entry(&mut zip, BARCODE_SETTING_FILE, &self.barcode_setting)?;
+        write_entry(
+            &mut zip,
+            CUSTOM_FIELD_SETTING_FILE,
+            &self.custom_field_setting,
+        )?;
+        write_entry(&mut zip, SYSTEM_SETTING_FILE, &self.system_setting)?;
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// Reads an archive, the manifest is checked before the other files. An
+    /// archive of version 1 has no change logs, which are then cleared as
+    /// they would not match the restored items.
+    fn read(data: Vec<u8>) -> Result<Self, Box<dyn error::Error>> {
+        let mut zip = ZipArchive::new(Cursor::new(data))?;
+        let manifest: Manifest = read_entry(&mut zip, MANIFEST_FILE)?;
+        if manifest.format != BACKUP_FORMAT {
+            return Err("Not a backup archive".into());
+        }
+        if manifest.version > BACKUP_VERSION {
+            return Err(format!("Unsupported backup version {}", manifest.version).into());
+        }
+
+        Ok(Self {
+            users: read_entry(&mut zip, USERS_FILE)?,
+            books: read_entry(&mut zip, BOOKS_FILE)?,
+            transactions: read_entry(&mut zip, TRANSACTIONS_FILE)?,
+            change_logs: match manifest.version {
+                1 => vec![],
+                _ => read_entry(&mut zip, CHANGE_LOGS_FILE)?,
+            },
+            rental_setting: read_entry(&mut zip, RENTAL_SETTING_FILE)?,
+            barcode_setting: read_entry(&mut zip, BARCODE_SETTING_FILE)?,
+            custom_field_setting: read_entry(&mut zip, CUSTOM_FIELD_SETTING_FILE)?,
+            system_setting: read_entry(&mut zip, SYSTEM_SETTING_FILE)?,
+            manifest,
+        })
+    }
+
+    /// Checks that the files are complete and consistent, and that the data
+    /// fits in the limits of the plan of the tenant restored to.
+    fn validate(&self, setting: &SystemSetting) -> Result<(), BibErrorResponse> {
+        let counts = [
+            (USERS_FILE, self.manifest.num_users, self.users.len()),
+            (BOOKS_FILE, self.manifest.num_books, self.books.len()),
+            (
+                TRANSACTIONS_FILE,
+                self.manifest.num_transactions,
+                self.transactions.len(),
+            ),
+            (
+                CHANGE_LOGS_FILE,
+                self.manifest.num_change_logs,
+                self.change_logs.len(),
+            ),
+        ];
+        for (name, expected, actual) in counts {
+            if expected != actual {
+                return Err(BibErrorResponse::InvalidArgument(format!(
+                    "{} has {} items instead of {}",
+                    name, actual, expected
+                )));
+            }
+        }
+
+        check_ids(USERS_FILE, self.users.iter().map(|user| user.id))?;
+        let book_ids = check_ids(BOOKS_FILE, self.books.iter().map(|book| book.id))?;
+        check_ids(
+            TRANSACTIONS_FILE,
+            self.transactions.iter().map(|item| item.id),
+        )?;
+        for user in &self.users {
+            for book in &user.borrowed_books {
+                if !book_ids.contains(&book.book_id) {
+                    return Err(BibErrorResponse::InvalidArgument(format!(
+                        "The user {} borrows the missing book {}",
+                        user.id, book.book_id
+                    )));
+                }
+            }
+        }
+
+        let num_users: u32 = self.users.len().try_into().unwrap();
+        if num_users > setting.max_registered_users {
+            return Err(BibErrorResponse::ExceedLimit(num_users));
+        }
+        let num_books: u32 = self.books.len().try_into().unwrap();
+        if num_books > setting.max_registered_books {
+            return Err(BibErrorResponse::ExceedLimit(num_books));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the IDs if none of them is 0 or used twice.
+fn check_ids(name: &str, ids: impl Iterator<Item = u32>) -> Result<HashSet<u32>, BibErrorResponse> {
+    let mut used_ids = HashSet::new();
+    for id in ids {
+        if id == 0 || !used_ids.insert(id) {
+            return Err(BibErrorResponse::InvalidArgument(format!(
+                "{} has the invalid ID {}",
+                name, id
+            )));
+        }
+    }
+    Ok(used_ids)
+}
+
+/// Reads all the items without the limit of a search.
+async fn read_all<T>(db: &Database, item: &T) -> Result<Vec<T>, Box<dyn error::Error>>
+where
+    T: Entity + DeserializeOwned + Unpin + Send + Sync,
+{
+    Ok(stream_items(db, item).await?.try_collect().await?)
+}
+
+pub async fn export_backup(
+    session: Session,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    let dbname = check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+    let system_setting = get_setting(&dbname, &setting_map)?;
+
+    let users = read_all(&db, &User::default())
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    let books = read_all(&db, &Book::default())
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    let transactions = read_all(&db, &TransactionItem::default())
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    let change_logs = read_all(&db, &ChangeLog::default())
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    let rental_setting = RentalSetting {
+        id: 1,
+        ..Default::default()
+    };
+    let rental_setting = search_item(&db, &rental_setting)
+        .await
+        .map_err(|e| BibErrorResponse::DataNotFound(e.to_string()))?;
+    let barcode_setting = BarcodeSetting {
+        id: 1,
+        ..Default::default()
+    };
+    let barcode_setting = search_item(&db, &barcode_setting)
+        .await
+        .map_err(|e| BibErrorResponse::DataNotFound(e.to_string()))?;
+
+    let now = get_nowtime(&system_setting.time_zone);
+    let backup = Backup {
+        manifest: Manifest {
+            format: BACKUP_FORMAT.to_string(),
+            version: BACKUP_VERSION,
+            created_at: format!("{}", now.format("%Y/%m/%d %H:%M:%S")),
+            num_users: users.len(),
+            num_books: books.len(),
+            num_transactions: transactions.len(),
+            num_change_logs: change_logs.len(),
+        },
+        users,
+        books,
+        transactions,
+        change_logs,
+        rental_setting,
+        barcode_setting,
+        custom_field_setting: get_custom_field_setting(&db).await,
+        system_setting,
+    };
+    let body = backup
+        .write()
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .header(
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"backup_{}.zip\"",
+                now.format("%Y%m%d")
+            ),
+        )
+        .body(body))
+}
+
+async fn read_payload(mut payload: Multipart) -> Result<Vec<u8>, Box<dyn error::Error>> {
+    let mut field = payload.try_next().await?.ok_or("Payload not found")?;
+    let mut data = vec![];
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > MAX_BACKUP_SIZE {
+            return Err(format!("The backup is larger than {} bytes", MAX_BACKUP_SIZE).into());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Loads the items into a staging collection next to their own, so that the
+/// current items are kept until all of them are loaded. Returns the names of
+/// the staging collection and of the collection it replaces.
+async fn stage<T>(db: &Database, items: &[T]) -> Result<(String, String), Box<dyn error::Error>>
+where
+    T: Entity + Default + Serialize + Send + Sync,
+{
+    let name = T::default().get_collection_name().to_string();
+    let staged = format!("{}-restore", name);
+    let collection = db.collection::<T>(&staged);
+    // Left by a restore that failed
+    collection.drop(None).await?;
+    db.create_collection(&staged, None).await?;
+    insert_many_items_into(&collection, items).await?;
+    Ok((staged, name))
+}
+
+async fn rename_collection(
+    db: &Database,
+    admin_db: &Database,
+    from: &str,
+    to: &str,
+) -> Result<(), Box<dyn error::Error>> {
+    let command = doc! {
+        "renameCollection": format!("{}.{}", db.name(), from),
+        "to": format!("{}.{}", db.name(), to),
+        "dropTarget": true,
+    };
+    admin_db.run_command(command, None).await?;
+    Ok(())
+}
+
+/// Replaces the collections with the staging ones. The current collections
+/// are renamed aside first, and all the renames are undone on a failure, so
+/// that the collections are not taken from different backups.
+async fn replace_with_staged(
+    db: &Database,
+    admin_db: &Database,
+    staged: &[(String, String)],
+) -> Result<(), Box<dyn error::Error>> {
+    let existing = db.list_collection_names(None).await?;
+    let mut renamed: Vec<(String, String)> = vec![];
+    let mut result = Ok(());
+    for (staged, name) in staged {
+        let previous = format!("{}-previous", name);
+        let mut renames = vec![(staged.clone(), name.clone())];
+        if existing.contains(name) {
+            renames.insert(0, (name.clone(), previous));
+        }
+        for (from, to) in renames {
+            result = rename_collection(db, admin_db, &from, &to).await;
+            if result.is_err() {
+                break;
+            }
+            renamed.push((from, to));
+        }
+        if result.is_err() {
+            break;
+        }
+    }
+
+    if let Err(e) = result {
+        for (from, to) in renamed.iter().rev() {
+            if let Err(e) = rename_collection(db, admin_db, to, from).await {
+                error!("Failed to roll back {} to {}: {}", to, from, e);
+            }
+        }
+        return Err(e);
+    }
+    for (_, name) in staged {
+        db.collection::<Document>(&format!("{}-previous", name))
+            .drop(None)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Replaces the users, the books, the transactions, the change logs and the
+/// settings of the tenant. The limits of the plan are kept and only the time
+/// zone is taken from the archived system setting. The items are loaded
+/// aside first, so a failure to load them leaves the tenant as it was.
+async fn restore(
+    db: &Database,
+    admin_db: &Database,
+    backup: Backup,
+    mut setting: SystemSetting,
+) -> Result<SystemSetting, Box<dyn error::Error>> {
+    let staged = [
+        stage(db, &backup.users).await?,
+        stage(db, &backup.books).await?,
+        stage(db, &backup.transactions).await?,
+        stage(db, &backup.change_logs).await?,
+    ];
+    replace_with_staged(db, admin_db, &staged).await?;
+    // The unique indexes of the replaced collections are dropped with them
+    create_unique_index(db).await?;
+
+    update_item(db, &backup.rental_setting).await?;
+    update_item(db, &backup.barcode_setting).await?;
+    update_item(db, &backup.custom_field_setting).await?;
+    setting.time_zone = backup.system_setting.time_zone;
+    update_item(db, &setting).await?;
+    Ok(setting)
+}
+
+pub async fn restore_backup(
+    session: Session,
+    payload: Multipart,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+    transaction_map: web::Data<Mutex<HashMap<String, Transaction>>>,
+    cache_map: web::Data<Mutex<HashMap<String, Cache>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    let dbname = check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+    let setting = get_setting(&dbname, &setting_map)?;
+
+    let archive = read_payload(payload)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    let backup =
+        Backup::read(archive).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    backup.validate(&setting)?;
+    info!("Restoring {:?}", backup.manifest);
+
+    let admin_db = get_db_with_name(&data, &"admin".to_string()).await?;
+    let setting = restore(&db, &admin_db, backup, setting)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    // Rebuild the states kept in memory from the restored DB
+    let last_counter = Transaction::last_counter(&db).await;
+    let cache = Cache::new();
+    cache.construct(&db).await;
+    transaction_map.lock().unwrap().insert(
+        dbname.clone(),
+        Transaction::new(setting.max_num_transactions, last_counter),
+    );
+    cache_map.lock().unwrap().insert(dbname.clone(), cache);
+    setting_map.lock().unwrap().insert(dbname, setting);
+
+    let reply = Reply::default();
+    Ok(HttpResponse::Ok().json(reply))
+}
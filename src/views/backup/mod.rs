@@ -0,0 +1,17 @@
+use crate::views::path::Path;
+use actix_web::web;
+mod archive;
+
+pub fn backup_factory(app: &mut web::ServiceConfig) {
+    let base_path: Path = Path {
+        prefix: String::from("/backup"),
+    };
+    app.route(
+        &base_path.define(String::from("/export")),
+        web::get().to(archive::export_backup),
+    )
+    .route(
+        &base_path.define(String::from("/restore")),
+        web::post().to(archive::restore_backup),
+    );
+}
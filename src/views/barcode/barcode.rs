@@ -1,6 +1,7 @@
-use crate::item::{atoi, search_items_range, Book, User};
+use crate::item::{atoi, search_items_range, BarcodeKind, BarcodeSetting, Book, User};
 use crate::views::content_loader::read_file;
 use crate::views::db_helper::get_db;
+use crate::views::id_allocator::get_barcode_setting;
 use crate::views::reply::Reply;
 use crate::{error::BibErrorResponse, views::session::check_operator_session};
 use actix_session::Session;
@@ -26,6 +27,8 @@ pub struct GenUserBarcodeGenPageForm {
     pub barcode_height_control: String,
     pub barcode_margin_control: String,
     pub barcode_size: String,
+    #[serde(default)]
+    pub barcode_format: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -44,6 +47,8 @@ pub struct GenBookBarcodeGenPageForm {
     pub barcode_height_control: String,
     pub barcode_margin_control: String,
     pub barcode_size: String,
+    #[serde(default)]
+    pub barcode_format: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -53,6 +58,22 @@ pub struct GenBookBarcodeForm {
     pub barcode_size: String,
 }
 
+/// Pads the ID with zeros to the number of digits, and adds the prefix and
+/// the check digit, as the barcode is scanned.
+pub fn barcode_value(
+    kind: BarcodeKind,
+    id: u32,
+    barcode_size: usize,
+    setting: &BarcodeSetting,
+) -> String {
+    let prefix = match kind {
+        BarcodeKind::User => &setting.user_prefix,
+        BarcodeKind::Book => &setting.book_prefix,
+    };
+    let digits = format!("{:0width$}", id, width = barcode_size);
+    format!("{}{}", prefix, setting.check_digit.append(&digits))
+}
+
 pub async fn get_user_page(form: web::Query<GenUserBarcodeGenPageForm>) -> HttpResponse {
     let mut html_data = read_file("src/html/barcode/user.html").unwrap();
     html_data = html_data
@@ -62,7 +83,8 @@ pub async fn get_user_page(form: web::Query<GenUserBarcodeGenPageForm>) -> HttpR
         .replace("{{BARCODE_WIDTH}}", &form.barcode_width_control)
         .replace("{{BARCODE_HEIGHT}}", &form.barcode_height_control)
         .replace("{{BARCODE_MARGIN}}", &form.barcode_margin_control)
-        .replace("{{BARCODE_SIZE}}", &form.barcode_size);
+        .replace("{{BARCODE_SIZE}}", &form.barcode_size)
+        .replace("{{BARCODE_FORMAT}}", &form.barcode_format);
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
@@ -90,9 +112,23 @@ pub async fn generate_user_barocde(
     let barcode_size =
         atoi(&form.barcode_size).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
 
+    let barcode_setting = get_barcode_setting(&db).await?;
+    let barcodes = users
+        .iter()
+        .map(|item| {
+            barcode_value(
+                BarcodeKind::User,
+                item.id,
+                barcode_size as usize,
+                &barcode_setting,
+            )
+        })
+        .collect();
+
     let mut reply = Reply::default();
     reply.user_list.append(&mut users);
     reply.barcode_size = barcode_size;
+    reply.barcodes = barcodes;
 
     Ok(HttpResponse::Ok().json(reply))
 }
@@ -106,7 +142,8 @@ pub async fn get_book_page(form: web::Query<GenBookBarcodeGenPageForm>) -> HttpR
         .replace("{{BARCODE_WIDTH}}", &form.barcode_width_control)
         .replace("{{BARCODE_HEIGHT}}", &form.barcode_height_control)
         .replace("{{BARCODE_MARGIN}}", &form.barcode_margin_control)
-        .replace("{{BARCODE_SIZE}}", &form.barcode_size);
+        .replace("{{BARCODE_SIZE}}", &form.barcode_size)
+        .replace("{{BARCODE_FORMAT}}", &form.barcode_format);
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
@@ -134,9 +171,23 @@ pub async fn generate_book_barcode(
     let barcode_size =
         atoi(&form.barcode_size).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
 
+    let barcode_setting = get_barcode_setting(&db).await?;
+    let barcodes = books
+        .iter()
+        .map(|item| {
+            barcode_value(
+                BarcodeKind::Book,
+                item.id,
+                barcode_size as usize,
+                &barcode_setting,
+            )
+        })
+        .collect();
+
     let mut reply = Reply::default();
     reply.book_list.append(&mut books);
     reply.barcode_size = barcode_size;
+    reply.barcodes = barcodes;
 
     Ok(HttpResponse::Ok().json(reply))
 }
@@ -0,0 +1,196 @@
+use super::barcode::barcode_value;
+use super::label::{draw_symbol, get_barcode_size, get_start_position};
+use super::symbology::{encode, Symbology};
+use crate::error::BibErrorResponse;
+use crate::item::{atoi, search_items, search_items_by_ids, search_items_range, BarcodeKind, User};
+use crate::views::db_helper::get_db;
+use crate::views::id_allocator::get_barcode_setting;
+use crate::views::pdf::{fit_text, LabelSheet, PdfSheet};
+use crate::views::session::{check_operator_session, get_uname};
+use crate::views::utils::get_system_user;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use log::debug;
+use mongodb::Database;
+use serde::Deserialize;
+use shared_mongodb::ClientHolder;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+const PADDING: f32 = 4.0;
+const LIBRARY_NAME_FONT_SIZE: f32 = 9.0;
+const NAME_FONT_SIZE: f32 = 14.0;
+const DETAIL_FONT_SIZE: f32 = 8.0;
+// The offsets from the top of a card in mm
+const NAME_TOP: f32 = 10.0;
+const DETAIL_TOP: f32 = 17.5;
+const BARCODE_TOP: f32 = 23.0;
+const BARCODE_BOTTOM: f32 = 46.5;
+
+/// The users are the ones listed in `user_ids`, or else the ones within the ID
+/// range, or all the users if the range is empty. They are narrowed down to
+/// the grade if it is given.
+#[derive(Deserialize, Debug)]
+pub struct CardPdfForm {
+    #[serde(default)]
+    pub user_id_start: String,
+    #[serde(default)]
+    pub user_id_end: String,
+    #[serde(default)]
+    pub user_ids: String,
+    #[serde(default)]
+    pub grade: String,
+    pub barcode_type: String,
+    #[serde(default)]
+    pub barcode_size: String,
+    #[serde(default)]
+    pub start_position: String,
+}
+
+async fn search_users(db: &Database, form: &CardPdfForm) -> Result<Vec<User>, BibErrorResponse> {
+    let user = User::default();
+    let user_ids: Vec<u32> = form
+        .user_ids
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|id| !id.is_empty())
+        .map(atoi)
+        .collect::<Result<_, _>>()
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+
+    let mut users = if !user_ids.is_empty() {
+        let users = search_items_by_ids(db, &user, &user_ids)
+            .await
+            .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+        let mut selected_users = vec![];
+        for id in user_ids {
+            match users.iter().find(|user| user.id == id) {
+                Some(user) => selected_users.push(user.clone()),
+                None => return Err(BibErrorResponse::UserNotFound(id)),
+            }
+        }
+        selected_users
+    } else if form.user_id_start.trim() != "" || form.user_id_end.trim() != "" {
+        let start_id = atoi(&form.user_id_start)
+            .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+        let end_id = atoi(&form.user_id_end)
+            .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+        search_items_range(db, &user, start_id, end_id)
+            .await
+            .map_err(|_| BibErrorResponse::UserNotFound(start_id))?
+    } else {
+        let mut users = search_items(db, &user)
+            .await
+            .map_err(|e| BibErrorResponse::DataNotFound(e.to_string()))?;
+        users.sort_by_key(|user| user.id);
+        users
+    };
+
+    let grade = form.grade.trim();
+    if !grade.is_empty() {
+        users.retain(|user| user.grade.trim() == grade);
+    }
+    Ok(users)
+}
+
+/// Lays out a card per user from `start_index` of the first page, with the
+/// library name, the user's name, grade and category, and the barcode.
+fn write_card_pdf(
+    users: &[(User, String)],
+    library_name: &str,
+    symbology: Symbology,
+    sheet: &LabelSheet,
+    start_index: u32,
+) -> Result<Vec<u8>, BibErrorResponse> {
+    let mut pdf =
+        PdfSheet::new("library cards").map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    let inner_width = sheet.label_width - PADDING * 2.0;
+    for (i, (user, value)) in users.iter().enumerate() {
+        let index = (i as u32 + start_index) % sheet.labels_per_page();
+        if i > 0 && index == 0 {
+            pdf.add_page();
+        }
+        let symbol = encode(symbology, value)
+            .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+
+        let (x, y) = sheet.position(index);
+        let center_x = x + sheet.label_width / 2.0;
+        // The outline is the cutting line
+        pdf.rect(x, y, sheet.label_width, sheet.label_height);
+
+        let text = fit_text(library_name, LIBRARY_NAME_FONT_SIZE, inner_width);
+        pdf.centered_text(&text, LIBRARY_NAME_FONT_SIZE, center_x, y + PADDING)?;
+
+        let text = fit_text(user.name.trim(), NAME_FONT_SIZE, inner_width);
+        pdf.text(&text, NAME_FONT_SIZE, x + PADDING, y + NAME_TOP)?;
+
+        let details: Vec<&str> = [user.grade.trim(), user.category.trim()]
+            .into_iter()
+            .filter(|detail| !detail.is_empty())
+            .collect();
+        let text = fit_text(&details.join("  "), DETAIL_FONT_SIZE, inner_width);
+        pdf.text(&text, DETAIL_FONT_SIZE, x + PADDING, y + DETAIL_TOP)?;
+
+        draw_symbol(
+            &pdf,
+            &symbol,
+            x + PADDING,
+            y + BARCODE_TOP,
+            inner_width,
+            BARCODE_BOTTOM - BARCODE_TOP,
+        );
+        let text = fit_text(value, DETAIL_FONT_SIZE, inner_width);
+        pdf.centered_text(&text, DETAIL_FONT_SIZE, center_x, y + BARCODE_BOTTOM)?;
+    }
+
+    pdf.save()
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))
+}
+
+/// Creates a PDF of the library cards of the selected users, printed in the
+/// credit card size on A4 paper.
+pub async fn get_card_pdf(
+    session: Session,
+    form: web::Query<CardPdfForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let sheet = LabelSheet::cards();
+    let symbology = Symbology::from_str(&form.barcode_type)
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let start_position = get_start_position(&form.start_position, &sheet)?;
+    let barcode_size = get_barcode_size(&form.barcode_size)?;
+
+    let uname = get_uname(&session)?;
+    let library_name = get_system_user(&data, Some(uname), None)
+        .await?
+        .library_name;
+    let barcode_setting = get_barcode_setting(&db).await?;
+
+    let users: Vec<(User, String)> = search_users(&db, &form)
+        .await?
+        .into_iter()
+        .map(|user| {
+            let value = barcode_value(BarcodeKind::User, user.id, barcode_size, &barcode_setting);
+            (user, value)
+        })
+        .collect();
+    if users.is_empty() {
+        return Err(BibErrorResponse::DataNotFound(String::new()));
+    }
+
+    let pdf_data = write_card_pdf(&users, &library_name, symbology, &sheet, start_position - 1)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/pdf")
+        .header(
+            "Content-Disposition",
+            "inline; filename=\"library_cards.pdf\"",
+        )
+        .body(pdf_data))
+}
@@ -0,0 +1,283 @@
+use super::symbology::{encode, Symbol, Symbology};
+use crate::error::BibErrorResponse;
+use crate::item::atoi;
+use crate::views::session::check_operator_session;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use log::debug;
+use serde::Deserialize;
+use std::error;
+use std::str::FromStr;
+
+// The text under a linear barcode, as drawn by the barcode pages
+const FONT_SIZE: u32 = 20;
+const TEXT_MARGIN: u32 = 2;
+// The largest image rendered, which bounds the memory of a PNG
+const MAX_PIXELS: usize = 4_000_000;
+
+#[derive(Deserialize, Debug)]
+pub struct BarcodeImageForm {
+    pub value: String,
+    pub barcode_type: String,
+    pub barcode_width_control: String,
+    pub barcode_height_control: String,
+    pub barcode_margin_control: String,
+    #[serde(default)]
+    pub format: String,
+}
+
+/// The sizes in pixels. `module_width` is the width of the narrowest bar, or
+/// the size of a QR module. The height is ignored by QR.
+pub struct BarcodeStyle {
+    pub module_width: u32,
+    pub height: u32,
+    pub margin: u32,
+}
+
+impl BarcodeStyle {
+    pub fn new(width: &str, height: &str, margin: &str) -> Result<Self, Box<dyn error::Error>> {
+        let style = Self {
+            module_width: atoi(width)?,
+            height: atoi(height)?,
+            margin: atoi(margin)?,
+        };
+        if !(1..=20).contains(&style.module_width)
+            || !(1..=1000).contains(&style.height)
+            || style.margin > 200
+        {
+            return Err("The barcode size is out of range".into());
+        }
+        Ok(style)
+    }
+
+    fn bar_height(&self, symbol: &Symbol) -> u32 {
+        if symbol.is_linear() {
+            self.height
+        } else {
+            symbol.width as u32 * self.module_width
+        }
+    }
+
+    /// The width and the height of the image without the text.
+    fn size(&self, symbol: &Symbol) -> (usize, usize) {
+        let margin = self.margin as usize;
+        (
+            symbol.width * self.module_width as usize + 2 * margin,
+            self.bar_height(symbol) as usize + 2 * margin,
+        )
+    }
+
+    pub fn check_size(&self, symbol: &Symbol) -> Result<(), Box<dyn error::Error>> {
+        let (width, height) = self.size(symbol);
+        if width * height > MAX_PIXELS {
+            return Err("The barcode image is too large".into());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the runs of dark modules as the start and the length.
+fn dark_runs(row: &[bool]) -> Vec<(usize, usize)> {
+    let mut runs = vec![];
+    let mut start = None;
+    for (i, dark) in row.iter().chain([false].iter()).enumerate() {
+        match (start, *dark) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                runs.push((s, i - s));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    runs
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn to_svg(symbol: &Symbol, style: &BarcodeStyle) -> String {
+    let w = style.module_width;
+    let bar_height = style.bar_height(symbol);
+    let row_height = if symbol.is_linear() { bar_height } else { w };
+    let text_height = if symbol.text.is_empty() {
+        0
+    } else {
+        TEXT_MARGIN + FONT_SIZE
+    };
+    let width = symbol.width as u32 * w + 2 * style.margin;
+    let height = bar_height + text_height + 2 * style.margin;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">",
+        width, height
+    );
+    svg.push_str(&format!(
+        "<rect x=\"0\" y=\"0\" width=\"{}\" height=\"{}\" fill=\"#ffffff\"/><g fill=\"#000000\">",
+        width, height
+    ));
+    for (y, row) in symbol.rows.iter().enumerate() {
+        for (x, len) in dark_runs(row) {
+            svg.push_str(&format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>",
+                style.margin + x as u32 * w,
+                style.margin + y as u32 * row_height,
+                len as u32 * w,
+                row_height
+            ));
+        }
+    }
+    svg.push_str("</g>");
+    if !symbol.text.is_empty() {
+        svg.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" font-family=\"monospace\" font-size=\"{}\">{}</text>",
+            width / 2,
+            style.margin + bar_height + text_height,
+            FONT_SIZE,
+            escape_xml(&symbol.text)
+        ));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Draws the modules in grayscale. The text of a linear barcode is not
+/// drawn as no font is available to the server.
+pub fn to_png(symbol: &Symbol, style: &BarcodeStyle) -> Result<Vec<u8>, Box<dyn error::Error>> {
+    let w = style.module_width as usize;
+    let margin = style.margin as usize;
+    let bar_height = style.bar_height(symbol) as usize;
+    let row_height = if symbol.is_linear() { bar_height } else { w };
+    let (width, height) = style.size(symbol);
+
+    let mut pixels = vec![255u8; width * height];
+    for (y, row) in symbol.rows.iter().enumerate() {
+        for (x, len) in dark_runs(row) {
+            for py in 0..row_height {
+                let offset = (margin + y * row_height + py) * width + margin + x * w;
+                pixels[offset..offset + len * w].fill(0);
+            }
+        }
+    }
+
+    let mut data = vec![];
+    let mut encoder = png::Encoder::new(&mut data, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(data)
+}
+
+/// Renders a barcode as an SVG or PNG image, so that it is printed in the
+/// same way regardless of the browser.
+pub async fn get_barcode_image(
+    session: Session,
+    form: web::Query<BarcodeImageForm>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    check_operator_session(&session)?;
+
+    let symbology = Symbology::from_str(&form.barcode_type)
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let style = BarcodeStyle::new(
+        &form.barcode_width_control,
+        &form.barcode_height_control,
+        &form.barcode_margin_control,
+    )
+    .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let symbol = encode(symbology, &form.value)
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    style
+        .check_size(&symbol)
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+
+    match form.format.as_str() {
+        "" | "svg" => Ok(HttpResponse::Ok()
+            .content_type("image/svg+xml")
+            .body(to_svg(&symbol, &style))),
+        "png" => {
+            let body = to_png(&symbol, &style)
+                .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            Ok(HttpResponse::Ok().content_type("image/png").body(body))
+        }
+        format => Err(BibErrorResponse::InvalidArgument(format.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn style(width: &str, height: &str, margin: &str) -> BarcodeStyle {
+        BarcodeStyle::new(width, height, margin).unwrap()
+    }
+
+    #[test]
+    fn test_barcode_style() {
+        assert!(BarcodeStyle::new("0", "50", "0").is_err());
+        assert!(BarcodeStyle::new("21", "50", "0").is_err());
+        assert!(BarcodeStyle::new("2", "1001", "0").is_err());
+        assert!(BarcodeStyle::new("2", "50", "201").is_err());
+        assert!(BarcodeStyle::new("2", "x", "0").is_err());
+    }
+
+    #[test]
+    fn test_check_size() {
+        let symbol = encode(Symbology::Code128, &"x".repeat(80)).unwrap();
+        assert!(style("1", "100", "10").check_size(&symbol).is_ok());
+        assert!(style("20", "1000", "200").check_size(&symbol).is_err());
+
+        let symbol = encode(Symbology::Qr, &"x".repeat(500)).unwrap();
+        assert!(style("4", "1", "10").check_size(&symbol).is_ok());
+        assert!(style("20", "1", "200").check_size(&symbol).is_err());
+    }
+
+    #[test]
+    fn test_dark_runs() {
+        assert_eq!(
+            dark_runs(&[true, true, false, true, false, false, true]),
+            vec![(0, 2), (3, 1), (6, 1)]
+        );
+        assert!(dark_runs(&[false, false]).is_empty());
+    }
+
+    #[test]
+    fn test_to_svg() {
+        let symbol = encode(Symbology::Code128, "A<&").unwrap();
+        let svg = to_svg(&symbol, &style("2", "50", "10"));
+        let width = symbol.width * 2 + 20;
+        let height = 50 + TEXT_MARGIN + FONT_SIZE + 20;
+        assert!(svg.starts_with(&format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\"",
+            width, height
+        )));
+        assert!(svg.contains(">A&lt;&amp;</text>"));
+        // The first bar of the start symbol
+        assert!(svg.contains("<rect x=\"10\" y=\"10\" width=\"4\" height=\"50\"/>"));
+        assert!(svg.ends_with("</svg>"));
+
+        let symbol = encode(Symbology::Qr, "1").unwrap();
+        let svg = to_svg(&symbol, &style("3", "50", "0"));
+        assert!(!svg.contains("<text"));
+        assert!(svg.contains("<rect x=\"0\" y=\"0\" width=\"21\" height=\"3\"/>"));
+    }
+
+    #[test]
+    fn test_to_png() {
+        let symbol = encode(Symbology::Ean13, "978020137962").unwrap();
+        let data = to_png(&symbol, &style("2", "40", "5")).unwrap();
+        let decoder = png::Decoder::new(data.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!(info.width, 95 * 2 + 10);
+        assert_eq!(info.height, 40 + 10);
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+    }
+}
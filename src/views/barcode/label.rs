@@ -0,0 +1,245 @@
+use super::barcode::barcode_value;
+use super::symbology::{encode, Symbol, Symbology};
+use crate::error::BibErrorResponse;
+use crate::item::{atoi, search_items_range, BarcodeKind, Book, User};
+use crate::views::db_helper::get_db;
+use crate::views::id_allocator::get_barcode_setting;
+use crate::views::pdf::{fit_text, LabelSheet, PdfSheet};
+use crate::views::session::check_operator_session;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use futures::TryFutureExt;
+use log::debug;
+use serde::Deserialize;
+use shared_mongodb::ClientHolder;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+const PADDING: f32 = 2.0;
+const MAX_LINE_HEIGHT: f32 = 4.0;
+// The narrowest bar is not widened beyond this to keep it readable
+const MAX_MODULE_WIDTH: f32 = 0.5;
+
+/// The sheet is a preset, or "custom" with the layout given in mm.
+#[derive(Deserialize, Debug)]
+pub struct LabelPdfForm {
+    pub target: String,
+    pub id_start: String,
+    pub id_end: String,
+    pub barcode_type: String,
+    #[serde(default)]
+    pub barcode_size: String,
+    pub sheet: String,
+    #[serde(default)]
+    pub start_position: String,
+    #[serde(default)]
+    pub columns: String,
+    #[serde(default)]
+    pub rows: String,
+    #[serde(default)]
+    pub label_width: String,
+    #[serde(default)]
+    pub label_height: String,
+    #[serde(default)]
+    pub margin_top: String,
+    #[serde(default)]
+    pub margin_left: String,
+    #[serde(default)]
+    pub pitch_x: String,
+    #[serde(default)]
+    pub pitch_y: String,
+}
+
+/// A label with the barcode of the ID, and the name or the title.
+struct Label {
+    value: String,
+    caption: String,
+}
+
+fn parse_mm(value: &str) -> Result<f32, BibErrorResponse> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| BibErrorResponse::InvalidArgument(value.to_string()))
+}
+
+fn get_sheet(form: &LabelPdfForm) -> Result<LabelSheet, BibErrorResponse> {
+    if form.sheet != "custom" {
+        return LabelSheet::find_preset(&form.sheet)
+            .ok_or(BibErrorResponse::InvalidArgument(form.sheet.clone()));
+    }
+    let sheet = LabelSheet {
+        name: "custom",
+        columns: atoi(&form.columns)
+            .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?,
+        rows: atoi(&form.rows).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?,
+        label_width: parse_mm(&form.label_width)?,
+        label_height: parse_mm(&form.label_height)?,
+        margin_top: parse_mm(&form.margin_top)?,
+        margin_left: parse_mm(&form.margin_left)?,
+        pitch_x: parse_mm(&form.pitch_x)?,
+        pitch_y: parse_mm(&form.pitch_y)?,
+    };
+    sheet
+        .validate()
+        .map_err(BibErrorResponse::InvalidArgument)?;
+    Ok(sheet)
+}
+
+/// Parses the 1-based position of the first label on a partly used sheet.
+pub fn get_start_position(value: &str, sheet: &LabelSheet) -> Result<u32, BibErrorResponse> {
+    let start_position = match value.trim() {
+        "" => 1,
+        position => atoi(position).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?,
+    };
+    if !(1..=sheet.labels_per_page()).contains(&start_position) {
+        return Err(BibErrorResponse::InvalidArgument(
+            start_position.to_string(),
+        ));
+    }
+    Ok(start_position)
+}
+
+/// Parses the number of digits of the barcodes, 0 if not padded.
+pub fn get_barcode_size(value: &str) -> Result<usize, BibErrorResponse> {
+    let barcode_size = match value.trim() {
+        "" => 0,
+        size => atoi(size).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?,
+    };
+    Ok(barcode_size as usize)
+}
+
+/// Draws the symbol in the middle of the area, scaled to fit it.
+pub fn draw_symbol(pdf: &PdfSheet, symbol: &Symbol, x: f32, y: f32, width: f32, height: f32) {
+    let (module_width, row_height) = if symbol.is_linear() {
+        let module_width = (width / symbol.width as f32).min(MAX_MODULE_WIDTH);
+        (module_width, height)
+    } else {
+        let module_width = width.min(height) / symbol.width as f32;
+        (module_width, module_width)
+    };
+    let left = x + (width - module_width * symbol.width as f32) / 2.0;
+    let top = y + (height - row_height * symbol.rows.len() as f32) / 2.0;
+    for (i, row) in symbol.rows.iter().enumerate() {
+        let mut start = None;
+        for (j, dark) in row.iter().chain([false].iter()).enumerate() {
+            match (start, *dark) {
+                (None, true) => start = Some(j),
+                (Some(s), false) => {
+                    pdf.fill_rect(
+                        left + module_width * s as f32,
+                        top + row_height * i as f32,
+                        module_width * (j - s) as f32,
+                        row_height,
+                    );
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Lays out the labels from `start_index` of the first page, so that a
+/// partly used sheet can be printed on.
+fn write_label_pdf(
+    labels: &[Label],
+    symbology: Symbology,
+    sheet: &LabelSheet,
+    start_index: u32,
+) -> Result<Vec<u8>, BibErrorResponse> {
+    let mut pdf = PdfSheet::new("barcode labels")
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    let line_height = (sheet.label_height / 5.0).min(MAX_LINE_HEIGHT);
+    let font_size = line_height * 72.0 / 25.4 * 0.8;
+    let inner_width = sheet.label_width - PADDING * 2.0;
+    let bar_height = sheet.label_height - PADDING * 2.0 - line_height * 2.0;
+    if inner_width <= 0.0 || bar_height <= 0.0 {
+        return Err(BibErrorResponse::InvalidArgument(
+            "The labels are too small".to_string(),
+        ));
+    }
+    for (i, label) in labels.iter().enumerate() {
+        let index = (i as u32 + start_index) % sheet.labels_per_page();
+        if i > 0 && index == 0 {
+            pdf.add_page();
+        }
+        let symbol = encode(symbology, &label.value)
+            .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+
+        let (x, y) = sheet.position(index);
+        let center_x = x + sheet.label_width / 2.0;
+        let caption = fit_text(label.caption.trim(), font_size, inner_width);
+        pdf.centered_text(&caption, font_size, center_x, y + PADDING)?;
+
+        let bar_top = y + PADDING + line_height;
+        draw_symbol(&pdf, &symbol, x + PADDING, bar_top, inner_width, bar_height);
+
+        let text = fit_text(&label.value, font_size, inner_width);
+        pdf.centered_text(&text, font_size, center_x, bar_top + bar_height)?;
+    }
+
+    pdf.save()
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))
+}
+
+/// Creates a PDF of the barcode labels of the users or the books in the ID
+/// range, printed on an A4 label sheet.
+pub async fn get_label_pdf(
+    session: Session,
+    form: web::Query<LabelPdfForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let sheet = get_sheet(&form)?;
+    let symbology = Symbology::from_str(&form.barcode_type)
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let start_position = get_start_position(&form.start_position, &sheet)?;
+    let barcode_size = get_barcode_size(&form.barcode_size)?;
+
+    let barcode_setting = get_barcode_setting(&db).await?;
+
+    let start_id =
+        atoi(&form.id_start).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let end_id =
+        atoi(&form.id_end).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let labels: Vec<Label> = match form.target.as_str() {
+        "user" => search_items_range(&db, &User::default(), start_id, end_id)
+            .map_err(|_| BibErrorResponse::UserNotFound(start_id))
+            .await?
+            .into_iter()
+            .map(|user| Label {
+                value: barcode_value(BarcodeKind::User, user.id, barcode_size, &barcode_setting),
+                caption: user.name,
+            })
+            .collect(),
+        "book" => search_items_range(&db, &Book::default(), start_id, end_id)
+            .map_err(|_| BibErrorResponse::BookNotFound(start_id))
+            .await?
+            .into_iter()
+            .map(|book| Label {
+                value: barcode_value(BarcodeKind::Book, book.id, barcode_size, &barcode_setting),
+                caption: book.title,
+            })
+            .collect(),
+        target => return Err(BibErrorResponse::InvalidArgument(target.to_string())),
+    };
+    if labels.is_empty() {
+        return Err(BibErrorResponse::DataNotFound(String::new()));
+    }
+
+    let pdf_data = write_label_pdf(&labels, symbology, &sheet, start_position - 1)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/pdf")
+        .header(
+            "Content-Disposition",
+            format!("inline; filename=\"{}_labels.pdf\"", form.target),
+        )
+        .body(pdf_data))
+}
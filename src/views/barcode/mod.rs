@@ -1,6 +1,11 @@
 use crate::views::path::Path;
 use actix_web::web;
 mod barcode;
+mod card;
+mod image;
+mod label;
+mod spine;
+mod symbology;
 
 pub fn barcode_factory(app: &mut web::ServiceConfig) {
     let base_path: Path = Path {
@@ -25,5 +30,29 @@ pub fn barcode_factory(app: &mut web::ServiceConfig) {
     .route(
         &base_path.define(String::from("/book/generate")),
         web::post().to(barcode::generate_book_barcode),
+    )
+    .route(
+        &base_path.define(String::from("/image")),
+        web::get().to(image::get_barcode_image),
+    )
+    .route(
+        &base_path.define(String::from("/label/pdf")),
+        web::get().to(label::get_label_pdf),
+    )
+    .route(
+        &base_path.define(String::from("/card/pdf")),
+        web::get().to(card::get_card_pdf),
+    )
+    .route(
+        &base_path.define(String::from("/spine/page")),
+        web::get().to(spine::get_spine_page),
+    )
+    .route(
+        &base_path.define(String::from("/spine/generate")),
+        web::post().to(spine::generate_spine_label),
+    )
+    .route(
+        &base_path.define(String::from("/spine/pdf")),
+        web::get().to(spine::get_spine_pdf),
     );
 }
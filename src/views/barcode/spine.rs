@@ -0,0 +1,165 @@
+use crate::item::{atoi, search_items_by_ids, search_items_range, Book};
+use crate::views::content_loader::read_file;
+use crate::views::db_helper::get_db;
+use crate::views::pdf::{LabelSheet, PdfSheet};
+use crate::views::reply::Reply;
+use crate::{error::BibErrorResponse, views::session::check_operator_session};
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use mongodb::Database;
+use serde::Deserialize;
+use shared_mongodb::ClientHolder;
+use std::sync::Mutex;
+
+const SPINE_FONT_SIZE: f32 = 11.0;
+
+#[derive(Deserialize, Debug)]
+pub struct GenSpineLabelForm {
+    #[serde(default)]
+    pub book_id_start: String,
+    #[serde(default)]
+    pub book_id_end: String,
+    #[serde(default)]
+    pub book_ids: String,
+    pub sheet: String,
+}
+
+fn get_sheet(name: &str) -> Result<LabelSheet, BibErrorResponse> {
+    LabelSheet::find_preset(name).ok_or(BibErrorResponse::InvalidArgument(name.to_string()))
+}
+
+/// Returns the books listed in `book_ids` in the given order, or else the
+/// books within the ID range.
+async fn search_books(
+    db: &Database,
+    form: &GenSpineLabelForm,
+) -> Result<Vec<Book>, BibErrorResponse> {
+    let book = Book::default();
+    let book_ids: Vec<u32> = form
+        .book_ids
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|id| !id.is_empty())
+        .map(atoi)
+        .collect::<Result<_, _>>()
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+
+    if book_ids.is_empty() {
+        let start_id = atoi(&form.book_id_start)
+            .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+        let end_id = atoi(&form.book_id_end)
+            .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+        return search_items_range(db, &book, start_id, end_id)
+            .await
+            .map_err(|_| BibErrorResponse::BookNotFound(start_id));
+    }
+
+    let books = search_items_by_ids(db, &book, &book_ids)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    let mut selected_books = vec![];
+    for id in book_ids {
+        match books.iter().find(|book| book.id == id) {
+            Some(book) => selected_books.push(book.clone()),
+            None => return Err(BibErrorResponse::BookNotFound(id)),
+        }
+    }
+    Ok(selected_books)
+}
+
+pub async fn get_spine_page(
+    form: web::Query<GenSpineLabelForm>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    let sheet = get_sheet(&form.sheet)?;
+
+    let mut html_data = read_file("src/html/barcode/spine.html").unwrap();
+    html_data = html_data
+        .replace("{{BOOK_ID_START}}", &form.book_id_start)
+        .replace("{{BOOK_ID_END}}", &form.book_id_end)
+        .replace("{{BOOK_IDS}}", &form.book_ids)
+        .replace("{{SHEET}}", sheet.name)
+        .replace("{{COLUMNS}}", &sheet.columns.to_string())
+        .replace("{{ROWS}}", &sheet.rows.to_string())
+        .replace("{{LABEL_WIDTH}}", &sheet.label_width.to_string())
+        .replace("{{LABEL_HEIGHT}}", &sheet.label_height.to_string())
+        .replace("{{MARGIN_TOP}}", &sheet.margin_top.to_string())
+        .replace("{{MARGIN_LEFT}}", &sheet.margin_left.to_string())
+        .replace("{{PITCH_X}}", &sheet.pitch_x.to_string())
+        .replace("{{PITCH_Y}}", &sheet.pitch_y.to_string())
+        .replace("{{FONT_SIZE}}", &SPINE_FONT_SIZE.to_string());
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html_data))
+}
+
+pub async fn generate_spine_label(
+    session: Session,
+    form: web::Json<GenSpineLabelForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let mut books = search_books(&db, &form).await?;
+
+    let mut reply = Reply::default();
+    reply.book_list.append(&mut books);
+
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+fn write_spine_pdf(books: &[Book], sheet: &LabelSheet) -> Result<Vec<u8>, BibErrorResponse> {
+    let mut pdf =
+        PdfSheet::new("spine labels").map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    let line_height = sheet.label_height / 3.0;
+    let font_size = SPINE_FONT_SIZE.min(line_height * 72.0 / 25.4 * 0.8);
+    for (i, book) in books.iter().enumerate() {
+        let index = i as u32 % sheet.labels_per_page();
+        if i > 0 && index == 0 {
+            pdf.add_page();
+        }
+        let (x, y) = sheet.position(index);
+        pdf.rect(x, y, sheet.label_width, sheet.label_height);
+
+        let center_x = x + sheet.label_width / 2.0;
+        let symbols = [
+            &book.category_symbol,
+            &book.library_symbol,
+            &book.volume_symbol,
+        ];
+        for (line, symbol) in symbols.iter().enumerate() {
+            let top = y + line_height * line as f32 + (line_height - font_size * 25.4 / 72.0) / 2.0;
+            pdf.centered_text(symbol.trim(), font_size, center_x, top)?;
+        }
+    }
+
+    pdf.save()
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))
+}
+
+pub async fn get_spine_pdf(
+    session: Session,
+    form: web::Query<GenSpineLabelForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let sheet = get_sheet(&form.sheet)?;
+    let books = search_books(&db, &form).await?;
+    if books.is_empty() {
+        return Err(BibErrorResponse::DataNotFound(String::new()));
+    }
+
+    let pdf_data = write_spine_pdf(&books, &sheet)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/pdf")
+        .header(
+            "Content-Disposition",
+            "inline; filename=\"spine_labels.pdf\"",
+        )
+        .body(pdf_data))
+}
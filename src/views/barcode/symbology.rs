@@ -0,0 +1,470 @@
+use qrcode::{Color, QrCode};
+use std::error;
+use std::str::FromStr;
+
+/// The barcode types rendered by the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Symbology {
+    Code39,
+    Code128,
+    // NW-7
+    Codabar,
+    Ean13,
+    Qr,
+}
+
+impl FromStr for Symbology {
+    type Err = String;
+
+    /// Accepts the names used by the barcode pages as well.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "code39" => Ok(Symbology::Code39),
+            "code128" => Ok(Symbology::Code128),
+            "codabar" | "nw7" | "nw-7" => Ok(Symbology::Codabar),
+            "ean13" | "ean-13" => Ok(Symbology::Ean13),
+            "qr" | "qrcode" => Ok(Symbology::Qr),
+            _ => Err(format!("Unsupported barcode type {}", s)),
+        }
+    }
+}
+
+impl Symbology {
+    /// The longest value accepted, which keeps a barcode readable by the
+    /// scanners and bounds the size of the rendered image.
+    pub fn max_len(&self) -> usize {
+        match self {
+            Symbology::Code39 | Symbology::Codabar => 40,
+            Symbology::Code128 => 80,
+            Symbology::Ean13 => 13,
+            Symbology::Qr => 500,
+        }
+    }
+}
+
+/// The modules of an encoded barcode, dark if true. A linear barcode has a
+/// single row that is drawn at the bar height.
+pub struct Symbol {
+    pub width: usize,
+    pub rows: Vec<Vec<bool>>,
+    // The text printed under a linear barcode
+    pub text: String,
+}
+
+impl Symbol {
+    fn linear(modules: Vec<bool>, text: String) -> Self {
+        Self {
+            width: modules.len(),
+            rows: vec![modules],
+            text,
+        }
+    }
+
+    pub fn is_linear(&self) -> bool {
+        self.rows.len() == 1
+    }
+}
+
+pub fn encode(symbology: Symbology, value: &str) -> Result<Symbol, Box<dyn error::Error>> {
+    if value.is_empty() {
+        return Err("The value is empty".into());
+    }
+    if value.chars().count() > symbology.max_len() {
+        return Err(format!(
+            "The value is longer than {} characters",
+            symbology.max_len()
+        )
+        .into());
+    }
+    match symbology {
+        Symbology::Code39 => encode_code39(value),
+        Symbology::Code128 => encode_code128(value),
+        Symbology::Codabar => encode_codabar(value),
+        Symbology::Ean13 => encode_ean13(value),
+        Symbology::Qr => encode_qr(value),
+    }
+}
+
+/// Appends the bars and the spaces that alternate from a bar, each of the
+/// given number of modules.
+fn push_widths(modules: &mut Vec<bool>, widths: impl Iterator<Item = usize>) {
+    for (i, width) in widths.enumerate() {
+        modules.extend(std::iter::repeat_n(i % 2 == 0, width));
+    }
+}
+
+// The wide elements of the 5 bars and 4 spaces of each character
+const CODE39: &[(char, &str)] = &[
+    ('0', "000110100"),
+    ('1', "100100001"),
+    ('2', "001100001"),
+    ('3', "101100000"),
+    ('4', "000110001"),
+    ('5', "100110000"),
+    ('6', "001110000"),
+    ('7', "000100101"),
+    ('8', "100100100"),
+    ('9', "001100100"),
+    ('A', "100001001"),
+    ('B', "001001001"),
+    ('C', "101001000"),
+    ('D', "000011001"),
+    ('E', "100011000"),
+    ('F', "001011000"),
+    ('G', "000001101"),
+    ('H', "100001100"),
+    ('I', "001001100"),
+    ('J', "000011100"),
+    ('K', "100000011"),
+    ('L', "001000011"),
+    ('M', "101000010"),
+    ('N', "000010011"),
+    ('O', "100010010"),
+    ('P', "001010010"),
+    ('Q', "000000111"),
+    ('R', "100000110"),
+    ('S', "001000110"),
+    ('T', "000010110"),
+    ('U', "110000001"),
+    ('V', "011000001"),
+    ('W', "111000000"),
+    ('X', "010010001"),
+    ('Y', "110010000"),
+    ('Z', "011010000"),
+    ('-', "010000101"),
+    ('.', "110000100"),
+    (' ', "011000100"),
+    ('$', "010101000"),
+    ('/', "010100010"),
+    ('+', "010001010"),
+    ('%', "000101010"),
+    ('*', "010010100"),
+];
+
+// The codes of NW-7, whose value is enclosed by the start and stop
+// characters A to D
+const CODABAR: &[(char, &str)] = &[
+    ('0', "0000011"),
+    ('1', "0000110"),
+    ('2', "0001001"),
+    ('3', "1100000"),
+    ('4', "0010010"),
+    ('5', "1000010"),
+    ('6', "0100001"),
+    ('7', "0100100"),
+    ('8', "0110000"),
+    ('9', "1001000"),
+    ('-', "0001100"),
+    ('$', "0011000"),
+    (':', "1000101"),
+    ('/', "1010001"),
+    ('.', "1010100"),
+    ('+', "0010101"),
+    ('A', "0011010"),
+    ('B', "0101001"),
+    ('C', "0001011"),
+    ('D', "0001110"),
+];
+
+// The wide elements are 3 modules and the characters are separated by a
+// narrow space
+const WIDE: usize = 3;
+
+fn push_wide_narrow(modules: &mut Vec<bool>, pattern: &str) {
+    push_widths(
+        modules,
+        pattern.chars().map(|c| if c == '1' { WIDE } else { 1 }),
+    );
+}
+
+fn lookup<'a>(table: &[(char, &'a str)], c: char) -> Result<&'a str, Box<dyn error::Error>> {
+    table
+        .iter()
+        .find(|(code, _)| *code == c)
+        .map(|(_, pattern)| *pattern)
+        .ok_or_else(|| format!("The character {} cannot be encoded", c).into())
+}
+
+fn encode_code39(value: &str) -> Result<Symbol, Box<dyn error::Error>> {
+    let value = value.to_ascii_uppercase();
+    if value.contains('*') {
+        return Err("The character * cannot be encoded".into());
+    }
+    let mut modules = vec![];
+    for c in format!("*{}*", value).chars() {
+        if !modules.is_empty() {
+            modules.push(false);
+        }
+        push_wide_narrow(&mut modules, lookup(CODE39, c)?);
+    }
+    Ok(Symbol::linear(modules, value))
+}
+
+fn encode_codabar(value: &str) -> Result<Symbol, Box<dyn error::Error>> {
+    // The added start and stop characters are not printed
+    let text = value.to_ascii_uppercase();
+    let is_guard = |c: char| matches!(c, 'A'..='D');
+    let enclosed = text.starts_with(is_guard) && text.ends_with(is_guard) && text.len() > 1;
+    let value = if enclosed {
+        text.clone()
+    } else {
+        format!("A{}A", text)
+    };
+    let inner = &value[1..value.len() - 1];
+    if inner.contains(is_guard) {
+        return Err("The start and stop characters are used inside".into());
+    }
+
+    let mut modules = vec![];
+    for c in value.chars() {
+        if !modules.is_empty() {
+            modules.push(false);
+        }
+        push_wide_narrow(&mut modules, lookup(CODABAR, c)?);
+    }
+    Ok(Symbol::linear(modules, text))
+}
+
+// The bar and space widths of the symbols 0 to 106
+const CODE128: [&str; 107] = [
+    "212222", "222122", "222221", "121223", "121322", "131222", "122213", "122312", "132212",
+    "221213", "221312", "231212", "112232", "122132", "122231", "113222", "123122", "123221",
+    "223211", "221132", "221231", "213212", "223112", "312131", "311222", "321122", "321221",
+    "312212", "322112", "322211", "212123", "212321", "232121", "111323", "131123", "131321",
+    "112313", "132113", "132311", "211313", "231113", "231311", "112133", "112331", "132131",
+    "113123", "113321", "133121", "313121", "211331", "231131", "213113", "213311", "213131",
+    "311123", "311321", "331121", "312113", "312311", "332111", "314111", "221411", "431111",
+    "111224", "111422", "121124", "121421", "141122", "141221", "112214", "112412", "122114",
+    "122411", "142112", "142211", "241211", "221114", "413111", "241112", "134111", "111242",
+    "121142", "121241", "114212", "124112", "124211", "411212", "421112", "421211", "212141",
+    "214121", "412121", "111143", "111341", "131141", "114113", "114311", "411113", "411311",
+    "113141", "114131", "311141", "411131", "211412", "211214", "211232", "2331112",
+];
+const CODE128_START_B: usize = 104;
+const CODE128_START_C: usize = 105;
+const CODE128_STOP: usize = 106;
+
+/// The start symbol and the data symbols weighted by their positions, where
+/// the start symbol counts as the first one.
+fn code128_checksum(symbols: &[usize]) -> usize {
+    symbols
+        .iter()
+        .enumerate()
+        .map(|(i, symbol)| i.max(1) * symbol)
+        .sum::<usize>()
+        % 103
+}
+
+/// Uses code set C for an even number of digits and code set B otherwise.
+fn encode_code128(value: &str) -> Result<Symbol, Box<dyn error::Error>> {
+    let mut symbols = vec![];
+    if value.len().is_multiple_of(2) && value.bytes().all(|c| c.is_ascii_digit()) {
+        symbols.push(CODE128_START_C);
+        for pair in value.as_bytes().chunks(2) {
+            symbols.push(((pair[0] - b'0') * 10 + (pair[1] - b'0')) as usize);
+        }
+    } else {
+        symbols.push(CODE128_START_B);
+        for c in value.chars() {
+            if !(' '..='~').contains(&c) {
+                return Err(format!("The character {} cannot be encoded", c).into());
+            }
+            symbols.push(c as usize - ' ' as usize);
+        }
+    }
+    symbols.push(code128_checksum(&symbols));
+    symbols.push(CODE128_STOP);
+
+    let mut modules = vec![];
+    for symbol in symbols {
+        let widths = CODE128[symbol].bytes().map(|w| (w - b'0') as usize);
+        push_widths(&mut modules, widths);
+    }
+    Ok(Symbol::linear(modules, value.to_string()))
+}
+
+const EAN_L: [&str; 10] = [
+    "0001101", "0011001", "0010011", "0111101", "0100011", "0110001", "0101111", "0111011",
+    "0110111", "0001011",
+];
+const EAN_G: [&str; 10] = [
+    "0100111", "0110011", "0011011", "0100001", "0011101", "0111001", "0000101", "0010001",
+    "0001001", "0010111",
+];
+// Whether each digit of the left half uses the G code, by the first digit
+const EAN_PARITY: [&str; 10] = [
+    "000000", "001011", "001101", "001110", "010011", "011001", "011100", "010101", "010110",
+    "011010",
+];
+
+pub fn ean13_check_digit(digits: &[u8]) -> u8 {
+    let sum: u32 = digits
+        .iter()
+        .enumerate()
+        .map(|(i, d)| *d as u32 * if i % 2 == 1 { 3 } else { 1 })
+        .sum();
+    ((10 - sum % 10) % 10) as u8
+}
+
+/// Takes the 12 digits, or the 13 digits with a correct check digit.
+fn encode_ean13(value: &str) -> Result<Symbol, Box<dyn error::Error>> {
+    if !value.bytes().all(|c| c.is_ascii_digit()) || !(12..=13).contains(&value.len()) {
+        return Err(format!("{} is not 12 or 13 digits", value).into());
+    }
+    let mut digits: Vec<u8> = value.bytes().map(|c| c - b'0').collect();
+    let check_digit = ean13_check_digit(&digits[..12]);
+    match digits.get(12) {
+        Some(digit) if *digit != check_digit => {
+            return Err(format!("The check digit of {} should be {}", value, check_digit).into());
+        }
+        Some(_) => {}
+        None => digits.push(check_digit),
+    }
+
+    let bits = |pattern: &str, invert: bool| -> Vec<bool> {
+        pattern.chars().map(|c| (c == '1') != invert).collect()
+    };
+    let parity = EAN_PARITY[digits[0] as usize].as_bytes();
+    let mut modules = bits("101", false);
+    for (i, digit) in digits[1..7].iter().enumerate() {
+        let table = if parity[i] == b'1' { &EAN_G } else { &EAN_L };
+        modules.extend(bits(table[*digit as usize], false));
+    }
+    modules.extend(bits("01010", false));
+    for digit in &digits[7..] {
+        // The R code is the complement of the L code
+        modules.extend(bits(EAN_L[*digit as usize], true));
+    }
+    modules.extend(bits("101", false));
+
+    let text = digits.iter().map(|d| (b'0' + d) as char).collect();
+    Ok(Symbol::linear(modules, text))
+}
+
+fn encode_qr(value: &str) -> Result<Symbol, Box<dyn error::Error>> {
+    let code = QrCode::new(value.as_bytes())?;
+    let width = code.width();
+    let colors = code.to_colors();
+    let rows = colors
+        .chunks(width)
+        .map(|row| row.iter().map(|color| *color == Color::Dark).collect())
+        .collect();
+    Ok(Symbol {
+        width,
+        rows,
+        text: String::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ean13_check_digit() {
+        let digits = |value: &str| -> Vec<u8> { value.bytes().map(|c| c - b'0').collect() };
+        assert_eq!(ean13_check_digit(&digits("978020137962")), 4);
+        assert_eq!(ean13_check_digit(&digits("490123456789")), 4);
+        assert_eq!(ean13_check_digit(&digits("000000000000")), 0);
+    }
+
+    #[test]
+    fn test_encode_ean13() {
+        let symbol = encode_ean13("978020137962").unwrap();
+        assert_eq!(symbol.text, "9780201379624");
+        assert_eq!(symbol.width, 95);
+        assert!(encode_ean13("9780201379624").is_ok());
+
+        let e = encode_ean13("9780201379625").err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "The check digit of 9780201379625 should be 4"
+        );
+        assert!(encode_ean13("97802013796").is_err());
+        assert!(encode_ean13("97802013796a").is_err());
+    }
+
+    #[test]
+    fn test_code128_checksum() {
+        // "PJJ123C" in code set B:
+        // 104 + 48 + 42*2 + 42*3 + 17*4 + 18*5 + 19*6 + 35*7 = 879 = 103*8 + 55
+        assert_eq!(
+            code128_checksum(&[CODE128_START_B, 48, 42, 42, 17, 18, 19, 35]),
+            55
+        );
+        // "123456" in code set C: 105 + 12 + 34*2 + 56*3 = 353 = 103*3 + 44
+        assert_eq!(code128_checksum(&[CODE128_START_C, 12, 34, 56]), 44);
+    }
+
+    fn modules(symbol: &Symbol) -> String {
+        symbol.rows[0]
+            .iter()
+            .map(|dark| if *dark { '1' } else { '0' })
+            .collect()
+    }
+
+    #[test]
+    fn test_encode_code39() {
+        let symbol = encode(Symbology::Code39, "a-1").unwrap();
+        assert_eq!(symbol.text, "A-1");
+        assert!(symbol.is_linear());
+        // 5 characters of 3 wide and 6 narrow elements, separated by a space
+        assert_eq!(symbol.width, 5 * (3 * WIDE + 6) + 4);
+        // The start character *
+        assert!(modules(&symbol).starts_with("100010111011101"));
+
+        assert!(encode(Symbology::Code39, "A*B").is_err());
+        assert!(encode(Symbology::Code39, "A#B").is_err());
+    }
+
+    #[test]
+    fn test_encode_codabar() {
+        let symbol = encode(Symbology::Codabar, "123").unwrap();
+        assert_eq!(symbol.text, "123");
+        let enclosed = encode(Symbology::Codabar, "A123A").unwrap();
+        assert_eq!(enclosed.text, "A123A");
+        assert_eq!(modules(&symbol), modules(&enclosed));
+
+        assert!(encode(Symbology::Codabar, "b123d").is_ok());
+        assert!(encode(Symbology::Codabar, "A1B2A").is_err());
+        assert!(encode(Symbology::Codabar, "12E").is_err());
+    }
+
+    #[test]
+    fn test_encode_code128() {
+        // Start C, 12, 34, 56, the checksum and the stop symbol
+        let symbol = encode(Symbology::Code128, "123456").unwrap();
+        assert_eq!(symbol.width, 5 * 11 + 13);
+        assert!(modules(&symbol).ends_with("1100011101011"));
+        // Code set B for an odd number of digits or letters
+        let symbol = encode(Symbology::Code128, "PJJ123C").unwrap();
+        assert_eq!(symbol.width, 9 * 11 + 13);
+        assert_eq!(symbol.text, "PJJ123C");
+
+        assert!(encode(Symbology::Code128, "図書").is_err());
+    }
+
+    #[test]
+    fn test_encode_qr() {
+        let symbol = encode(Symbology::Qr, "https://example.com/").unwrap();
+        assert!(!symbol.is_linear());
+        assert_eq!(symbol.rows.len(), symbol.width);
+        assert!(symbol.rows.iter().all(|row| row.len() == symbol.width));
+        assert!(symbol.text.is_empty());
+        // The finder pattern at the top left
+        assert!(symbol.rows[0][..7].iter().all(|dark| *dark));
+    }
+
+    #[test]
+    fn test_encode_max_len() {
+        assert!(encode(Symbology::Code39, "").is_err());
+        for symbology in [Symbology::Code39, Symbology::Codabar, Symbology::Code128] {
+            let value = "1".repeat(symbology.max_len());
+            assert!(encode(symbology, &value).is_ok());
+            let value = "1".repeat(symbology.max_len() + 1);
+            assert!(encode(symbology, &value).is_err());
+        }
+        assert!(encode(Symbology::Qr, &"x".repeat(500)).is_ok());
+        assert!(encode(Symbology::Qr, &"x".repeat(501)).is_err());
+    }
+}
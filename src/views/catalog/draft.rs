@@ -0,0 +1,365 @@
+use crate::error::BibErrorResponse;
+use crate::item::{atoi, delete_item, delete_item_all, insert_item, search_item, search_items};
+use crate::item::{normalize_isbn, update_item};
+use crate::item::{Book, CatalogDraft, DuplicateIndex, SystemSetting};
+use crate::views::change_log::record_change;
+use crate::views::content_loader::read_file;
+use crate::views::db_helper::get_db;
+use crate::views::edit::UpdateBookForm;
+use crate::views::id_allocator::new_book_id_allocator;
+use crate::views::reply::Reply;
+use crate::views::session::check_operator_session;
+use crate::views::utils::fetch_book_info;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use futures::future::join_all;
+use futures::stream::{self, StreamExt};
+use log::{debug, info};
+use mongodb::Database;
+use serde::Deserialize;
+use shared_mongodb::{database, ClientHolder};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub async fn load(_session: Session) -> HttpResponse {
+    let html_data = read_file("src/html/catalog.html").unwrap();
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html_data)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ScanIsbnForm {
+    pub isbn_list: String,
+    pub book_location: String,
+    pub book_category: String,
+    pub book_register_date: String,
+    pub book_register_type: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteDraftForm {
+    pub book_id: String,
+}
+
+async fn get_drafts_from_db(db: &Database) -> Vec<CatalogDraft> {
+    let draft = CatalogDraft::default();
+    search_items(db, &draft).await.unwrap_or_default()
+}
+
+pub async fn scan(
+    session: Session,
+    form: web::Json<ScanIsbnForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    let dbname = check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let setting = {
+        let setting_map = setting_map.lock().unwrap();
+        setting_map.get(&dbname).cloned()
+    };
+    let setting = setting.ok_or(BibErrorResponse::NotAuthorized)?;
+
+    let isbn_list: Vec<String> = form
+        .isbn_list
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|isbn| !isbn.is_empty())
+        .map(normalize_isbn)
+        .collect::<Result<_, _>>()
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    if isbn_list.is_empty() {
+        return Err(BibErrorResponse::InvalidArgument("isbn_list".to_string()));
+    }
+
+    // The drafts are committed at once, so they are limited in the same way
+    let drafts = get_drafts_from_db(&db).await;
+    if drafts.len() + isbn_list.len() > setting.max_parallel_registrations as usize {
+        return Err(BibErrorResponse::ExceedLimitInParallel(
+            setting.max_parallel_registrations,
+        ));
+    }
+
+    // Resolve the ISBNs concurrently with the runtime reqwest needs, out of
+    // the worker thread
+    let num_threads = setting.num_threads.max(1) as usize;
+    let books = web::block(move || {
+        let fut = stream::iter(isbn_list)
+            .map(|isbn| async move {
+                match fetch_book_info(&isbn).await {
+                    Ok(book) => (book, true),
+                    Err(e) => {
+                        info!("isbn({}) is not resolved: {}", isbn, e);
+                        let book = Book {
+                            isbn,
+                            ..Book::default()
+                        };
+                        (book, false)
+                    }
+                }
+            })
+            .buffered(num_threads)
+            .collect::<Vec<_>>();
+        tokio::runtime::Runtime::new().map(|rt| rt.block_on(fut))
+    })
+    .await
+    .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    // Assign the book IDs following the last registered book or draft
+    let book = Book::default();
+    let registered_books = search_items(&db, &book).await.unwrap_or_default();
+    let mut allocator = new_book_id_allocator(&db, &registered_books).await?;
+    for draft in drafts {
+        allocator.reserve(draft.id);
+    }
+
+    let mut new_drafts = vec![];
+    for (mut book, resolved) in books {
+        let book_id = allocator.allocate()?;
+        book.location = form.book_location.clone();
+        book.category = form.book_category.clone();
+        book.register_date = form.book_register_date.clone();
+        book.register_type = form.book_register_type.clone();
+        let draft = CatalogDraft::new(book_id, book, resolved);
+        insert_item(&db, &draft)
+            .await
+            .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+        new_drafts.push(draft);
+    }
+
+    let reply = Reply {
+        draft_list: new_drafts,
+        ..Default::default()
+    };
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+pub async fn get_drafts(
+    session: Session,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let reply = Reply {
+        draft_list: get_drafts_from_db(&db).await,
+        ..Default::default()
+    };
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+pub async fn update_draft(
+    session: Session,
+    form: web::Json<UpdateBookForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let draft = CatalogDraft {
+        id: atoi(&form.book_id).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?,
+        ..Default::default()
+    };
+    let draft = search_item(&db, &draft)
+        .await
+        .map_err(|_| BibErrorResponse::BookNotFound(draft.id))?;
+
+    let isbn = normalize_isbn(&form.book_isbn)
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+
+    let book = Book::new(
+        &form.book_id,
+        &form.book_title,
+        &form.book_location,
+        &form.book_category,
+        &form.book_status,
+        &form.book_author,
+        &form.book_publisher,
+        &form.book_published_date,
+        &form.book_series,
+        &form.book_volume,
+        &form.book_page,
+        &form.book_kana,
+        &form.book_category_symbol,
+        &form.book_library_symbol,
+        &form.book_volume_symbol,
+        &form.book_forbidden,
+        &form.book_remark,
+        &isbn,
+        &form.book_register_date,
+        &form.book_register_type,
+    )
+    .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let draft = CatalogDraft::new(draft.id, book, draft.resolved);
+
+    update_item(&db, &draft)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    let reply = Reply::default();
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+pub async fn delete_draft(
+    session: Session,
+    form: web::Json<DeleteDraftForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let draft = CatalogDraft {
+        id: atoi(&form.book_id).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?,
+        ..Default::default()
+    };
+    delete_item(&db, &draft)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    let reply = Reply::default();
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+pub async fn delete_drafts(
+    session: Session,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let draft = CatalogDraft::default();
+    delete_item_all(&db, &draft)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    let reply = Reply::default();
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CommitDraftsForm {
+    #[serde(default)]
+    pub allow_duplicate: String,
+}
+
+/// Registers a draft as a book. The draft is removed as soon as the book is
+/// inserted, so that a commit that failed partway can be retried.
+async fn commit_draft(
+    db: &Database,
+    session: &Session,
+    time_zone: &str,
+    draft: &CatalogDraft,
+) -> Result<(), BibErrorResponse> {
+    insert_item(db, &draft.book)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    delete_item(db, draft)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    record_change(db, session, time_zone, "insert", None, Some(&draft.book)).await
+}
+
+pub async fn commit(
+    session: Session,
+    form: web::Json<CommitDraftsForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    let dbname = check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let setting = {
+        let setting_map = setting_map.lock().unwrap();
+        setting_map.get(&dbname).cloned()
+    };
+    let setting = setting.ok_or(BibErrorResponse::NotAuthorized)?;
+
+    let drafts = get_drafts_from_db(&db).await;
+    if drafts.is_empty() {
+        return Err(BibErrorResponse::DataNotFound(String::new()));
+    }
+
+    // Check the number of items that can be registered at once
+    let ndrafts: u32 = drafts.len().try_into().unwrap();
+    if ndrafts > setting.max_parallel_registrations {
+        return Err(BibErrorResponse::ExceedLimitInParallel(
+            setting.max_parallel_registrations,
+        ));
+    }
+
+    // Check the number of items
+    let book = Book::default();
+    let books = search_items(&db, &book).await.unwrap_or_default();
+    let nbooks: u32 = books.len().try_into().unwrap();
+    let nsize = nbooks + ndrafts;
+    if nsize > setting.max_registered_books {
+        return Err(BibErrorResponse::ExceedLimit(nsize));
+    }
+
+    // The IDs may have been taken by a manual registration after the scan
+    let registered: HashMap<u32, bool> = books.iter().map(|book| (book.id, true)).collect();
+    for draft in &drafts {
+        if registered.contains_key(&draft.id) {
+            return Err(BibErrorResponse::ItemAlreadyExists(draft.id));
+        }
+    }
+
+    // Warn about other copies, among the drafts as well, unless the operator
+    // has confirmed them
+    if form.allow_duplicate != "true" {
+        let mut index = DuplicateIndex::new(&books);
+        let mut duplicates = vec![];
+        for draft in &drafts {
+            for (id, title) in index.find(&draft.book) {
+                duplicates.push((draft.id, id, title));
+            }
+            index.add(&draft.book);
+        }
+        if !duplicates.is_empty() {
+            return Err(BibErrorResponse::BookDuplicated(duplicates));
+        }
+    }
+
+    // Update the DB
+    let mut num_processed: usize = 0;
+    loop {
+        let mut futures = vec![];
+        loop {
+            if futures.len() as u32 == setting.num_threads || num_processed as u32 == ndrafts {
+                break;
+            }
+            futures.push(commit_draft(
+                &db,
+                &session,
+                &setting.time_zone,
+                &drafts[num_processed],
+            ));
+            num_processed += 1;
+        }
+        let reses = join_all(futures).await;
+        for res in reses {
+            if let Err(e) = res {
+                database::disconnect(&data);
+                return Err(e);
+            }
+        }
+        if num_processed as u32 == ndrafts {
+            break;
+        }
+    }
+
+    let reply = Reply {
+        draft_list: drafts,
+        ..Default::default()
+    };
+    Ok(HttpResponse::Ok().json(reply))
+}
@@ -0,0 +1,37 @@
+use crate::views::path::Path;
+use actix_web::web;
+mod draft;
+
+pub fn catalog_factory(app: &mut web::ServiceConfig) {
+    let base_path: Path = Path {
+        prefix: String::from("/catalog"),
+    };
+    app.route(
+        &base_path.define(String::from("/main")),
+        web::get().to(draft::load),
+    )
+    .route(
+        &base_path.define(String::from("/scan")),
+        web::post().to(draft::scan),
+    )
+    .route(
+        &base_path.define(String::from("/drafts")),
+        web::get().to(draft::get_drafts),
+    )
+    .route(
+        &base_path.define(String::from("/drafts")),
+        web::delete().to(draft::delete_drafts),
+    )
+    .route(
+        &base_path.define(String::from("/draft")),
+        web::put().to(draft::update_draft),
+    )
+    .route(
+        &base_path.define(String::from("/draft")),
+        web::delete().to(draft::delete_draft),
+    )
+    .route(
+        &base_path.define(String::from("/commit")),
+        web::post().to(draft::commit),
+    );
+}
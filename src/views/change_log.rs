@@ -0,0 +1,253 @@
+use crate::error::BibErrorResponse;
+use crate::item::{insert_item, Book, ChangeLog, FieldChange, User};
+use crate::views::session::get_uname;
+use crate::views::utils::get_nowtime;
+use actix_session::Session;
+use chrono::Utc;
+use mongodb::Database;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::error;
+
+// The loan status is kept by the rental process, not by the operator
+const UNTRACKED_FIELDS: [&str; 4] = [
+    "borrowed_books",
+    "borrowed_count",
+    "owner_id",
+    "return_deadline",
+];
+
+/// The records whose changes are logged.
+pub trait Tracked: Serialize {
+    const TARGET: &'static str;
+    fn target_id(&self) -> u32;
+}
+
+impl Tracked for User {
+    const TARGET: &'static str = "user";
+    fn target_id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Tracked for Book {
+    const TARGET: &'static str = "book";
+    fn target_id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// Returns the tracked fields of a record with the custom fields flattened
+/// as `custom_fields.<name>`.
+fn tracked_fields<T: Serialize>(item: Option<&T>) -> BTreeMap<String, Value> {
+    let mut fields = BTreeMap::new();
+    let object = match item.map(serde_json::to_value) {
+        Some(Ok(Value::Object(object))) => object,
+        _ => return fields,
+    };
+    for (key, value) in object {
+        if UNTRACKED_FIELDS.contains(&key.as_str()) {
+            continue;
+        }
+        match value {
+            Value::Object(custom_fields) if key == "custom_fields" => {
+                for (name, value) in custom_fields {
+                    fields.insert(format!("custom_fields.{}", name), value);
+                }
+            }
+            value => {
+                fields.insert(key, value);
+            }
+        }
+    }
+    fields
+}
+
+fn diff_fields<T: Serialize>(before: Option<&T>, after: Option<&T>) -> Vec<FieldChange> {
+    let before = tracked_fields(before);
+    let after = tracked_fields(after);
+
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let before = before.get(name).cloned().unwrap_or(Value::Null);
+            let after = after.get(name).cloned().unwrap_or(Value::Null);
+            if before == after {
+                return None;
+            }
+            Some(FieldChange {
+                field: name.clone(),
+                before,
+                after,
+            })
+        })
+        .collect()
+}
+
+/// Saves the field-level changes of a user or a book made by the operator.
+pub async fn record_change<T: Tracked>(
+    db: &Database,
+    session: &Session,
+    time_zone: &str,
+    operation: &str,
+    before: Option<&T>,
+    after: Option<&T>,
+) -> Result<(), BibErrorResponse> {
+    let operator = get_uname(session)?;
+    record_change_by(db, &operator, time_zone, operation, before, after).await
+}
+
+/// Saves the changes made out of a request, such as by an import job.
+pub async fn record_change_by<T: Tracked>(
+    db: &Database,
+    operator: &str,
+    time_zone: &str,
+    operation: &str,
+    before: Option<&T>,
+    after: Option<&T>,
+) -> Result<(), BibErrorResponse> {
+    let changes = diff_fields(before, after);
+    let target_id = match before.or(after) {
+        Some(item) if !changes.is_empty() => item.target_id(),
+        _ => return Ok(()),
+    };
+
+    let log = ChangeLog {
+        id: Utc::now().timestamp_micros(),
+        target: T::TARGET.to_string(),
+        target_id,
+        operation: operation.to_string(),
+        operator: operator.to_string(),
+        changed_at: format!("{}", get_nowtime(time_zone).format("%Y/%m/%d %H:%M:%S")),
+        changes,
+    };
+    insert_item(db, &log)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))
+}
+
+/// Rebuilds the version of a record right after the change `log_id` by
+/// undoing the later changes on the current record. `logs` must be sorted
+/// in the order of the changes.
+pub fn restore_version<T: Serialize + DeserializeOwned>(
+    current: &T,
+    logs: &[ChangeLog],
+    log_id: i64,
+) -> Result<T, Box<dyn error::Error>> {
+    let mut object = match serde_json::to_value(current)? {
+        Value::Object(object) => object,
+        _ => return Err("Invalid record".into()),
+    };
+
+    for log in logs.iter().rev().take_while(|log| log.id > log_id) {
+        for change in &log.changes {
+            match change.field.strip_prefix("custom_fields.") {
+                Some(name) => {
+                    let custom_fields = object
+                        .entry("custom_fields")
+                        .or_insert_with(|| Value::Object(Map::new()));
+                    if let Value::Object(custom_fields) = custom_fields {
+                        set_field(custom_fields, name, &change.before);
+                    }
+                }
+                None => set_field(&mut object, &change.field, &change.before),
+            }
+        }
+    }
+
+    Ok(serde_json::from_value(Value::Object(object))?)
+}
+
+fn set_field(object: &mut Map<String, Value>, name: &str, value: &Value) {
+    if value.is_null() {
+        object.remove(name);
+    } else {
+        object.insert(name.to_string(), value.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn book(title: &str, owner_id: Option<u32>, custom_fields: &[(&str, &str)]) -> Book {
+        Book {
+            id: 1,
+            title: title.to_string(),
+            owner_id,
+            custom_fields: custom_fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Book::default()
+        }
+    }
+
+    fn log(id: i64, before: Option<&Book>, after: Option<&Book>) -> ChangeLog {
+        ChangeLog {
+            id,
+            changes: diff_fields(before, after),
+            ..ChangeLog::default()
+        }
+    }
+
+    #[test]
+    fn test_diff_fields() {
+        let before = book("A", None, &[("棚", "1")]);
+        let after = book("B", Some(3), &[("メモ", "x")]);
+        let changes = diff_fields(Some(&before), Some(&after));
+        let changes: Vec<(&str, &Value, &Value)> = changes
+            .iter()
+            .map(|change| (change.field.as_str(), &change.before, &change.after))
+            .collect();
+        // The loan status is not a change
+        assert_eq!(
+            changes,
+            vec![
+                ("custom_fields.メモ", &Value::Null, &json!("x")),
+                ("custom_fields.棚", &json!("1"), &Value::Null),
+                ("title", &json!("A"), &json!("B")),
+            ]
+        );
+        assert!(diff_fields(Some(&before), Some(&book("A", Some(3), &[("棚", "1")]))).is_empty());
+        assert!(diff_fields::<Book>(None, None).is_empty());
+        // An insert has every field
+        let inserted = diff_fields(None, Some(&before));
+        assert!(inserted.iter().all(|change| change.before.is_null()));
+        assert!(inserted
+            .iter()
+            .any(|change| change.field == "custom_fields.棚"));
+    }
+
+    #[test]
+    fn test_restore_version() {
+        let v1 = book("A", None, &[("棚", "1")]);
+        let v2 = book("B", None, &[("棚", "1"), ("メモ", "x")]);
+        let v3 = book("C", Some(3), &[("メモ", "y")]);
+        let logs = vec![
+            log(10, None, Some(&v1)),
+            log(20, Some(&v1), Some(&v2)),
+            log(30, Some(&v2), Some(&v3)),
+        ];
+
+        let restored = restore_version(&v3, &logs, 20).unwrap();
+        assert_eq!(restored.title, "B");
+        assert_eq!(restored.custom_fields, v2.custom_fields);
+        // The loan status stays as it is now
+        assert_eq!(restored.owner_id, Some(3));
+
+        let restored = restore_version(&v3, &logs, 10).unwrap();
+        assert_eq!(restored.title, "A");
+        assert_eq!(restored.custom_fields, v1.custom_fields);
+
+        let restored = restore_version(&v3, &logs, 30).unwrap();
+        assert_eq!(restored.title, "C");
+    }
+}
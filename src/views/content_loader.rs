@@ -1,3 +1,6 @@
+use crate::views::text_encoding::decode_text;
+use calamine::{open_workbook, Data, Reader, Xlsx};
+use chrono::{Duration, NaiveDate, NaiveTime};
 use std::error;
 use std::fs;
 
@@ -6,14 +9,170 @@ pub fn read_file(file_path: &str) -> Result<String, Box<dyn error::Error>> {
     Ok(data)
 }
 
-pub fn read_csv(file_path: &str) -> Result<Vec<csv::StringRecord>, Box<dyn error::Error>> {
-    let csv = read_file(&file_path)?;
+/// Reads a CSV file in UTF-8 or CP932 and returns the header and the
+/// following records.
+pub fn read_csv(
+    file_path: &str,
+) -> Result<(csv::StringRecord, Vec<csv::StringRecord>), Box<dyn error::Error>> {
+    let csv = decode_text(&fs::read(file_path)?)?;
 
     let mut records = vec![];
     let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    let headers = reader.headers()?.clone();
     for record in reader.records() {
         let record = record?;
         records.push(record);
     }
-    Ok(records)
+    Ok((headers, records))
+}
+
+/// Formats the serial number of a date in the 1900 date system.
+fn excel_date_to_string(serial: f64) -> String {
+    let epoch = NaiveDate::from_ymd_opt(1899, 12, 30)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    let datetime = epoch + Duration::milliseconds((serial * 86_400_000.0).round() as i64);
+    if datetime.time() == NaiveTime::from_hms_opt(0, 0, 0).unwrap() {
+        datetime.format("%Y/%m/%d").to_string()
+    } else {
+        datetime.format("%Y/%m/%d %H:%M").to_string()
+    }
+}
+
+fn cell_to_string(cell: &Data) -> String {
+    match cell {
+        Data::Empty => String::new(),
+        Data::String(s) | Data::DateTimeIso(s) | Data::DurationIso(s) => s.clone(),
+        Data::Int(i) => i.to_string(),
+        Data::Float(f) if f.fract() == 0.0 && f.abs() < 1e15 => (*f as i64).to_string(),
+        Data::Float(f) => f.to_string(),
+        Data::Bool(b) => b.to_string(),
+        Data::DateTime(datetime) => excel_date_to_string(datetime.as_f64()),
+        Data::Error(e) => e.to_string(),
+    }
+}
+
+/// Reads the first sheet of an XLSX workbook in the same way as a CSV file.
+/// The columns beyond the header and the blank rows are skipped.
+pub fn read_xlsx(
+    file_path: &str,
+) -> Result<(csv::StringRecord, Vec<csv::StringRecord>), Box<dyn error::Error>> {
+    let mut workbook: Xlsx<_> = open_workbook(file_path)?;
+    let range = workbook
+        .worksheet_range_at(0)
+        .ok_or("The workbook has no sheet")??;
+
+    let mut rows = range
+        .rows()
+        .map(|row| row.iter().map(cell_to_string).collect::<Vec<String>>());
+    let mut headers = rows.next().ok_or("The sheet is empty")?;
+    let num_columns = headers
+        .iter()
+        .rposition(|header| !header.is_empty())
+        .map_or(0, |i| i + 1);
+    headers.truncate(num_columns);
+
+    // The line numbers are kept for the error reports
+    let records = rows
+        .zip(2..)
+        .filter(|(row, _)| row.iter().any(|cell| !cell.is_empty()))
+        .map(|(mut row, line)| {
+            row.truncate(num_columns);
+            let mut record = csv::StringRecord::from(row);
+            let mut position = csv::Position::new();
+            position.set_line(line);
+            record.set_position(Some(position));
+            record
+        })
+        .collect();
+    Ok((csv::StringRecord::from(headers), records))
+}
+
+/// Reads an uploaded table, either an XLSX workbook or a CSV file.
+pub fn read_table(
+    file_path: &str,
+) -> Result<(csv::StringRecord, Vec<csv::StringRecord>), Box<dyn error::Error>> {
+    if file_path.to_lowercase().ends_with(".xlsx") {
+        read_xlsx(file_path)
+    } else {
+        read_csv(file_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_xlsxwriter::{ExcelDateTime, Format, Workbook};
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("bib-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_excel_date_to_string() {
+        assert_eq!(excel_date_to_string(45292.0), "2024/01/01");
+        assert_eq!(excel_date_to_string(45292.5), "2024/01/01 12:00");
+        assert_eq!(excel_date_to_string(61.0), "1900/03/01");
+        // A time saved with a rounding error is not a minute off
+        assert_eq!(
+            excel_date_to_string(45292.0 + 0.75 - 1e-9),
+            "2024/01/01 18:00"
+        );
+    }
+
+    #[test]
+    fn test_cell_to_string() {
+        assert_eq!(
+            cell_to_string(&Data::Float(9784101092058.0)),
+            "9784101092058"
+        );
+        assert_eq!(cell_to_string(&Data::Float(1.5)), "1.5");
+        assert_eq!(cell_to_string(&Data::Int(-3)), "-3");
+        assert_eq!(cell_to_string(&Data::Empty), "");
+    }
+
+    #[test]
+    fn test_read_xlsx() {
+        let mut workbook = Workbook::new();
+        let worksheet = workbook.add_worksheet();
+        worksheet.write_string(0, 0, "図書ID").unwrap();
+        worksheet.write_string(0, 1, "登録日").unwrap();
+        worksheet.write_number(1, 0, 1.0).unwrap();
+        let date = ExcelDateTime::from_ymd(2024, 4, 1).unwrap();
+        let date_format = Format::new().set_num_format("yyyy/mm/dd");
+        worksheet
+            .write_datetime_with_format(1, 1, &date, &date_format)
+            .unwrap();
+        // A note beyond the header is not a column
+        worksheet.write_string(1, 3, "memo").unwrap();
+        worksheet.write_string(3, 1, "2024/05/01").unwrap();
+
+        let path = temp_path("read.xlsx");
+        workbook.save(&path).unwrap();
+        let result = read_xlsx(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let (headers, records) = result.unwrap();
+
+        assert_eq!(headers, vec!["図書ID", "登録日"]);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], vec!["1", "2024/04/01"]);
+        assert_eq!(records[0].position().unwrap().line(), 2);
+        // The blank row is skipped but counted
+        assert_eq!(records[1], vec!["", "2024/05/01"]);
+        assert_eq!(records[1].position().unwrap().line(), 4);
+    }
+
+    #[test]
+    fn test_read_csv() {
+        let path = temp_path("read.csv");
+        let (cp932, _, _) = encoding_rs::SHIFT_JIS.encode("氏名,備考\n髙橋,①\n");
+        fs::write(&path, &cp932).unwrap();
+        let result = read_csv(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let (headers, records) = result.unwrap();
+        assert_eq!(headers, vec!["氏名", "備考"]);
+        assert_eq!(records[0], vec!["髙橋", "①"]);
+    }
 }
@@ -0,0 +1,22 @@
+use crate::item::{search_items, CustomFieldSetting};
+use mongodb::Database;
+use std::collections::HashMap;
+
+/// The prefix of the form keys carrying the custom field values.
+const FORM_KEY_PREFIX: &str = "custom_";
+
+pub async fn get_custom_field_setting(db: &Database) -> CustomFieldSetting {
+    let setting = CustomFieldSetting::default();
+    match search_items(db, &setting).await {
+        Ok(mut settings) => settings.pop().unwrap_or_default(),
+        Err(_) => setting,
+    }
+}
+
+/// Picks the custom field values out of the extra keys of a form.
+pub fn form_values(extra: &HashMap<String, String>) -> impl Iterator<Item = (&str, &str)> {
+    extra.iter().filter_map(|(key, value)| {
+        key.strip_prefix(FORM_KEY_PREFIX)
+            .map(|name| (name, value.as_str()))
+    })
+}
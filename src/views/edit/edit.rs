@@ -1,10 +1,14 @@
 use crate::error::BibErrorResponse;
-use crate::item::{atoi, insert_item, search_items, SystemSetting};
+use crate::item::validate_custom_fields;
+use crate::item::{atoi, insert_item, normalize_isbn, search_items, SystemSetting};
 use crate::item::{delete_item, search_item, update_item};
-use crate::item::{Book, User};
+use crate::item::{Book, DuplicateIndex, User};
 use crate::views::cache::Cache;
+use crate::views::change_log::record_change;
 use crate::views::content_loader::read_file;
+use crate::views::custom_field::{form_values, get_custom_field_setting};
 use crate::views::db_helper::get_db;
+use crate::views::id_allocator::{new_book_id_allocator, new_user_id_allocator};
 use crate::views::reply::Reply;
 use crate::views::session::check_operator_session;
 use actix_session::Session;
@@ -12,7 +16,7 @@ use actix_web::{web, HttpResponse, Result};
 use log::debug;
 use serde::Deserialize;
 use shared_mongodb::ClientHolder;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Mutex;
 
 pub async fn load(_session: Session) -> HttpResponse {
@@ -31,6 +35,8 @@ pub struct UpdateUserForm {
     pub user_grade: String,
     pub user_remark: String,
     pub user_register_date: String,
+    #[serde(flatten)]
+    pub custom_fields: HashMap<String, String>,
 }
 
 pub async fn insert_user(
@@ -81,11 +87,30 @@ async fn user(
     let setting = setting.unwrap().clone();
     drop(setting_map);
 
-    // Read the User from DB first
+    let custom_fields = if operation == "delete" {
+        BTreeMap::new()
+    } else {
+        let custom_field_setting = get_custom_field_setting(&db).await;
+        validate_custom_fields(
+            &custom_field_setting.user_fields,
+            form_values(&form.custom_fields),
+        )
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?
+    };
+
+    // Read the User from DB first unless the ID is left to the server
+    let auto_id = operation == "insert" && form.user_id.is_empty();
     let mut user = User::default();
-    user.id = atoi(&form.user_id).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
-    user = match search_item(&db, &user).await {
-        Ok(mut user) => {
+    let registered_user = if auto_id {
+        None
+    } else {
+        user.id =
+            atoi(&form.user_id).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+        search_item(&db, &user).await.ok()
+    };
+    let before = registered_user.clone();
+    user = match registered_user {
+        Some(mut user) => {
             if operation == "insert" {
                 return Err(BibErrorResponse::ItemAlreadyExists(user.id));
             }
@@ -95,9 +120,10 @@ async fn user(
             user.grade = form.user_grade.clone();
             user.remark = form.user_remark.clone();
             user.register_date = form.user_register_date.clone();
+            user.custom_fields = custom_fields;
             user
         }
-        Err(_) => {
+        None => {
             // Check the number of items
             user.id = 0;
             let users = match search_items(&db, &user).await {
@@ -109,8 +135,15 @@ async fn user(
                 return Err(BibErrorResponse::ExceedLimit(nsize));
             }
 
-            User::new(
-                &form.user_id,
+            let user_id = if auto_id {
+                let mut allocator = new_user_id_allocator(&db, &users).await?;
+                allocator.allocate()?.to_string()
+            } else {
+                form.user_id.clone()
+            };
+
+            let mut new_user = User::new(
+                &user_id,
                 &form.user_name,
                 &form.user_kana,
                 &form.user_category,
@@ -118,20 +151,42 @@ async fn user(
                 &form.user_remark,
                 &form.user_register_date,
             )
-            .unwrap()
+            .unwrap();
+            new_user.custom_fields = custom_fields;
+            new_user
         }
     };
 
+    let mut reply = Reply::default();
     match operation {
         "insert" => {
             insert_item(&db, &user)
                 .await
                 .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            record_change(
+                &db,
+                session,
+                &setting.time_zone,
+                operation,
+                None,
+                Some(&user),
+            )
+            .await?;
+            reply.assigned_ids.push(user.id);
         }
         "update" => {
             update_item(&db, &user)
                 .await
                 .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            record_change(
+                &db,
+                session,
+                &setting.time_zone,
+                operation,
+                before.as_ref(),
+                Some(&user),
+            )
+            .await?;
         }
         "delete" => {
             if user.borrowed_books.len() > 0 {
@@ -140,13 +195,21 @@ async fn user(
             delete_item(&db, &user)
                 .await
                 .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            record_change(
+                &db,
+                session,
+                &setting.time_zone,
+                operation,
+                before.as_ref(),
+                None,
+            )
+            .await?;
         }
         _ => {
             return Err(BibErrorResponse::NotImplemented);
         }
     }
 
-    let reply = Reply::default();
     Ok(HttpResponse::Ok().json(reply))
 }
 
@@ -172,6 +235,10 @@ pub struct UpdateBookForm {
     pub book_isbn: String,
     pub book_register_date: String,
     pub book_register_type: String,
+    #[serde(default)]
+    pub allow_duplicate: String,
+    #[serde(flatten)]
+    pub custom_fields: HashMap<String, String>,
 }
 
 pub async fn insert_book(
@@ -226,11 +293,39 @@ async fn book(
     let setting = setting.unwrap().clone();
     drop(setting_map);
 
-    // Read the Book from DB first
+    // Not checked on delete, so that a book with a legacy ISBN can be removed
+    let normalized_isbn = || {
+        if operation == "delete" {
+            return Ok(form.book_isbn.clone());
+        }
+        normalize_isbn(&form.book_isbn)
+            .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))
+    };
+
+    let custom_fields = if operation == "delete" {
+        BTreeMap::new()
+    } else {
+        let custom_field_setting = get_custom_field_setting(&db).await;
+        validate_custom_fields(
+            &custom_field_setting.book_fields,
+            form_values(&form.custom_fields),
+        )
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?
+    };
+
+    // Read the Book from DB first unless the ID is left to the server
+    let auto_id = operation == "insert" && form.book_id.is_empty();
     let mut book = Book::default();
-    book.id = atoi(&form.book_id).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
-    book = match search_item(&db, &book).await {
-        Ok(mut book) => {
+    let registered_book = if auto_id {
+        None
+    } else {
+        book.id =
+            atoi(&form.book_id).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+        search_item(&db, &book).await.ok()
+    };
+    let before = registered_book.clone();
+    book = match registered_book {
+        Some(mut book) => {
             if operation == "insert" {
                 return Err(BibErrorResponse::ItemAlreadyExists(book.id));
             }
@@ -250,11 +345,16 @@ async fn book(
             book.volume_symbol = form.book_volume_symbol.clone();
             book.forbidden = form.book_forbidden.clone();
             book.remark = form.book_remark.clone();
+            // A registered ISBN left as it is is not checked again
+            if form.book_isbn != book.isbn {
+                book.isbn = normalized_isbn()?;
+            }
             book.register_date = form.book_register_date.clone();
             book.register_type = form.book_register_type.clone();
+            book.custom_fields = custom_fields;
             book
         }
-        Err(_) => {
+        None => {
             // Check the number of items
             book.id = 0;
             let books = match search_items(&db, &book).await {
@@ -266,8 +366,15 @@ async fn book(
                 return Err(BibErrorResponse::ExceedLimit(nsize));
             }
 
-            Book::new(
-                &form.book_id,
+            let book_id = if auto_id {
+                let mut allocator = new_book_id_allocator(&db, &books).await?;
+                allocator.allocate()?.to_string()
+            } else {
+                form.book_id.clone()
+            };
+
+            let mut new_book = Book::new(
+                &book_id,
                 &form.book_title,
                 &form.book_location,
                 &form.book_category,
@@ -284,24 +391,58 @@ async fn book(
                 &form.book_volume_symbol,
                 &form.book_forbidden,
                 &form.book_remark,
-                &form.book_isbn,
+                &normalized_isbn()?,
                 &form.book_register_date,
                 &form.book_register_type,
             )
-            .unwrap()
+            .unwrap();
+            new_book.custom_fields = custom_fields;
+
+            // Warn about another copy unless the operator has confirmed it
+            if operation == "insert" && form.allow_duplicate != "true" {
+                let duplicates: Vec<(u32, u32, String)> = DuplicateIndex::new(&books)
+                    .find(&new_book)
+                    .into_iter()
+                    .map(|(id, title)| (new_book.id, id, title))
+                    .collect();
+                if !duplicates.is_empty() {
+                    return Err(BibErrorResponse::BookDuplicated(duplicates));
+                }
+            }
+            new_book
         }
     };
 
+    let mut reply = Reply::default();
     match operation {
         "insert" => {
             insert_item(&db, &book)
                 .await
                 .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            record_change(
+                &db,
+                session,
+                &setting.time_zone,
+                operation,
+                None,
+                Some(&book),
+            )
+            .await?;
+            reply.assigned_ids.push(book.id);
         }
         "update" => {
             update_item(&db, &book)
                 .await
                 .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            record_change(
+                &db,
+                session,
+                &setting.time_zone,
+                operation,
+                before.as_ref(),
+                Some(&book),
+            )
+            .await?;
         }
         "delete" => {
             let cache_map = cache_map.lock().unwrap();
@@ -317,12 +458,20 @@ async fn book(
             delete_item(&db, &book)
                 .await
                 .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            record_change(
+                &db,
+                session,
+                &setting.time_zone,
+                operation,
+                before.as_ref(),
+                None,
+            )
+            .await?;
         }
         _ => {
             return Err(BibErrorResponse::NotImplemented);
         }
     }
 
-    let reply = Reply::default();
     Ok(HttpResponse::Ok().json(reply))
 }
@@ -0,0 +1,182 @@
+use crate::error::BibErrorResponse;
+use crate::item::{atoi, search_item, search_items, update_item};
+use crate::item::{Book, ChangeLog, Entity, SystemSetting, User};
+use crate::views::change_log::{record_change, restore_version, Tracked};
+use crate::views::db_helper::get_db;
+use crate::views::reply::Reply;
+use crate::views::session::check_operator_session;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use log::{debug, error};
+use mongodb::Database;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use shared_mongodb::ClientHolder;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Deserialize, Debug)]
+pub struct GetChangeLogForm {
+    pub id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RestoreVersionForm {
+    pub id: String,
+    pub log_id: String,
+}
+
+async fn get_change_logs(db: &Database, target: &str, target_id: u32) -> Vec<ChangeLog> {
+    let log = ChangeLog {
+        target: target.to_string(),
+        target_id,
+        ..Default::default()
+    };
+    search_items(db, &log).await.unwrap_or_default()
+}
+
+async fn get_history<T: Tracked>(
+    session: &Session,
+    form: &GetChangeLogForm,
+    data: &web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    check_operator_session(session)?;
+    let db = get_db(data, session).await?;
+
+    let target_id = atoi(&form.id).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let logs = get_change_logs(&db, T::TARGET, target_id).await;
+    if logs.is_empty() {
+        return Err(BibErrorResponse::DataNotFound(form.id.clone()));
+    }
+
+    let reply = Reply {
+        change_logs: logs,
+        ..Default::default()
+    };
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+/// Restores the version of a record right after the selected change and
+/// returns it. The loan status is left as it is.
+async fn restore<T>(
+    session: &Session,
+    form: &RestoreVersionForm,
+    data: &web::Data<Mutex<ClientHolder>>,
+    setting_map: &web::Data<Mutex<HashMap<String, SystemSetting>>>,
+    mut item: T,
+    set_id: impl Fn(&mut T, u32),
+) -> Result<T, BibErrorResponse>
+where
+    T: Tracked + Entity + DeserializeOwned + Clone + Send + Sync,
+{
+    let dbname = check_operator_session(session)?;
+    let db = get_db(data, session).await?;
+
+    let setting = {
+        let setting_map = setting_map.lock().unwrap();
+        setting_map.get(&dbname).cloned()
+    };
+    let setting = setting.ok_or(BibErrorResponse::NotAuthorized)?;
+
+    let target_id = atoi(&form.id).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let log_id: i64 = form
+        .log_id
+        .parse()
+        .map_err(|_| BibErrorResponse::InvalidArgument(form.log_id.clone()))?;
+
+    let logs = get_change_logs(&db, T::TARGET, target_id).await;
+    match logs.iter().find(|log| log.id == log_id) {
+        Some(log) if log.operation == "delete" => {
+            return Err(BibErrorResponse::InvalidArgument(form.log_id.clone()));
+        }
+        Some(_) => {}
+        None => return Err(BibErrorResponse::DataNotFound(form.log_id.clone())),
+    }
+
+    set_id(&mut item, target_id);
+    let current = search_item(&db, &item).await.ok();
+    let mut restored = restore_version(current.as_ref().unwrap_or(&item), &logs, log_id)
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    set_id(&mut restored, target_id);
+
+    update_item(&db, &restored)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    // The restore is done even if it fails to be logged
+    if let Err(e) = record_change(
+        &db,
+        session,
+        &setting.time_zone,
+        "restore",
+        current.as_ref(),
+        Some(&restored),
+    )
+    .await
+    {
+        error!("Failed to record the restore of {}: {:?}", target_id, e);
+    }
+
+    Ok(restored)
+}
+
+pub async fn get_user_history(
+    session: Session,
+    form: web::Query<GetChangeLogForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+    get_history::<User>(&session, &form, &data).await
+}
+
+pub async fn get_book_history(
+    session: Session,
+    form: web::Query<GetChangeLogForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+    get_history::<Book>(&session, &form, &data).await
+}
+
+pub async fn restore_user(
+    session: Session,
+    form: web::Json<RestoreVersionForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+    let user = restore(
+        &session,
+        &form,
+        &data,
+        &setting_map,
+        User::default(),
+        |user, id| user.id = id,
+    )
+    .await?;
+
+    let mut reply = Reply::default();
+    reply.user_list.push(user);
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+pub async fn restore_book(
+    session: Session,
+    form: web::Json<RestoreVersionForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+    let book = restore(
+        &session,
+        &form,
+        &data,
+        &setting_map,
+        Book::default(),
+        |book, id| book.id = id,
+    )
+    .await?;
+
+    let mut reply = Reply::default();
+    reply.book_list.push(book);
+    Ok(HttpResponse::Ok().json(reply))
+}
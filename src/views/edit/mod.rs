@@ -1,6 +1,8 @@
 use crate::views::path::Path;
 use actix_web::web;
 mod edit;
+mod history;
+pub use edit::UpdateBookForm;
 
 pub fn edit_factory(app: &mut web::ServiceConfig) {
     let base_path: Path = Path {
@@ -33,5 +35,21 @@ pub fn edit_factory(app: &mut web::ServiceConfig) {
     .route(
         &base_path.define(String::from("/book/profile")),
         web::delete().to(edit::delete_book),
+    )
+    .route(
+        &base_path.define(String::from("/user/profile/history")),
+        web::get().to(history::get_user_history),
+    )
+    .route(
+        &base_path.define(String::from("/user/profile/restore")),
+        web::post().to(history::restore_user),
+    )
+    .route(
+        &base_path.define(String::from("/book/profile/history")),
+        web::get().to(history::get_book_history),
+    )
+    .route(
+        &base_path.define(String::from("/book/profile/restore")),
+        web::post().to(history::restore_book),
     );
 }
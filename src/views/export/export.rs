@@ -1,22 +1,113 @@
+use super::json_lines::{stream_json_array, stream_json_lines};
+use super::table::{CellKind, ExportFormat, Table};
 use crate::error::BibErrorResponse;
-use crate::item::{search_items, Book, User};
-use crate::item::{SystemSetting, TransactionItem};
+use crate::item::SystemSetting;
+use crate::item::{stream_items, Book, CustomField, User};
 use crate::views::content_loader::read_file;
+use crate::views::custom_field::get_custom_field_setting;
 use crate::views::db_helper::get_db;
 use crate::views::session::check_operator_session;
-use crate::views::utils::get_nowtime;
-use crate::Transaction;
-use actix_files::NamedFile;
+use crate::views::text_encoding::TextEncoding;
+use crate::views::utils::{get_nowtime, get_setting};
 use actix_session::Session;
 use actix_web::{web, HttpResponse, Result};
-use csv::WriterBuilder;
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
 use log::error;
+use serde::{Deserialize, Serialize};
 use shared_mongodb::{database, ClientHolder};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
+use std::str::FromStr;
 use std::sync::Mutex;
-use std::{env, error};
+
+#[derive(Deserialize, Debug)]
+pub struct ExportForm {
+    #[serde(default)]
+    pub format: String,
+    #[serde(default)]
+    pub encoding: String,
+}
+
+/// Returns the file format, the encoding is used only for CSV. JSON is always
+/// written in UTF-8.
+pub fn get_format(format: &str, encoding: &str) -> Result<ExportFormat, BibErrorResponse> {
+    match format {
+        "" | "csv" => {
+            let encoding = TextEncoding::from_str(encoding)
+                .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+            Ok(ExportFormat::Csv(encoding))
+        }
+        "xlsx" => Ok(ExportFormat::Xlsx),
+        "json" => Ok(ExportFormat::Json),
+        "jsonl" => Ok(ExportFormat::JsonLines),
+        _ => Err(BibErrorResponse::InvalidArgument(format.to_string())),
+    }
+}
+
+/// Sends the items as an attachment named after `name` and the date. The CSV
+/// rows are streamed as they are read, while a workbook is built in memory
+/// because it is written at once. JSON is streamed from the items themselves
+/// and the table is not used.
+pub async fn send_table<T, E, S, F>(
+    mut table: Table,
+    items: S,
+    to_row: F,
+    name: &str,
+    time_zone: &str,
+    format: ExportFormat,
+) -> Result<HttpResponse, BibErrorResponse>
+where
+    T: Serialize + 'static,
+    E: ToString + 'static,
+    S: Stream<Item = Result<T, E>> + 'static,
+    F: Fn(T) -> Vec<String> + 'static,
+{
+    let dt = get_nowtime(time_zone);
+    let fname = format!("{}_{}.{}", name, dt.format("%Y%m%d"), format.extension());
+    // The response is already sent when a read fails, so it is only cut off
+    let items =
+        items.inspect_err(|e| error!("Failed to read the items to export: {}", e.to_string()));
+
+    let mut response = HttpResponse::Ok();
+    response.content_type(format.content_type()).header(
+        "Content-Disposition",
+        format!("attachment; filename=\"{}\"", fname),
+    );
+    match format {
+        ExportFormat::Csv(encoding) => {
+            let stream = table.stream_csv(encoding, items, to_row);
+            Ok(response.streaming(Box::pin(stream)))
+        }
+        ExportFormat::Xlsx => {
+            table.rows = items
+                .map_ok(to_row)
+                .try_collect()
+                .await
+                .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            let body = table
+                .write(format)
+                .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            Ok(response.body(body))
+        }
+        ExportFormat::Json => Ok(response.streaming(Box::pin(stream_json_array(items)))),
+        ExportFormat::JsonLines => Ok(response.streaming(Box::pin(stream_json_lines(items)))),
+    }
+}
+
+/// Returns the items with the first one read ahead, or DataNotFound if there
+/// are none.
+pub async fn ensure_not_empty<T, E, S>(
+    mut items: S,
+) -> Result<impl Stream<Item = Result<T, E>>, BibErrorResponse>
+where
+    E: ToString,
+    S: Stream<Item = Result<T, E>> + Unpin,
+{
+    match items.try_next().await {
+        Ok(Some(first)) => Ok(stream::once(future::ready(Ok(first))).chain(items)),
+        Ok(None) => Err(BibErrorResponse::DataNotFound(String::new())),
+        Err(e) => Err(BibErrorResponse::SystemError(e.to_string())),
+    }
+}
 
 pub async fn load(_session: Session) -> HttpResponse {
     let html_data = read_file("src/html/export.html").unwrap();
@@ -25,273 +116,167 @@ pub async fn load(_session: Session) -> HttpResponse {
         .body(html_data)
 }
 
-fn write_user_list(
-    users: Vec<User>,
-    prefix: &str,
-    time_zone: &str,
-) -> Result<String, Box<dyn error::Error>> {
-    let mut wtr = WriterBuilder::new().has_headers(false).from_writer(vec![]);
-
-    wtr.write_record(&[
-        "利用者ID",
-        "氏名",
-        "カナ",
-        "利用者区分",
-        "学年クラス",
-        "備考",
-        "登録日",
-        "貸出回数",
-        "",
-    ])?;
-
-    for user in users {
-        wtr.serialize(User {
-            id: user.id,
-            name: user.name.clone(),
-            kana: user.kana.clone(),
-            category: user.category.clone(),
-            grade: user.grade.clone(),
-            remark: user.remark.clone(),
-            register_date: user.register_date.clone(),
-            borrowed_count: user.borrowed_count.clone(),
-            reserved: user.reserved.clone(),
-            borrowed_books: vec![],
-        })?;
+fn user_table(custom_fields: &[CustomField]) -> Table {
+    let mut table = Table::new(&[
+        ("利用者ID", CellKind::Number),
+        ("氏名", CellKind::Text),
+        ("カナ", CellKind::Text),
+        ("利用者区分", CellKind::Text),
+        ("学年クラス", CellKind::Text),
+        ("備考", CellKind::Text),
+        ("登録日", CellKind::Date),
+        ("貸出回数", CellKind::Number),
+        ("", CellKind::Text),
+    ]);
+    for field in custom_fields {
+        table.add_column(&field.name);
     }
+    table
+}
 
-    let dt = get_nowtime(time_zone);
-
-    let fname = format!("user_list_{}_{}.csv", dt.format("%Y%m%d"), prefix);
-    let dir = env::temp_dir();
-    let mut temp_file_path = dir.as_path().to_owned();
-    temp_file_path.push(fname);
-    let mut file = File::create(temp_file_path.to_owned())?;
-    file.write_all(&wtr.into_inner()?)?;
-
-    Ok(temp_file_path.to_str().unwrap().to_owned())
+fn user_row(user: User, custom_fields: &[CustomField]) -> Vec<String> {
+    let mut record = vec![
+        user.id.to_string(),
+        user.name,
+        user.kana,
+        user.category,
+        user.grade,
+        user.remark,
+        user.register_date,
+        user.borrowed_count.to_string(),
+        user.reserved,
+    ];
+    for field in custom_fields {
+        record.push(
+            user.custom_fields
+                .get(&field.name)
+                .cloned()
+                .unwrap_or_default(),
+        );
+    }
+    record
 }
 
 pub async fn export_user_list(
     session: Session,
+    form: web::Query<ExportForm>,
     data: web::Data<Mutex<ClientHolder>>,
     setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
-) -> Result<NamedFile, BibErrorResponse> {
+) -> Result<HttpResponse, BibErrorResponse> {
     let dbname = check_operator_session(&session)?;
     let db = get_db(&data, &session).await?;
 
-    let setting_map = setting_map.lock().unwrap();
-    let setting = setting_map.get(&dbname);
-    if setting.is_none() {
-        return Err(BibErrorResponse::NotAuthorized);
-    }
-    let setting = setting.unwrap().clone();
-    drop(setting_map);
+    let time_zone = get_setting(&dbname, &setting_map)?.time_zone;
+    let format = get_format(&form.format, &form.encoding)?;
 
     let user = User::default();
-    let users = match search_items(&db, &user).await {
+    let users = match stream_items(&db, &user).await {
         Ok(users) => users,
         Err(e) => {
             database::disconnect(&data);
-            return Err(BibErrorResponse::DataNotFound(e.to_string()));
-        }
-    };
-
-    match write_user_list(users, &dbname, &setting.time_zone) {
-        Ok(fname) => {
-            return Ok(
-                NamedFile::open(fname).map_err(|e| BibErrorResponse::SystemError(e.to_string()))?
-            );
-        }
-        Err(e) => {
             return Err(BibErrorResponse::SystemError(e.to_string()));
         }
     };
-}
-
-fn write_book_list(
-    books: Vec<Book>,
-    prefix: &str,
-    time_zone: &str,
-) -> Result<String, Box<dyn error::Error>> {
-    let mut wtr = WriterBuilder::new().has_headers(false).from_writer(vec![]);
 
-    wtr.write_record(&[
-        "図書ID",
-        "タイトル",
-        "保管場所",
-        "図書分類",
-        "破損状況",
-        "著者",
-        "出版社",
-        "出版日",
-        "シリーズ",
-        "巻数",
-        "ページ",
-        "タイトルヨミ",
-        "分類記号",
-        "図書記号",
-        "巻冊記号",
-        "禁帯出",
-        "備考",
-        "ISBN",
-        "登録日",
-        "登録区分",
-        "貸出回数",
-        "",
-        "",
-    ])?;
+    let custom_fields = get_custom_field_setting(&db).await.user_fields;
+    let table = user_table(&custom_fields);
+    let to_row = move |user| user_row(user, &custom_fields);
+    send_table(table, users, to_row, "user_list", &time_zone, format).await
+}
 
-    for book in books {
-        wtr.serialize(Book {
-            id: book.id,
-            title: book.title.clone(),
-            location: book.location.clone(),
-            category: book.category.clone(),
-            status: book.status.clone(),
-            author: book.author.clone(),
-            publisher: book.publisher.clone(),
-            published_date: book.published_date.clone(),
-            series: book.series.clone(),
-            volume: book.volume.clone(),
-            page: book.page.clone(),
-            kana: book.kana.clone(),
-            category_symbol: book.category_symbol.clone(),
-            library_symbol: book.library_symbol.clone(),
-            volume_symbol: book.volume_symbol.clone(),
-            forbidden: book.forbidden.clone(),
-            remark: book.remark.clone(),
-            isbn: book.isbn.clone(),
-            register_date: book.register_date.clone(),
-            register_type: book.register_type.clone(),
-            borrowed_count: book.borrowed_count.clone(),
-            owner_id: None,
-            return_deadline: None,
-        })?;
+pub fn book_table(custom_fields: &[CustomField]) -> Table {
+    let mut table = Table::new(&[
+        ("図書ID", CellKind::Number),
+        ("タイトル", CellKind::Text),
+        ("保管場所", CellKind::Text),
+        ("図書分類", CellKind::Text),
+        ("破損状況", CellKind::Text),
+        ("著者", CellKind::Text),
+        ("出版社", CellKind::Text),
+        ("出版日", CellKind::Date),
+        ("シリーズ", CellKind::Text),
+        ("巻数", CellKind::Text),
+        ("ページ", CellKind::Number),
+        ("タイトルヨミ", CellKind::Text),
+        ("分類記号", CellKind::Text),
+        ("図書記号", CellKind::Text),
+        ("巻冊記号", CellKind::Text),
+        ("禁帯出", CellKind::Text),
+        ("備考", CellKind::Text),
+        ("ISBN", CellKind::Text),
+        ("登録日", CellKind::Date),
+        ("登録区分", CellKind::Text),
+        ("貸出回数", CellKind::Number),
+        ("", CellKind::Text),
+        ("", CellKind::Text),
+    ]);
+    for field in custom_fields {
+        table.add_column(&field.name);
     }
+    table
+}
 
-    let dt = get_nowtime(time_zone);
-
-    let fname = format!("book_list_{}_{}.csv", dt.format("%Y%m%d"), prefix);
-    let dir = env::temp_dir();
-    let mut temp_file_path = dir.as_path().to_owned();
-    temp_file_path.push(fname);
-    let mut file = File::create(temp_file_path.to_owned())?;
-    file.write_all(&wtr.into_inner()?)?;
-
-    Ok(temp_file_path.to_str().unwrap().to_owned())
+pub fn book_row(book: Book, custom_fields: &[CustomField]) -> Vec<String> {
+    let mut record = vec![
+        book.id.to_string(),
+        book.title,
+        book.location,
+        book.category,
+        book.status,
+        book.author,
+        book.publisher,
+        book.published_date,
+        book.series,
+        book.volume,
+        book.page,
+        book.kana,
+        book.category_symbol,
+        book.library_symbol,
+        book.volume_symbol,
+        book.forbidden,
+        book.remark,
+        book.isbn,
+        book.register_date,
+        book.register_type,
+        book.borrowed_count.to_string(),
+        String::new(),
+        String::new(),
+    ];
+    for field in custom_fields {
+        record.push(
+            book.custom_fields
+                .get(&field.name)
+                .cloned()
+                .unwrap_or_default(),
+        );
+    }
+    record
 }
 
 pub async fn export_book_list(
     session: Session,
+    form: web::Query<ExportForm>,
     data: web::Data<Mutex<ClientHolder>>,
     setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
-) -> Result<NamedFile, BibErrorResponse> {
+) -> Result<HttpResponse, BibErrorResponse> {
     let dbname = check_operator_session(&session)?;
     let db = get_db(&data, &session).await?;
 
-    let setting_map = setting_map.lock().unwrap();
-    let setting = setting_map.get(&dbname);
-    if setting.is_none() {
-        return Err(BibErrorResponse::NotAuthorized);
-    }
-    let setting = setting.unwrap().clone();
-    drop(setting_map);
+    let time_zone = get_setting(&dbname, &setting_map)?.time_zone;
+    let format = get_format(&form.format, &form.encoding)?;
 
     let book = Book::default();
-    let books = match search_items(&db, &book).await {
+    let books = match stream_items(&db, &book).await {
         Ok(books) => books,
         Err(e) => {
             error!("{:?}", e);
             database::disconnect(&data);
-            return Err(BibErrorResponse::DataNotFound(e.to_string()));
-        }
-    };
-
-    match write_book_list(books, &dbname, &setting.time_zone) {
-        Ok(fname) => {
-            return Ok(
-                NamedFile::open(fname).map_err(|e| BibErrorResponse::SystemError(e.to_string()))?
-            );
-        }
-        Err(e) => {
             return Err(BibErrorResponse::SystemError(e.to_string()));
         }
     };
-}
-
-fn write_transaction_list(
-    items: Vec<TransactionItem>,
-    prefix: &str,
-    time_zone: &str,
-) -> Result<String, Box<dyn error::Error>> {
-    let mut wtr = WriterBuilder::new().has_headers(false).from_writer(vec![]);
-
-    wtr.write_record(&[
-        "",
-        "利用者ID",
-        "利用者氏名",
-        "図書ID",
-        "図書タイトル",
-        "貸出日",
-        "返却日",
-    ])?;
-
-    for item in items {
-        wtr.serialize(TransactionItem {
-            id: item.id,
-            user_id: item.user_id,
-            user_name: item.user_name,
-            book_id: item.book_id,
-            book_title: item.book_title,
-            borrowed_date: item.borrowed_date,
-            returned_date: item.returned_date,
-        })?;
-    }
-
-    let dt = get_nowtime(time_zone);
-
-    let fname = format!("transaction_list_{}_{}.csv", dt.format("%Y%m%d"), prefix);
-    let dir = env::temp_dir();
-    let mut temp_file_path = dir.as_path().to_owned();
-    temp_file_path.push(fname);
-    let mut file = File::create(temp_file_path.to_owned())?;
-    file.write_all(&wtr.into_inner()?)?;
-
-    Ok(temp_file_path.to_str().unwrap().to_owned())
-}
-
-pub async fn export_history_list(
-    session: Session,
-    data: web::Data<Mutex<ClientHolder>>,
-    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
-) -> Result<NamedFile, BibErrorResponse> {
-    let dbname = check_operator_session(&session)?;
-    let db = get_db(&data, &session).await?;
-
-    let setting_map = setting_map.lock().unwrap();
-    let setting = setting_map.get(&dbname);
-    if setting.is_none() {
-        return Err(BibErrorResponse::NotAuthorized);
-    }
-    let setting = setting.unwrap().clone();
-    drop(setting_map);
 
-    let item = TransactionItem::default();
-    let transaction_items = Transaction::search(&db, &item).await;
-
-    if transaction_items.len() == 0 {
-        return Err(BibErrorResponse::DataNotFound(String::new()));
-    }
-
-    match write_transaction_list(transaction_items, &dbname, &setting.time_zone) {
-        Ok(fname) => {
-            return Ok(
-                NamedFile::open(fname).map_err(|e| BibErrorResponse::SystemError(e.to_string()))?
-            );
-        }
-        Err(e) => {
-            error!("{:?}", e);
-            return Err(BibErrorResponse::SystemError(e.to_string()));
-        }
-    };
+    let custom_fields = get_custom_field_setting(&db).await.book_fields;
+    let table = book_table(&custom_fields);
+    let to_row = move |book| book_row(book, &custom_fields);
+    send_table(table, books, to_row, "book_list", &time_zone, format).await
 }
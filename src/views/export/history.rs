@@ -0,0 +1,283 @@
+use super::export::{ensure_not_empty, get_format, send_table};
+use super::table::{CellKind, Table};
+use crate::error::BibErrorResponse;
+use crate::item::{atoi, search_items_or_empty, stream_items_by};
+use crate::item::{Book, SystemSetting, TransactionItem, User};
+use crate::views::db_helper::get_db;
+use crate::views::session::check_operator_session;
+use crate::views::utils::get_setting;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use bson::Document;
+use chrono::{Duration, NaiveDate};
+use log::{debug, error};
+use mongodb::bson::doc;
+use mongodb::Database;
+use serde::Deserialize;
+use shared_mongodb::ClientHolder;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+#[derive(Deserialize, Debug)]
+pub struct HistoryExportForm {
+    #[serde(default)]
+    pub borrowed_from: String,
+    #[serde(default)]
+    pub borrowed_to: String,
+    #[serde(default)]
+    pub returned: String,
+    #[serde(default)]
+    pub user_id: String,
+    #[serde(default)]
+    pub grade: String,
+    #[serde(default)]
+    pub user_category: String,
+    #[serde(default)]
+    pub book_category: String,
+    #[serde(default)]
+    pub format: String,
+    #[serde(default)]
+    pub encoding: String,
+}
+
+struct HistoryCondition {
+    borrowed_from: Option<NaiveDate>,
+    borrowed_to: Option<NaiveDate>,
+    // Whether the book has been returned
+    returned: Option<bool>,
+    // The users and the books the loans are limited to
+    user_ids: Option<HashSet<u32>>,
+    book_ids: Option<HashSet<u32>>,
+}
+
+/// Reads a date such as "2024-04-01" sent by a date input, or "2024/04/01".
+fn parse_date(date: &str) -> Result<Option<NaiveDate>, BibErrorResponse> {
+    let date = date.trim();
+    if date.is_empty() {
+        return Ok(None);
+    }
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%Y/%m/%d"))
+        .map(Some)
+        .map_err(|_| BibErrorResponse::InvalidArgument(date.to_string()))
+}
+
+impl HistoryCondition {
+    /// The users are chosen by their current grade and category, as the
+    /// loans do not keep them.
+    async fn new(db: &Database, form: &HistoryExportForm) -> Result<Self, BibErrorResponse> {
+        let returned = match form.returned.as_str() {
+            "" => None,
+            "returned" => Some(true),
+            "unreturned" => Some(false),
+            returned => return Err(BibErrorResponse::InvalidArgument(returned.to_string())),
+        };
+
+        let user_id = match form.user_id.trim() {
+            "" => None,
+            id => Some(atoi(id).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?),
+        };
+        let grade = form.grade.trim();
+        let user_category = form.user_category.trim();
+        let user_ids = if grade.is_empty() && user_category.is_empty() {
+            user_id.map(|id| HashSet::from([id]))
+        } else {
+            let users = search_items_or_empty(db, &User::default())
+                .await
+                .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            let user_ids = users
+                .into_iter()
+                .filter(|user| grade.is_empty() || user.grade.trim() == grade)
+                .filter(|user| user_category.is_empty() || user.category == user_category)
+                .map(|user| user.id)
+                .filter(|id| user_id.is_none_or(|user_id| *id == user_id))
+                .collect();
+            Some(user_ids)
+        };
+
+        let book_category = form.book_category.trim();
+        let book_ids = if book_category.is_empty() {
+            None
+        } else {
+            let books = search_items_or_empty(db, &Book::default())
+                .await
+                .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            let book_ids = books
+                .into_iter()
+                .filter(|book| book.category == book_category)
+                .map(|book| book.id)
+                .collect();
+            Some(book_ids)
+        };
+
+        Ok(Self {
+            borrowed_from: parse_date(&form.borrowed_from)?,
+            borrowed_to: parse_date(&form.borrowed_to)?,
+            returned,
+            user_ids,
+            book_ids,
+        })
+    }
+
+    /// Builds the query of the loans, so that only the matching ones are
+    /// read from the DB.
+    fn to_query(&self) -> Document {
+        let mut query = doc! {};
+        // The dates are kept as "%Y/%m/%d %H:%M", which sort as the dates
+        let mut borrowed_date = doc! {};
+        if let Some(from) = self.borrowed_from {
+            borrowed_date.insert("$gte", from.format("%Y/%m/%d").to_string());
+        }
+        if let Some(to) = self.borrowed_to {
+            let next_day = to + Duration::days(1);
+            borrowed_date.insert("$lt", next_day.format("%Y/%m/%d").to_string());
+        }
+        if !borrowed_date.is_empty() {
+            query.insert("borrowed_date", borrowed_date);
+        }
+        match self.returned {
+            Some(true) => query.insert("returned_date", doc! { "$ne": "" }),
+            Some(false) => query.insert("returned_date", ""),
+            None => None,
+        };
+        if let Some(user_ids) = &self.user_ids {
+            query.insert("user_id", doc! { "$in": sorted_ids(user_ids) });
+        }
+        if let Some(book_ids) = &self.book_ids {
+            query.insert("book_id", doc! { "$in": sorted_ids(book_ids) });
+        }
+        query
+    }
+}
+
+fn sorted_ids(ids: &HashSet<u32>) -> Vec<u32> {
+    let mut ids: Vec<u32> = ids.iter().copied().collect();
+    ids.sort_unstable();
+    ids
+}
+
+fn transaction_table() -> Table {
+    Table::new(&[
+        ("", CellKind::Number),
+        ("利用者ID", CellKind::Number),
+        ("利用者氏名", CellKind::Text),
+        ("図書ID", CellKind::Number),
+        ("図書タイトル", CellKind::Text),
+        ("貸出日", CellKind::DateTime),
+        ("返却日", CellKind::DateTime),
+    ])
+}
+
+fn transaction_row(item: TransactionItem) -> Vec<String> {
+    vec![
+        item.id.to_string(),
+        item.user_id.to_string(),
+        item.user_name,
+        item.book_id.to_string(),
+        item.book_title,
+        item.borrowed_date,
+        item.returned_date,
+    ]
+}
+
+pub async fn export_history_list(
+    session: Session,
+    form: web::Query<HistoryExportForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    let dbname = check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let time_zone = get_setting(&dbname, &setting_map)?.time_zone;
+    let format = get_format(&form.format, &form.encoding)?;
+    let condition = HistoryCondition::new(&db, &form).await?;
+
+    let item = TransactionItem::default();
+    let transaction_items = match stream_items_by(&db, &item, condition.to_query()).await {
+        Ok(items) => items,
+        Err(e) => {
+            error!("{:?}", e);
+            return Err(BibErrorResponse::SystemError(e.to_string()));
+        }
+    };
+    let transaction_items = ensure_not_empty(transaction_items).await?;
+
+    let table = transaction_table();
+    send_table(
+        table,
+        transaction_items,
+        transaction_row,
+        "transaction_list",
+        &time_zone,
+        format,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition() -> HistoryCondition {
+        HistoryCondition {
+            borrowed_from: None,
+            borrowed_to: None,
+            returned: None,
+            user_ids: None,
+            book_ids: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_date() {
+        let date = NaiveDate::from_ymd_opt(2024, 4, 1);
+        assert_eq!(parse_date("2024-04-01").unwrap(), date);
+        assert_eq!(parse_date(" 2024/4/1 ").unwrap(), date);
+        assert_eq!(parse_date("").unwrap(), None);
+        assert!(parse_date("2024/02/30").is_err());
+        assert!(parse_date("April").is_err());
+    }
+
+    #[test]
+    fn test_to_query() {
+        assert_eq!(condition().to_query(), doc! {});
+
+        // The last day is included up to its end
+        let query = HistoryCondition {
+            borrowed_from: parse_date("2024/03/01").unwrap(),
+            borrowed_to: parse_date("2024/03/31").unwrap(),
+            returned: Some(false),
+            ..condition()
+        }
+        .to_query();
+        assert_eq!(
+            query,
+            doc! {
+                "borrowed_date": { "$gte": "2024/03/01", "$lt": "2024/04/01" },
+                "returned_date": "",
+            }
+        );
+
+        let query = HistoryCondition {
+            borrowed_to: parse_date("2024/12/31").unwrap(),
+            returned: Some(true),
+            user_ids: Some(HashSet::from([3, 1, 2])),
+            book_ids: Some(HashSet::new()),
+            ..condition()
+        }
+        .to_query();
+        // No books in the category match no loans
+        assert_eq!(
+            query,
+            doc! {
+                "borrowed_date": { "$lt": "2025/01/01" },
+                "returned_date": { "$ne": "" },
+                "user_id": { "$in": [1, 2, 3] },
+                "book_id": { "$in": [] },
+            }
+        );
+    }
+}
@@ -0,0 +1,45 @@
+use crate::error::BibErrorResponse;
+use actix_web::web::Bytes;
+use futures::{stream, Stream, StreamExt};
+use serde::Serialize;
+
+fn to_json<T: Serialize>(item: &T) -> Result<Vec<u8>, BibErrorResponse> {
+    serde_json::to_vec(item).map_err(|e| BibErrorResponse::SystemError(e.to_string()))
+}
+
+/// Writes each item as a line of JSON as it is read.
+pub fn stream_json_lines<T, E, S>(items: S) -> impl Stream<Item = Result<Bytes, BibErrorResponse>>
+where
+    T: Serialize,
+    E: ToString,
+    S: Stream<Item = Result<T, E>>,
+{
+    items.map(|item| {
+        let item = item.map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+        let mut line = to_json(&item)?;
+        line.push(b'\n');
+        Ok(Bytes::from(line))
+    })
+}
+
+/// Writes the items as a JSON array, one element per line.
+pub fn stream_json_array<T, E, S>(items: S) -> impl Stream<Item = Result<Bytes, BibErrorResponse>>
+where
+    T: Serialize,
+    E: ToString,
+    S: Stream<Item = Result<T, E>>,
+{
+    let elements = items.enumerate().map(|(i, item)| {
+        let item = item.map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+        let mut element = if i == 0 {
+            b"\n".to_vec()
+        } else {
+            b",\n".to_vec()
+        };
+        element.extend(to_json(&item)?);
+        Ok(Bytes::from(element))
+    });
+    stream::once(async { Ok(Bytes::from_static(b"[")) })
+        .chain(elements)
+        .chain(stream::once(async { Ok(Bytes::from_static(b"\n]\n")) }))
+}
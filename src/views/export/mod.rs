@@ -1,6 +1,10 @@
 use crate::views::path::Path;
 use actix_web::web;
 mod export;
+mod history;
+mod json_lines;
+mod table;
+mod weeding;
 
 pub fn export_factory(app: &mut web::ServiceConfig) {
     let base_path: Path = Path {
@@ -20,6 +24,14 @@ pub fn export_factory(app: &mut web::ServiceConfig) {
     )
     .route(
         &base_path.define(String::from("/history/export")),
-        web::get().to(export::export_history_list),
+        web::get().to(history::export_history_list),
+    )
+    .route(
+        &base_path.define(String::from("/book/weeding/search")),
+        web::get().to(weeding::search_weeding_list),
+    )
+    .route(
+        &base_path.define(String::from("/book/weeding/export")),
+        web::get().to(weeding::export_weeding_list),
     );
 }
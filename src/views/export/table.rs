@@ -0,0 +1,201 @@
+use crate::error::BibErrorResponse;
+use crate::views::text_encoding::TextEncoding;
+use actix_web::web::Bytes;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+use csv::WriterBuilder;
+use futures::{stream, Stream, StreamExt};
+use rust_xlsxwriter::{ExcelDateTime, Format, Workbook};
+use std::error;
+
+/// How the values of a column are written to a workbook.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellKind {
+    Text,
+    Number,
+    // "%Y/%m/%d"
+    Date,
+    // "%Y/%m/%d %H:%M"
+    DateTime,
+}
+
+/// The formats of the exported files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv(TextEncoding),
+    Xlsx,
+    // The serde representation of the items rather than the table
+    Json,
+    JsonLines,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv(_) => "csv",
+            ExportFormat::Xlsx => "xlsx",
+            ExportFormat::Json => "json",
+            ExportFormat::JsonLines => "jsonl",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv(TextEncoding::Cp932) => "text/csv; charset=Shift_JIS",
+            ExportFormat::Csv(_) => "text/csv; charset=utf-8",
+            ExportFormat::Xlsx => {
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+            }
+            ExportFormat::Json => "application/json",
+            ExportFormat::JsonLines => "application/x-ndjson",
+        }
+    }
+}
+
+/// The exported rows with the header.
+pub struct Table {
+    pub header: Vec<String>,
+    pub kinds: Vec<CellKind>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(columns: &[(&str, CellKind)]) -> Self {
+        Self {
+            header: columns.iter().map(|(name, _)| name.to_string()).collect(),
+            kinds: columns.iter().map(|(_, kind)| *kind).collect(),
+            rows: vec![],
+        }
+    }
+
+    /// Appends a text column such as a custom field.
+    pub fn add_column(&mut self, name: &str) {
+        self.header.push(name.to_string());
+        self.kinds.push(CellKind::Text);
+    }
+
+    pub fn write(&self, format: ExportFormat) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        match format {
+            ExportFormat::Csv(encoding) => self.write_csv(encoding),
+            ExportFormat::Xlsx => self.write_xlsx(),
+            ExportFormat::Json | ExportFormat::JsonLines => {
+                Err(format!("{} is not a table", format.extension()).into())
+            }
+        }
+    }
+
+    fn write_csv(&self, encoding: TextEncoding) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let mut wtr = WriterBuilder::new().has_headers(false).from_writer(vec![]);
+        wtr.write_record(&self.header)?;
+        for row in &self.rows {
+            wtr.write_record(row)?;
+        }
+        let csv = String::from_utf8(wtr.into_inner()?)?;
+        Ok(encoding.encode(&csv))
+    }
+
+    /// Writes the header and then each item as it is read, without holding
+    /// the rows. The rows already in the table are not written.
+    pub fn stream_csv<T, E, S, F>(
+        self,
+        encoding: TextEncoding,
+        items: S,
+        to_row: F,
+    ) -> impl Stream<Item = Result<Bytes, BibErrorResponse>>
+    where
+        S: Stream<Item = Result<T, E>>,
+        E: ToString,
+        F: Fn(T) -> Vec<String>,
+    {
+        let header = csv_line(&self.header).map(|line| Bytes::from(encoding.encode(&line)));
+        let rows = items.map(move |item| {
+            let item = item.map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+            let line = csv_line(&to_row(item))?;
+            Ok(Bytes::from(encoding.encode_continued(&line)))
+        });
+        stream::once(async move { header }).chain(rows)
+    }
+
+    /// Writes the numbers and the dates as typed cells. The values that
+    /// cannot be read as such are kept as text.
+    fn write_xlsx(&self) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let date_format = Format::new().set_num_format("yyyy/mm/dd");
+        let datetime_format = Format::new().set_num_format("yyyy/mm/dd hh:mm");
+
+        let mut workbook = Workbook::new();
+        let worksheet = workbook.add_worksheet();
+        for (col, name) in self.header.iter().enumerate() {
+            worksheet.write_string(0, col as u16, name)?;
+        }
+
+        for (i, row) in self.rows.iter().enumerate() {
+            let row_num = i as u32 + 1;
+            for (col, value) in row.iter().enumerate() {
+                let col = col as u16;
+                let kind = self.kinds.get(col as usize).copied();
+                if value.is_empty() {
+                    continue;
+                }
+                match kind {
+                    Some(CellKind::Number) => {
+                        if let Ok(number) = value.parse::<f64>() {
+                            worksheet.write_number(row_num, col, number)?;
+                            continue;
+                        }
+                    }
+                    Some(CellKind::Date) => {
+                        if let Some(date) = to_excel_date(value) {
+                            worksheet.write_datetime_with_format(
+                                row_num,
+                                col,
+                                &date,
+                                &date_format,
+                            )?;
+                            continue;
+                        }
+                    }
+                    Some(CellKind::DateTime) => {
+                        if let Some(datetime) = to_excel_datetime(value) {
+                            worksheet.write_datetime_with_format(
+                                row_num,
+                                col,
+                                &datetime,
+                                &datetime_format,
+                            )?;
+                            continue;
+                        }
+                    }
+                    _ => {}
+                }
+                worksheet.write_string(row_num, col, value)?;
+            }
+        }
+        Ok(workbook.save_to_buffer()?)
+    }
+}
+
+fn csv_line(record: &[String]) -> Result<String, BibErrorResponse> {
+    let mut wtr = WriterBuilder::new().has_headers(false).from_writer(vec![]);
+    wtr.write_record(record)
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    let line = wtr
+        .into_inner()
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    String::from_utf8(line).map_err(|e| BibErrorResponse::SystemError(e.to_string()))
+}
+
+fn to_excel_date(value: &str) -> Option<ExcelDateTime> {
+    let date = NaiveDate::parse_from_str(value.trim(), "%Y/%m/%d").ok()?;
+    ExcelDateTime::from_ymd(date.year() as u16, date.month() as u8, date.day() as u8).ok()
+}
+
+fn to_excel_datetime(value: &str) -> Option<ExcelDateTime> {
+    let datetime = NaiveDateTime::parse_from_str(value.trim(), "%Y/%m/%d %H:%M").ok()?;
+    ExcelDateTime::from_ymd(
+        datetime.year() as u16,
+        datetime.month() as u8,
+        datetime.day() as u8,
+    )
+    .ok()?
+    .and_hms(datetime.hour() as u16, datetime.minute() as u8, 0)
+    .ok()
+}
@@ -0,0 +1,248 @@
+use super::export::{book_row, book_table, get_format, send_table};
+use crate::error::BibErrorResponse;
+use crate::item::{atoi, search_items, Book, SystemSetting};
+use crate::views::custom_field::get_custom_field_setting;
+use crate::views::db_helper::get_db;
+use crate::views::reply::Reply;
+use crate::views::session::check_operator_session;
+use crate::views::utils::get_nowtime;
+use crate::views::utils::get_setting;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use chrono::Datelike;
+use futures::stream;
+use log::debug;
+use mongodb::Database;
+use serde::Deserialize;
+use shared_mongodb::ClientHolder;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Deserialize, Debug)]
+pub struct WeedingForm {
+    #[serde(default)]
+    pub max_borrowed_count: String,
+    #[serde(default)]
+    pub years: String,
+    #[serde(default)]
+    pub date_field: String,
+    #[serde(default)]
+    pub category: String,
+    #[serde(default)]
+    pub category_symbol: String,
+    #[serde(default)]
+    pub format: String,
+    #[serde(default)]
+    pub encoding: String,
+}
+
+struct WeedingCondition {
+    max_borrowed_count: u32,
+    // Year and month before which the books are old enough
+    cutoff: Option<(i32, u32)>,
+    use_published_date: bool,
+    category: String,
+    category_symbol: String,
+}
+
+impl WeedingCondition {
+    fn new(form: &WeedingForm, time_zone: &str) -> Result<Self, BibErrorResponse> {
+        let max_borrowed_count = match form.max_borrowed_count.trim() {
+            "" => 0,
+            count => atoi(count).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?,
+        };
+        let cutoff = match form.years.trim() {
+            "" => None,
+            years => {
+                let years =
+                    atoi(years).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+                let now = get_nowtime(time_zone);
+                Some((now.year() - years as i32, now.month()))
+            }
+        };
+        let use_published_date = match form.date_field.as_str() {
+            "" | "register_date" => false,
+            "published_date" => true,
+            field => return Err(BibErrorResponse::InvalidArgument(field.to_string())),
+        };
+
+        Ok(Self {
+            max_borrowed_count,
+            cutoff,
+            use_published_date,
+            category: form.category.trim().to_string(),
+            category_symbol: form.category_symbol.trim().to_string(),
+        })
+    }
+
+    fn matches(&self, book: &Book) -> bool {
+        if book.borrowed_count > self.max_borrowed_count {
+            return false;
+        }
+        if !self.category.is_empty() && book.category != self.category {
+            return false;
+        }
+        if !self.category_symbol.is_empty()
+            && !book
+                .category_symbol
+                .trim()
+                .starts_with(&self.category_symbol)
+        {
+            return false;
+        }
+        if let Some(cutoff) = self.cutoff {
+            let date = if self.use_published_date {
+                &book.published_date
+            } else {
+                &book.register_date
+            };
+            // A book without a readable date is not known to be old
+            match parse_year_month(date) {
+                Some(year_month) if year_month < cutoff => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Reads the year and the month of a date such as "2010/04/01", "2010-04"
+/// or "2010". The month defaults to January.
+fn parse_year_month(date: &str) -> Option<(i32, u32)> {
+    let mut parts = date
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|part| !part.is_empty());
+    let year = parts.next()?;
+    if year.len() != 4 {
+        return None;
+    }
+    let year: i32 = year.parse().ok()?;
+    let month = match parts.next().map(|month| month.parse::<u32>()) {
+        Some(Ok(month)) if (1..=12).contains(&month) => month,
+        Some(_) => return None,
+        None => 1,
+    };
+    Some((year, month))
+}
+
+async fn search_weeding_books(
+    db: &Database,
+    condition: &WeedingCondition,
+) -> Result<Vec<Book>, BibErrorResponse> {
+    let book = Book::default();
+    let books = search_items(db, &book)
+        .await
+        .map_err(|e| BibErrorResponse::DataNotFound(e.to_string()))?;
+
+    let books: Vec<Book> = books
+        .into_iter()
+        .filter(|book| condition.matches(book))
+        .collect();
+    if books.is_empty() {
+        return Err(BibErrorResponse::DataNotFound(String::new()));
+    }
+    Ok(books)
+}
+
+pub async fn search_weeding_list(
+    session: Session,
+    form: web::Query<WeedingForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    let dbname = check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let time_zone = get_setting(&dbname, &setting_map)?.time_zone;
+    let condition = WeedingCondition::new(&form, &time_zone)?;
+    let books = search_weeding_books(&db, &condition).await?;
+
+    let reply = Reply {
+        book_list: books,
+        ..Default::default()
+    };
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+pub async fn export_weeding_list(
+    session: Session,
+    form: web::Query<WeedingForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    let dbname = check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let time_zone = get_setting(&dbname, &setting_map)?.time_zone;
+    let condition = WeedingCondition::new(&form, &time_zone)?;
+    let format = get_format(&form.format, &form.encoding)?;
+    let books = search_weeding_books(&db, &condition).await?;
+
+    let custom_fields = get_custom_field_setting(&db).await.book_fields;
+    let table = book_table(&custom_fields);
+    let books = stream::iter(books.into_iter().map(Ok::<Book, BibErrorResponse>));
+    let to_row = move |book| book_row(book, &custom_fields);
+    send_table(table, books, to_row, "weeding_list", &time_zone, format).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_year_month() {
+        assert_eq!(parse_year_month("2010/04/01"), Some((2010, 4)));
+        assert_eq!(parse_year_month("2010-12"), Some((2010, 12)));
+        assert_eq!(parse_year_month("2010年4月"), Some((2010, 4)));
+        assert_eq!(parse_year_month("2010"), Some((2010, 1)));
+        assert_eq!(parse_year_month(" 2010.04 "), Some((2010, 4)));
+        assert_eq!(parse_year_month("2010/13"), None);
+        assert_eq!(parse_year_month("2010/0"), None);
+        assert_eq!(parse_year_month("10/04/01"), None);
+        assert_eq!(parse_year_month("不明"), None);
+        assert_eq!(parse_year_month(""), None);
+    }
+
+    fn condition(cutoff: Option<(i32, u32)>, category_symbol: &str) -> WeedingCondition {
+        WeedingCondition {
+            max_borrowed_count: 1,
+            cutoff,
+            use_published_date: false,
+            category: String::new(),
+            category_symbol: category_symbol.to_string(),
+        }
+    }
+
+    fn book(register_date: &str, borrowed_count: u32, category_symbol: &str) -> Book {
+        Book {
+            register_date: register_date.to_string(),
+            borrowed_count,
+            category_symbol: category_symbol.to_string(),
+            ..Book::default()
+        }
+    }
+
+    #[test]
+    fn test_weeding_condition() {
+        let condition = condition(Some((2015, 4)), "9");
+        assert!(condition.matches(&book("2015/03/31", 1, " 913")));
+        // Not older than the cutoff month
+        assert!(!condition.matches(&book("2015/04/01", 0, "913")));
+        // A book without a readable date is kept
+        assert!(!condition.matches(&book("", 0, "913")));
+        assert!(!condition.matches(&book("2010/01/01", 2, "913")));
+        assert!(!condition.matches(&book("2010/01/01", 0, "813")));
+
+        let condition = WeedingCondition {
+            use_published_date: true,
+            ..condition
+        };
+        let mut old = book("2020/01/01", 0, "913");
+        old.published_date = String::from("1999");
+        assert!(condition.matches(&old));
+    }
+}
@@ -0,0 +1,107 @@
+use crate::error::BibErrorResponse;
+use crate::item::{search_items, BarcodeSetting, Book, User};
+use mongodb::Database;
+use std::collections::HashSet;
+
+pub struct IdAllocator {
+    min_id: u32,
+    max_id: u32,
+    used_ids: HashSet<u32>,
+    next_id: u32,
+}
+
+impl IdAllocator {
+    /// Creates an allocator for the IDs whose number of digits is within
+    /// `keta_min..=keta_max`, skipping `used_ids`.
+    pub fn new(keta_min: u32, keta_max: u32, used_ids: impl Iterator<Item = u32>) -> Self {
+        let min_id = match keta_min {
+            0 | 1 => 1,
+            n => 10u32.checked_pow(n - 1).unwrap_or(u32::MAX),
+        };
+        let max_id = match 10u32.checked_pow(keta_max) {
+            Some(n) => n - 1,
+            None => u32::MAX,
+        };
+        let used_ids: HashSet<u32> = used_ids.collect();
+        let last_id = used_ids.iter().copied().max().unwrap_or(0);
+        Self {
+            min_id,
+            max_id,
+            used_ids,
+            next_id: last_id.saturating_add(1).max(min_id),
+        }
+    }
+
+    /// Marks an ID given by the operator as used.
+    pub fn reserve(&mut self, id: u32) {
+        self.used_ids.insert(id);
+        if id >= self.next_id {
+            self.next_id = id.saturating_add(1);
+        }
+    }
+
+    /// Returns the next free ID following the last used one. Once the end of
+    /// the range is reached, the gaps from the beginning of the range are used.
+    pub fn allocate(&mut self) -> Result<u32, BibErrorResponse> {
+        if self.min_id > self.max_id {
+            return Err(BibErrorResponse::BarcodeDigitsOutOfRange);
+        }
+        let mut id = self.next_id;
+        if id < self.min_id || id > self.max_id {
+            id = self.min_id;
+        }
+        let start_id = id;
+        while self.used_ids.contains(&id) {
+            id = if id >= self.max_id {
+                self.min_id
+            } else {
+                id + 1
+            };
+            if id == start_id {
+                return Err(BibErrorResponse::BarcodeDigitsOutOfRange);
+            }
+        }
+
+        self.used_ids.insert(id);
+        self.next_id = id.saturating_add(1);
+        Ok(id)
+    }
+}
+
+pub async fn get_barcode_setting(db: &Database) -> Result<BarcodeSetting, BibErrorResponse> {
+    let barcode_setting = BarcodeSetting::default();
+    let mut barcode_setting = match search_items(db, &barcode_setting).await {
+        Ok(barcode_setting) => barcode_setting,
+        Err(e) => {
+            return Err(BibErrorResponse::DataNotFound(e.to_string()));
+        }
+    };
+    if barcode_setting.len() != 1 {
+        return Err(BibErrorResponse::DataDuplicated(0));
+    }
+    Ok(barcode_setting.pop().unwrap())
+}
+
+pub async fn new_user_id_allocator(
+    db: &Database,
+    users: &[User],
+) -> Result<IdAllocator, BibErrorResponse> {
+    let setting = get_barcode_setting(db).await?;
+    Ok(IdAllocator::new(
+        setting.user_keta_min,
+        setting.user_keta_max,
+        users.iter().map(|user| user.id),
+    ))
+}
+
+pub async fn new_book_id_allocator(
+    db: &Database,
+    books: &[Book],
+) -> Result<IdAllocator, BibErrorResponse> {
+    let setting = get_barcode_setting(db).await?;
+    Ok(IdAllocator::new(
+        setting.book_keta_min,
+        setting.book_keta_max,
+        books.iter().map(|book| book.id),
+    ))
+}
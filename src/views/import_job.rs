@@ -0,0 +1,92 @@
+use crate::item::{Entity, ImportJob};
+use crate::views::utils::get_nowtime;
+use bson::{doc, Document};
+use mongodb::Database;
+use std::collections::HashMap;
+use std::env;
+use std::error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The cancel flags of the import jobs running in this process, keyed by the
+/// DB name and the job ID. `instance` names this process on the jobs it runs.
+#[derive(Default)]
+pub struct ImportJobs {
+    pub instance: String,
+    pub flags: Mutex<HashMap<(String, i64), Arc<AtomicBool>>>,
+}
+
+/// Returns the name of this server among the ones sharing the DBs, given by
+/// `BIB_INSTANCE_ID` or else the host name. It has to stay the same across
+/// restarts so that the jobs left by this server can be told.
+pub fn instance_id() -> String {
+    if let Ok(id) = env::var("BIB_INSTANCE_ID") {
+        return id;
+    }
+    match std::fs::read_to_string("/etc/hostname") {
+        Ok(hostname) if hostname.trim() != "" => hostname.trim().to_string(),
+        _ => String::from("default"),
+    }
+}
+
+impl ImportJobs {
+    pub fn new(instance: &str) -> Self {
+        Self {
+            instance: instance.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn register(&self, dbname: &str, id: i64) -> Arc<AtomicBool> {
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut flags = self.flags.lock().unwrap();
+        flags.insert((dbname.to_string(), id), flag.clone());
+        flag
+    }
+
+    pub fn remove(&self, dbname: &str, id: i64) {
+        let mut flags = self.flags.lock().unwrap();
+        flags.remove(&(dbname.to_string(), id));
+    }
+
+    /// Asks a running job to stop after the rows being written. Returns
+    /// false if the job is not running.
+    pub fn cancel(&self, dbname: &str, id: i64) -> bool {
+        let flags = self.flags.lock().unwrap();
+        match flags.get(&(dbname.to_string(), id)) {
+            Some(flag) => {
+                flag.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Marks the jobs left checking or running by the previous process of this
+/// instance as failed, since their cancel flags are gone and nothing is
+/// going to finish them. The jobs of the other instances are left alone.
+pub async fn fail_interrupted_jobs(
+    db: &Database,
+    instance: &str,
+    time_zone: &str,
+) -> Result<u64, Box<dyn error::Error>> {
+    let job = ImportJob::default();
+    let collection = db.collection::<Document>(job.get_collection_name());
+    let finished_at = get_nowtime(time_zone).format("%Y/%m/%d %H:%M:%S");
+    let result = collection
+        .update_many(
+            doc! {
+                "instance": instance,
+                "status": { "$in": ["checking", "running"] },
+            },
+            doc! { "$set": {
+                "status": "failed",
+                "message": "The server was restarted while the job was running",
+                "finished_at": finished_at.to_string(),
+            } },
+            None,
+        )
+        .await?;
+    Ok(result.modified_count)
+}
@@ -1,23 +1,31 @@
 use actix_web::{web, HttpResponse};
 mod account;
 mod auth;
+mod backup;
 mod barcode;
 pub mod cache;
+mod catalog;
+mod change_log;
 mod constatns;
 mod content_loader;
 mod csv;
+mod custom_field;
 mod db_helper;
 mod edit;
 mod export;
 mod history;
+mod id_allocator;
+pub mod import_job;
 mod manual;
 mod member;
 mod path;
+pub mod pdf;
 mod reply;
 mod search;
 mod session;
 mod setting;
 mod stripe;
+mod text_encoding;
 pub mod transaction;
 mod user;
 mod utils;
@@ -95,6 +103,8 @@ pub fn views_factory(app: &mut web::ServiceConfig) {
     account::account_factory(app);
     manual::manual_factory(app);
     barcode::barcode_factory(app);
+    backup::backup_factory(app);
+    catalog::catalog_factory(app);
     csv::csv_factory(app);
     stripe::stripe_factory(app);
 
@@ -0,0 +1,267 @@
+use crate::error::BibErrorResponse;
+use lazy_static::lazy_static;
+use printpdf::path::PaintMode;
+use printpdf::{
+    Color, Greyscale, IndirectFontRef, Mm, PdfDocument, PdfDocumentReference, PdfLayerReference,
+    Rect,
+};
+use std::env;
+use std::error;
+use std::fs;
+
+lazy_static! {
+    // A TrueType/OpenType font with Japanese glyphs, e.g. IPAexGothic, given
+    // by BIB_PDF_FONT as described in README.md. It is read once and only
+    // the glyphs used are embedded in each PDF.
+    static ref PDF_FONT: Result<Vec<u8>, String> = read_font();
+}
+
+fn read_font() -> Result<Vec<u8>, String> {
+    let path = env::var("BIB_PDF_FONT").map_err(|_| "BIB_PDF_FONT is not set".to_string())?;
+    let data = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+    // Parsed here so that a broken font fails at startup
+    PdfDocument::empty("")
+        .add_external_font(data.as_slice())
+        .map_err(|e| format!("{}: {}", path, e))?;
+    Ok(data)
+}
+
+/// Checks the font at startup, as the PDFs cannot be made without it.
+pub fn check_font() -> Result<(), String> {
+    PDF_FONT.as_ref().map(|_| ()).map_err(|e| e.clone())
+}
+
+pub const A4_WIDTH: f32 = 210.0;
+pub const A4_HEIGHT: f32 = 297.0;
+
+const PT_PER_MM: f32 = 72.0 / 25.4;
+// The labels smaller than 5mm cannot hold a barcode
+const MAX_LABELS_PER_SIDE: u32 = 40;
+
+/// An A4 document whose coordinates are given in mm from the top-left corner.
+pub struct PdfSheet {
+    doc: PdfDocumentReference,
+    font: IndirectFontRef,
+    layer: PdfLayerReference,
+}
+
+impl PdfSheet {
+    pub fn new(title: &str) -> Result<Self, Box<dyn error::Error>> {
+        let (doc, page, layer) = PdfDocument::new(title, Mm(A4_WIDTH), Mm(A4_HEIGHT), "Layer 1");
+        let font = PDF_FONT.as_ref().map_err(|e| e.clone())?;
+        let font = doc.add_external_font(font.as_slice())?;
+        let layer = doc.get_page(page).get_layer(layer);
+        Ok(Self { doc, font, layer })
+    }
+
+    pub fn add_page(&mut self) {
+        let (page, layer) = self.doc.add_page(Mm(A4_WIDTH), Mm(A4_HEIGHT), "Layer 1");
+        self.layer = self.doc.get_page(page).get_layer(layer);
+    }
+
+    /// Writes a text whose top-left corner is at (x, y).
+    pub fn text(&self, text: &str, font_size: f32, x: f32, y: f32) -> Result<(), BibErrorResponse> {
+        let baseline = y + font_size / PT_PER_MM * 0.85;
+        self.layer
+            .use_text(text, font_size, Mm(x), Mm(A4_HEIGHT - baseline), &self.font);
+        Ok(())
+    }
+
+    /// Writes a text centered horizontally at `center_x`.
+    pub fn centered_text(
+        &self,
+        text: &str,
+        font_size: f32,
+        center_x: f32,
+        y: f32,
+    ) -> Result<(), BibErrorResponse> {
+        let x = center_x - text_width(text, font_size) / 2.0;
+        self.text(text, font_size, x, y)
+    }
+
+    pub fn rect(&self, x: f32, y: f32, width: f32, height: f32) {
+        self.layer.set_outline_thickness(0.3);
+        let rect = Rect::new(
+            Mm(x),
+            Mm(A4_HEIGHT - y - height),
+            Mm(x + width),
+            Mm(A4_HEIGHT - y),
+        )
+        .with_mode(PaintMode::Stroke);
+        self.layer.add_rect(rect);
+    }
+
+    /// Paints a black rectangle, such as a bar of a barcode.
+    pub fn fill_rect(&self, x: f32, y: f32, width: f32, height: f32) {
+        self.layer
+            .set_fill_color(Color::Greyscale(Greyscale::new(0.0, None)));
+        let rect = Rect::new(
+            Mm(x),
+            Mm(A4_HEIGHT - y - height),
+            Mm(x + width),
+            Mm(A4_HEIGHT - y),
+        )
+        .with_mode(PaintMode::Fill);
+        self.layer.add_rect(rect);
+    }
+
+    pub fn save(self) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        Ok(self.doc.save_to_bytes()?)
+    }
+}
+
+/// A sheet of labels laid out in a grid on A4 paper. All values are in mm.
+#[derive(Clone, Debug)]
+pub struct LabelSheet {
+    pub name: &'static str,
+    pub columns: u32,
+    pub rows: u32,
+    pub label_width: f32,
+    pub label_height: f32,
+    pub margin_top: f32,
+    pub margin_left: f32,
+    pub pitch_x: f32,
+    pub pitch_y: f32,
+}
+
+impl LabelSheet {
+    /// Common A4 label sheets for spine labels.
+    pub fn presets() -> Vec<Self> {
+        vec![
+            Self {
+                name: "a4_5x13",
+                columns: 5,
+                rows: 13,
+                label_width: 38.1,
+                label_height: 21.2,
+                margin_top: 10.7,
+                margin_left: 4.65,
+                pitch_x: 40.6,
+                pitch_y: 21.2,
+            },
+            Self {
+                name: "a4_4x11",
+                columns: 4,
+                rows: 11,
+                label_width: 48.3,
+                label_height: 25.4,
+                margin_top: 8.8,
+                margin_left: 8.4,
+                pitch_x: 48.3,
+                pitch_y: 25.4,
+            },
+            Self {
+                name: "a4_3x8",
+                columns: 3,
+                rows: 8,
+                label_width: 70.0,
+                label_height: 33.9,
+                margin_top: 12.9,
+                margin_left: 0.0,
+                pitch_x: 70.0,
+                pitch_y: 33.9,
+            },
+        ]
+    }
+
+    /// Credit card size cards, 2 by 5 in the middle of A4 paper.
+    pub fn cards() -> Self {
+        Self {
+            name: "a4_card_2x5",
+            columns: 2,
+            rows: 5,
+            label_width: 85.6,
+            label_height: 54.0,
+            margin_top: 13.5,
+            margin_left: 19.4,
+            pitch_x: 85.6,
+            pitch_y: 54.0,
+        }
+    }
+
+    pub fn find_preset(name: &str) -> Option<Self> {
+        Self::presets().into_iter().find(|sheet| sheet.name == name)
+    }
+
+    /// Checks that the labels are laid out within the A4 paper.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.columns == 0 || self.rows == 0 {
+            return Err("The number of labels is 0".to_string());
+        }
+        if self.columns > MAX_LABELS_PER_SIDE || self.rows > MAX_LABELS_PER_SIDE {
+            return Err(format!(
+                "The number of labels exceeds {} per row or column",
+                MAX_LABELS_PER_SIDE
+            ));
+        }
+        if self.columns.checked_mul(self.rows).is_none() {
+            return Err("The number of labels is too large".to_string());
+        }
+        let sizes = [
+            self.label_width,
+            self.label_height,
+            self.margin_top,
+            self.margin_left,
+            self.pitch_x,
+            self.pitch_y,
+        ];
+        if sizes.iter().any(|size| !size.is_finite() || *size < 0.0)
+            || self.label_width == 0.0
+            || self.label_height == 0.0
+        {
+            return Err("The label sizes are invalid".to_string());
+        }
+        // The labels must not overlap, which rules out a pitch of 0 as well
+        if (self.columns > 1 && self.pitch_x < self.label_width)
+            || (self.rows > 1 && self.pitch_y < self.label_height)
+        {
+            return Err("The pitches are smaller than the label sizes".to_string());
+        }
+        let right = self.margin_left + self.pitch_x * (self.columns - 1) as f32 + self.label_width;
+        let bottom = self.margin_top + self.pitch_y * (self.rows - 1) as f32 + self.label_height;
+        // Allow for the rounding of the sizes given by the label makers
+        if right > A4_WIDTH + 0.5 || bottom > A4_HEIGHT + 0.5 {
+            return Err("The labels do not fit on A4 paper".to_string());
+        }
+        Ok(())
+    }
+
+    /// Returns the number of labels on a page, which is at least 1 for the
+    /// sheets that are validated.
+    pub fn labels_per_page(&self) -> u32 {
+        self.columns.saturating_mul(self.rows).max(1)
+    }
+
+    /// Returns the top-left corner of the index-th label on its page.
+    pub fn position(&self, index: u32) -> (f32, f32) {
+        let index = index % self.labels_per_page();
+        let column = index % self.columns;
+        let row = index / self.columns;
+        (
+            self.margin_left + self.pitch_x * column as f32,
+            self.margin_top + self.pitch_y * row as f32,
+        )
+    }
+}
+
+/// Estimates the width of a text in mm, counting the full-width characters
+/// as 1em and the others as 0.55em.
+pub fn text_width(text: &str, font_size: f32) -> f32 {
+    let em = text
+        .chars()
+        .map(|c| if c.is_ascii() { 0.55 } else { 1.0 })
+        .sum::<f32>();
+    em * font_size / PT_PER_MM
+}
+
+/// Shortens a text with "..." so that it fits in `width` mm.
+pub fn fit_text(text: &str, font_size: f32, width: f32) -> String {
+    if text_width(text, font_size) <= width {
+        return text.to_string();
+    }
+    let mut fitted: String = text.to_string();
+    while !fitted.is_empty() && text_width(&format!("{}...", fitted), font_size) > width {
+        fitted.pop();
+    }
+    format!("{}...", fitted)
+}
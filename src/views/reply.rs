@@ -1,5 +1,10 @@
-use crate::item::{BarcodeSetting, Book, BorrowedBook, RentalSetting, TransactionItem, User};
+use crate::error::ImportError;
+use crate::item::{
+    BarcodeSetting, Book, BorrowedBook, CatalogDraft, ChangeLog, CustomFieldSetting, ImportJob,
+    ImportMapping, RentalSetting, TransactionItem, User,
+};
 use crate::views::search::search::DelayedBook;
+use crate::views::search::series::Series;
 use serde::Serialize;
 
 #[derive(Serialize, Debug)]
@@ -14,12 +19,28 @@ pub struct Reply {
     pub delayed_list: Vec<DelayedBook>,
     pub uname: String,
     pub email: String,
+    pub library_name: String,
     pub plan: String,
     pub rental_setting: RentalSetting,
     pub barcode_setting: BarcodeSetting,
+    pub custom_field_setting: CustomFieldSetting,
     pub returned_book_title: String,
     pub returned_book_id: u32,
+    pub scan_action: String,
     pub barcode_size: u32,
+    pub barcodes: Vec<String>,
+    pub draft_list: Vec<CatalogDraft>,
+    pub assigned_ids: Vec<u32>,
+    pub series_list: Vec<Series>,
+    pub change_logs: Vec<ChangeLog>,
+    pub import_errors: Vec<ImportError>,
+    pub num_inserted: usize,
+    pub num_updated: usize,
+    pub import_mappings: Vec<ImportMapping>,
+    pub import_fields: Vec<String>,
+    pub preview_headers: Vec<String>,
+    pub preview_rows: Vec<Vec<String>>,
+    pub import_job: ImportJob,
 }
 
 impl Default for Reply {
@@ -35,12 +56,28 @@ impl Default for Reply {
             delayed_list: vec![],
             uname: String::new(),
             email: String::new(),
+            library_name: String::new(),
             plan: String::new(),
             rental_setting: RentalSetting::default(),
             barcode_setting: BarcodeSetting::default(),
+            custom_field_setting: CustomFieldSetting::default(),
             returned_book_title: String::new(),
             returned_book_id: 0,
+            scan_action: String::new(),
             barcode_size: 0,
+            barcodes: vec![],
+            draft_list: vec![],
+            assigned_ids: vec![],
+            series_list: vec![],
+            change_logs: vec![],
+            import_errors: vec![],
+            num_inserted: 0,
+            num_updated: 0,
+            import_mappings: vec![],
+            import_fields: vec![],
+            preview_headers: vec![],
+            preview_rows: vec![],
+            import_job: ImportJob::default(),
         }
     }
 }
@@ -1,8 +1,9 @@
 use crate::error::BibErrorResponse;
-use crate::item::atoi;
 use crate::item::search_items;
 use crate::item::Book;
+use crate::item::{atoi, normalize_isbn, normalize_isbn_query};
 use crate::views::cache::*;
+use crate::views::custom_field::get_custom_field_setting;
 use crate::views::db_helper::get_db;
 use crate::views::reply::Reply;
 use crate::views::session::check_operator_session;
@@ -22,6 +23,12 @@ pub struct GetBookForm {
     pub title: String,
     pub kana: String,
     pub author: String,
+    #[serde(default)]
+    pub isbn: String,
+    #[serde(default)]
+    pub custom_field: String,
+    #[serde(default)]
+    pub custom_value: String,
     pub user_id: String,
 }
 
@@ -39,7 +46,11 @@ pub async fn search_isbn(
     _session: Session,
     form: web::Query<GetBookByISBNForm>,
 ) -> Result<HttpResponse, BibErrorResponse> {
-    let isbn = form.isbn.clone();
+    let isbn =
+        normalize_isbn(&form.isbn).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    if isbn.is_empty() {
+        return Err(BibErrorResponse::InvalidArgument("isbn".to_string()));
+    }
 
     let fut = async move {
         fetch_book_info(&isbn)
@@ -75,6 +86,19 @@ pub async fn search_book(
         dbname = check_user_session(&session, user_id)?;
     }
 
+    // The name is used as a path in the documents
+    if !form.custom_field.is_empty() {
+        let db = get_db(&data, &session).await?;
+        let setting = get_custom_field_setting(&db).await;
+        if !setting
+            .book_fields
+            .iter()
+            .any(|field| field.name == form.custom_field)
+        {
+            return Err(BibErrorResponse::InvalidArgument(form.custom_field.clone()));
+        }
+    }
+
     let cache_map = cache_map.lock().unwrap();
     let cache = cache_map.get(&dbname);
     if cache.is_none() {
@@ -96,6 +120,12 @@ pub async fn search_book(
     book.title = form.title.clone();
     book.kana = form.kana.clone();
     book.author = form.author.clone();
+    book.isbn = normalize_isbn_query(&form.isbn)
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    if !form.custom_field.is_empty() && !form.custom_value.is_empty() {
+        book.custom_fields
+            .insert(form.custom_field.clone(), form.custom_value.clone());
+    }
     get_book_list(&session, data, &cache, &book).await
 }
 
@@ -2,6 +2,7 @@ use crate::views::path::Path;
 use actix_web::web;
 mod book;
 pub mod search;
+pub mod series;
 mod user;
 
 pub fn search_factory(app: &mut web::ServiceConfig) {
@@ -27,5 +28,9 @@ pub fn search_factory(app: &mut web::ServiceConfig) {
     .route(
         &base_path.define(String::from("/book/isbn/search")),
         web::get().to(book::search_isbn),
+    )
+    .route(
+        &base_path.define(String::from("/book/series/search")),
+        web::get().to(series::search_series),
     );
 }
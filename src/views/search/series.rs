@@ -0,0 +1,161 @@
+use crate::error::BibErrorResponse;
+use crate::item::{normalize_series, parse_volume, search_items, Book};
+use crate::views::cache::*;
+use crate::views::db_helper::get_db;
+use crate::views::reply::Reply;
+use crate::views::session::check_operator_session;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use shared_mongodb::ClientHolder;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+// The volume numbers beyond these are taken as typos or as years, and the
+// missing volumes are not reported for them
+const MAX_VOLUME: u32 = 999;
+const MAX_VOLUMES_PER_BOOK: u32 = 10;
+
+#[derive(Deserialize, Debug)]
+pub struct GetSeriesForm {
+    #[serde(default)]
+    pub series: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Series {
+    pub name: String,
+    pub books: Vec<Book>,
+    pub missing_volumes: Vec<u32>,
+    pub num_on_loan: u32,
+    pub total_borrowed_count: u32,
+}
+
+impl Series {
+    fn new(mut books: Vec<Book>) -> Self {
+        // The books without a volume number follow the numbered ones
+        books.sort_by(|a, b| {
+            let a_volume = parse_volume(&a.volume).unwrap_or(u32::MAX);
+            let b_volume = parse_volume(&b.volume).unwrap_or(u32::MAX);
+            a_volume
+                .cmp(&b_volume)
+                .then_with(|| a.volume.cmp(&b.volume))
+                .then_with(|| a.id.cmp(&b.id))
+        });
+
+        let volumes: HashSet<u32> = books
+            .iter()
+            .filter_map(|book| parse_volume(&book.volume))
+            .collect();
+        let last_volume = volumes.iter().copied().max().unwrap_or(0);
+        let num_books = u32::try_from(books.len()).unwrap_or(u32::MAX);
+        let missing_volumes = if last_volume > MAX_VOLUME
+            || last_volume > num_books.saturating_mul(MAX_VOLUMES_PER_BOOK)
+        {
+            vec![]
+        } else {
+            (1..last_volume)
+                .filter(|volume| !volumes.contains(volume))
+                .collect()
+        };
+
+        Self {
+            name: books[0].series.trim().to_string(),
+            missing_volumes,
+            num_on_loan: books.iter().filter(|book| book.owner_id.is_some()).count() as u32,
+            total_borrowed_count: books.iter().map(|book| book.borrowed_count).sum(),
+            books,
+        }
+    }
+}
+
+pub async fn search_series(
+    session: Session,
+    form: web::Query<GetSeriesForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+    cache_map: web::Data<Mutex<HashMap<String, Cache>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    let dbname = check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let book = Book::default();
+    let books = match search_items(&db, &book).await {
+        Ok(books) => books,
+        Err(_) => {
+            return Err(BibErrorResponse::BookNotFound(book.id));
+        }
+    };
+
+    let cache_map = cache_map.lock().unwrap();
+    let cache = cache_map.get(&dbname);
+    if cache.is_none() {
+        return Err(BibErrorResponse::NotAuthorized);
+    }
+    let cache = cache.unwrap();
+
+    let keyword = normalize_series(&form.series);
+    let mut groups: BTreeMap<String, Vec<Book>> = BTreeMap::new();
+    for mut book in books {
+        let series = normalize_series(&book.series);
+        if series.is_empty() || !series.contains(&keyword) {
+            continue;
+        }
+        if let Some(info) = cache.get(book.id) {
+            book.owner_id = Some(info.owner_id);
+            book.return_deadline = Some(info.return_deadline.clone());
+        }
+        groups.entry(series).or_default().push(book);
+    }
+    drop(cache_map);
+
+    if groups.is_empty() {
+        return Err(BibErrorResponse::DataNotFound(String::new()));
+    }
+
+    let reply = Reply {
+        series_list: groups.into_values().map(Series::new).collect(),
+        ..Default::default()
+    };
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(volumes: &[&str]) -> Series {
+        let books = volumes
+            .iter()
+            .enumerate()
+            .map(|(i, volume)| Book {
+                id: i as u32 + 1,
+                series: String::from(" Series "),
+                volume: volume.to_string(),
+                ..Book::default()
+            })
+            .collect();
+        Series::new(books)
+    }
+
+    #[test]
+    fn test_series_order() {
+        let series = series(&["第十巻", "", "2", "１"]);
+        assert_eq!(series.name, "Series");
+        let volumes: Vec<&str> = series.books.iter().map(|b| b.volume.as_str()).collect();
+        assert_eq!(volumes, vec!["１", "2", "第十巻", ""]);
+    }
+
+    #[test]
+    fn test_missing_volumes() {
+        assert_eq!(series(&["1", "3", "6"]).missing_volumes, vec![2, 4, 5]);
+        assert!(series(&["1", "2", "3"]).missing_volumes.is_empty());
+        assert!(series(&["", "上"]).missing_volumes.is_empty());
+        // A year or a typo is not taken as the last volume
+        assert!(series(&["1", "2020"]).missing_volumes.is_empty());
+        assert!(series(&["1", "21"]).missing_volumes.is_empty());
+        assert_eq!(series(&["1", "20"]).missing_volumes.len(), 18);
+    }
+}
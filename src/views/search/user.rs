@@ -2,6 +2,7 @@ use crate::error::BibErrorResponse;
 use crate::item::atoi;
 use crate::item::search_items;
 use crate::item::User;
+use crate::views::custom_field::get_custom_field_setting;
 use crate::views::db_helper::get_db;
 use crate::views::reply::Reply;
 use crate::views::session::check_operator_session;
@@ -18,6 +19,10 @@ pub struct GetUserForm {
     pub name: String,
     pub kana: String,
     pub category: String,
+    #[serde(default)]
+    pub custom_field: String,
+    #[serde(default)]
+    pub custom_value: String,
 }
 
 pub async fn search_user(
@@ -28,6 +33,19 @@ pub async fn search_user(
     debug!("{:?}", form);
     check_operator_session(&session)?;
 
+    // The name is used as a path in the documents
+    if !form.custom_field.is_empty() {
+        let db = get_db(&data, &session).await?;
+        let setting = get_custom_field_setting(&db).await;
+        if !setting
+            .user_fields
+            .iter()
+            .any(|field| field.name == form.custom_field)
+        {
+            return Err(BibErrorResponse::InvalidArgument(form.custom_field.clone()));
+        }
+    }
+
     let mut user = User::default();
     if form.id == "" {
         user.id = 0;
@@ -37,6 +55,10 @@ pub async fn search_user(
     user.name = form.name.clone();
     user.kana = form.kana.clone();
     user.category = form.category.clone();
+    if !form.custom_field.is_empty() && !form.custom_value.is_empty() {
+        user.custom_fields
+            .insert(form.custom_field.clone(), form.custom_value.clone());
+    }
     get_user_list(data, &user, &session).await
 }
 
@@ -0,0 +1,387 @@
+use super::job::ImportRow;
+use super::setting::find_duplicate_books;
+use crate::error::ImportError;
+use crate::item::{normalize_isbn, BarcodeSetting, Book, CustomField, CustomFieldSetting};
+use crate::item::{SystemSetting, User};
+use bson::Document;
+use std::collections::BTreeMap;
+use std::error;
+
+type Setter<T> = fn(&mut T, &str) -> Result<(), Box<dyn error::Error>>;
+type Getter<T> = fn(&T) -> String;
+
+/// A column of the imported file and the field it is written to.
+pub struct Column<T> {
+    pub name: &'static str,
+    pub field: &'static str,
+    pub set: Setter<T>,
+    pub get: Getter<T>,
+}
+
+impl<T> Clone for Column<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Column<T> {}
+
+/// A value of the imported file to write to a registered record.
+pub enum Change<T> {
+    Field(Column<T>, String),
+    Custom(String, String),
+}
+
+// The columns written by the export but kept by the rental process
+const RUNTIME_COLUMNS: [&str; 1] = ["貸出回数"];
+
+/// The records imported by the column names in the header.
+pub trait Importable: Clone + Default {
+    const ID_COLUMN: &'static str;
+    fn columns() -> Vec<Column<Self>>;
+    fn custom_field_defs(setting: &CustomFieldSetting) -> &[CustomField];
+    fn max_registered(setting: &SystemSetting) -> u32;
+    /// Returns the minimum and maximum number of digits of the IDs.
+    fn id_digits(setting: &BarcodeSetting) -> (u32, u32);
+    fn id(&self) -> u32;
+    fn set_id(&mut self, id: u32);
+    fn custom_fields_mut(&mut self) -> &mut BTreeMap<String, String>;
+    /// Builds the new record again through the constructor used by a
+    /// single registration.
+    fn validate_new(&self) -> Result<Self, Box<dyn error::Error>>;
+    /// Reports the new records that look like other copies of the registered
+    /// ones or of the preceding rows.
+    fn check_duplicates(
+        _registered: &[Self],
+        _rows: &[ImportRow<Self>],
+        _errors: &mut Vec<ImportError>,
+    ) {
+    }
+}
+
+macro_rules! column {
+    ($name:expr, $field:ident) => {
+        Column {
+            name: $name,
+            field: stringify!($field),
+            set: |item, value| {
+                item.$field = value.to_string();
+                Ok(())
+            },
+            get: |item| item.$field.clone(),
+        }
+    };
+}
+
+impl Importable for User {
+    const ID_COLUMN: &'static str = "利用者ID";
+
+    fn columns() -> Vec<Column<Self>> {
+        vec![
+            column!("氏名", name),
+            column!("カナ", kana),
+            column!("利用者区分", category),
+            column!("学年クラス", grade),
+            column!("備考", remark),
+            column!("登録日", register_date),
+        ]
+    }
+
+    fn custom_field_defs(setting: &CustomFieldSetting) -> &[CustomField] {
+        &setting.user_fields
+    }
+
+    fn max_registered(setting: &SystemSetting) -> u32 {
+        setting.max_registered_users
+    }
+
+    fn id_digits(setting: &BarcodeSetting) -> (u32, u32) {
+        (setting.user_keta_min, setting.user_keta_max)
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    fn custom_fields_mut(&mut self) -> &mut BTreeMap<String, String> {
+        &mut self.custom_fields
+    }
+
+    fn validate_new(&self) -> Result<Self, Box<dyn error::Error>> {
+        let mut user = User::new(
+            &self.id.to_string(),
+            &self.name,
+            &self.kana,
+            &self.category,
+            &self.grade,
+            &self.remark,
+            &self.register_date,
+        )?;
+        user.custom_fields = self.custom_fields.clone();
+        Ok(user)
+    }
+}
+
+impl Importable for Book {
+    const ID_COLUMN: &'static str = "図書ID";
+
+    fn columns() -> Vec<Column<Self>> {
+        vec![
+            column!("タイトル", title),
+            column!("保管場所", location),
+            column!("図書分類", category),
+            column!("破損状況", status),
+            column!("著者", author),
+            column!("出版社", publisher),
+            column!("出版日", published_date),
+            column!("シリーズ", series),
+            column!("巻数", volume),
+            column!("ページ", page),
+            column!("タイトルヨミ", kana),
+            column!("分類記号", category_symbol),
+            column!("図書記号", library_symbol),
+            column!("巻冊記号", volume_symbol),
+            column!("禁帯出", forbidden),
+            column!("備考", remark),
+            Column {
+                name: "ISBN",
+                field: "isbn",
+                // An unchanged ISBN is kept as stored, so that an export of
+                // the books with legacy ISBNs can be imported again
+                set: |book, value| {
+                    if value != book.isbn {
+                        book.isbn = normalize_isbn(value)?;
+                    }
+                    Ok(())
+                },
+                get: |book| book.isbn.clone(),
+            },
+            column!("登録日", register_date),
+            column!("登録区分", register_type),
+        ]
+    }
+
+    fn custom_field_defs(setting: &CustomFieldSetting) -> &[CustomField] {
+        &setting.book_fields
+    }
+
+    fn max_registered(setting: &SystemSetting) -> u32 {
+        setting.max_registered_books
+    }
+
+    fn id_digits(setting: &BarcodeSetting) -> (u32, u32) {
+        (setting.book_keta_min, setting.book_keta_max)
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    fn custom_fields_mut(&mut self) -> &mut BTreeMap<String, String> {
+        &mut self.custom_fields
+    }
+
+    fn validate_new(&self) -> Result<Self, Box<dyn error::Error>> {
+        let mut book = Book::new(
+            &self.id.to_string(),
+            &self.title,
+            &self.location,
+            &self.category,
+            &self.status,
+            &self.author,
+            &self.publisher,
+            &self.published_date,
+            &self.series,
+            &self.volume,
+            &self.page,
+            &self.kana,
+            &self.category_symbol,
+            &self.library_symbol,
+            &self.volume_symbol,
+            &self.forbidden,
+            &self.remark,
+            &normalize_isbn(&self.isbn)?,
+            &self.register_date,
+            &self.register_type,
+        )?;
+        book.custom_fields = self.custom_fields.clone();
+        Ok(book)
+    }
+
+    fn check_duplicates(
+        registered: &[Self],
+        rows: &[ImportRow<Self>],
+        errors: &mut Vec<ImportError>,
+    ) {
+        find_duplicate_books(registered, rows, errors);
+    }
+}
+
+/// Applies the changes of an imported row to `item` and returns the `$set`
+/// document of the changed fields, so that the fields updated by loans and
+/// returns in the meantime are not overwritten.
+pub fn apply_changes<T: Importable>(
+    item: &mut T,
+    changes: &[Change<T>],
+) -> Result<Document, Box<dyn error::Error>> {
+    let mut set = Document::new();
+    let mut custom = false;
+    for change in changes {
+        match change {
+            Change::Field(column, value) => {
+                (column.set)(item, value)?;
+                set.insert(column.field, (column.get)(item));
+            }
+            Change::Custom(name, value) => {
+                match value.as_str() {
+                    "" => item.custom_fields_mut().remove(name),
+                    _ => item.custom_fields_mut().insert(name.clone(), value.clone()),
+                };
+                custom = true;
+            }
+        }
+    }
+    if custom {
+        set.insert("custom_fields", bson::to_bson(item.custom_fields_mut())?);
+    }
+    Ok(set)
+}
+
+/// Returns the column names that can be imported, the ID comes first.
+pub fn field_names<T: Importable>(custom_fields: &[CustomField]) -> Vec<String> {
+    let mut names = vec![T::ID_COLUMN.to_string()];
+    names.extend(T::columns().iter().map(|column| column.name.to_string()));
+    names.extend(custom_fields.iter().map(|field| field.name.clone()));
+    names
+}
+
+/// What a column of the imported file is written to.
+pub enum Target<'a, T> {
+    Id,
+    Field(&'a Column<T>),
+    Custom(&'a CustomField),
+    Ignored,
+}
+
+/// Finds the target of each column by the header name, except for the
+/// `ignored` columns. The unknown names are returned as errors.
+pub fn map_headers<'a, T: Importable>(
+    headers: &csv::StringRecord,
+    ignored: &[usize],
+    columns: &'a [Column<T>],
+    custom_fields: &'a [CustomField],
+) -> Result<Vec<Target<'a, T>>, Vec<String>> {
+    let mut targets = vec![];
+    let mut unknown = vec![];
+    for (i, header) in headers.iter().map(str::trim).enumerate() {
+        if ignored.contains(&i) {
+            targets.push(Target::Ignored);
+        } else if header == T::ID_COLUMN {
+            targets.push(Target::Id);
+        } else if let Some(column) = columns.iter().find(|column| column.name == header) {
+            targets.push(Target::Field(column));
+        } else if let Some(field) = custom_fields.iter().find(|field| field.name == header) {
+            targets.push(Target::Custom(field));
+        } else if RUNTIME_COLUMNS.contains(&header) {
+            targets.push(Target::Ignored);
+        } else {
+            unknown.push(header.to_string());
+        }
+    }
+    if !unknown.is_empty() {
+        return Err(unknown);
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book_column(name: &str) -> Column<Book> {
+        *Book::columns()
+            .iter()
+            .find(|column| column.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_map_headers() {
+        let columns = Book::columns();
+        let custom_fields = vec![CustomField::new("棚,text").unwrap()];
+        let headers =
+            csv::StringRecord::from(vec!["図書ID", " タイトル ", "棚", "貸出回数", "メモ"]);
+
+        let unknown = map_headers(&headers, &[], &columns, &custom_fields)
+            .err()
+            .unwrap();
+        assert_eq!(unknown, vec!["メモ"]);
+
+        let targets = map_headers(&headers, &[4], &columns, &custom_fields).unwrap();
+        assert!(matches!(targets[0], Target::Id));
+        assert!(matches!(targets[1], Target::Field(column) if column.field == "title"));
+        assert!(matches!(targets[2], Target::Custom(field) if field.name == "棚"));
+        // The borrowed count is kept by the rental process
+        assert!(matches!(targets[3], Target::Ignored));
+        assert!(matches!(targets[4], Target::Ignored));
+
+        // A known name can be ignored as well
+        let targets = map_headers(&headers, &[0, 4], &columns, &custom_fields).unwrap();
+        assert!(matches!(targets[0], Target::Ignored));
+    }
+
+    #[test]
+    fn test_apply_changes() {
+        let mut book = Book {
+            title: String::from("Old"),
+            author: String::from("Author"),
+            ..Book::default()
+        };
+        book.custom_fields
+            .insert(String::from("棚"), String::from("1"));
+        let changes = vec![
+            Change::Field(book_column("タイトル"), String::from("New")),
+            Change::Field(book_column("ISBN"), String::from("4-10-109205-2")),
+            Change::Custom(String::from("棚"), String::new()),
+            Change::Custom(String::from("メモ"), String::from("x")),
+        ];
+        let set = apply_changes(&mut book, &changes).unwrap();
+
+        assert_eq!(book.title, "New");
+        assert_eq!(book.author, "Author");
+        assert_eq!(book.isbn, "9784101092058");
+        let keys: Vec<&str> = set.keys().map(String::as_str).collect();
+        assert_eq!(keys, vec!["title", "isbn", "custom_fields"]);
+        let custom_fields = set.get_document("custom_fields").unwrap();
+        assert_eq!(custom_fields.get_str("メモ").unwrap(), "x");
+        assert!(!custom_fields.contains_key("棚"));
+
+        // Only the custom fields are not written without a custom change
+        let set = apply_changes(&mut book, &changes[..1]).unwrap();
+        assert!(!set.contains_key("custom_fields"));
+    }
+
+    #[test]
+    fn test_apply_isbn() {
+        // A legacy ISBN is kept when the file has the same value
+        let mut book = Book {
+            isbn: String::from("legacy"),
+            ..Book::default()
+        };
+        let same = vec![Change::Field(book_column("ISBN"), String::from("legacy"))];
+        apply_changes(&mut book, &same).unwrap();
+        assert_eq!(book.isbn, "legacy");
+        assert!(book.validate_new().is_err());
+
+        let changed = vec![Change::Field(book_column("ISBN"), String::from("other"))];
+        assert!(apply_changes(&mut book, &changed).is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use crate::error::{BibErrorResponse, ImportError};
+use crate::item::{insert_item, search_item, update_item, update_item_fields};
+use crate::item::{is_not_found, Entity, ImportJob, SystemSetting};
+use crate::views::change_log::{record_change_by, Tracked};
+use crate::views::db_helper::get_db;
+use crate::views::import_job::ImportJobs;
+use crate::views::reply::Reply;
+use crate::views::session::{check_operator_session, get_uname};
+use crate::views::setting::columns::{apply_changes, Change, Importable};
+use crate::views::setting::setting::Upsert;
+use crate::views::text_encoding::TextEncoding;
+use crate::views::utils::get_nowtime;
+use actix_session::Session;
+use actix_web::body::{Body, ResponseBody};
+use actix_web::{web, HttpResponse, ResponseError, Result};
+use bson::doc;
+use chrono::Utc;
+use csv::WriterBuilder;
+use futures::future::join_all;
+use log::{debug, error, info};
+use mongodb::Database;
+use serde::Deserialize;
+use serde_json::Value;
+use shared_mongodb::ClientHolder;
+use std::error;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A validated row of an imported file. `before` is the registered record
+/// updated by the row and `changes` are the values of the file written to it.
+pub struct ImportRow<T> {
+    pub row: usize,
+    pub before: Option<T>,
+    pub item: T,
+    pub changes: Vec<Change<T>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImportJobForm {
+    pub id: i64,
+}
+
+fn format_time(time_zone: &str) -> String {
+    format!("{}", get_nowtime(time_zone).format("%Y/%m/%d %H:%M:%S"))
+}
+
+/// Creates the job record and runs `check` in the background. The rows it
+/// returns are written if there are no errors and it is not a dry run.
+pub async fn start_import_job<T, F>(
+    db: &Database,
+    jobs: web::Data<ImportJobs>,
+    session: &Session,
+    setting: &SystemSetting,
+    dry_run: bool,
+    check: F,
+) -> Result<ImportJob, BibErrorResponse>
+where
+    T: Importable + Tracked + Entity + Send + Sync + 'static,
+    F: Future<Output = Result<Upsert<T>, BibErrorResponse>> + 'static,
+{
+    let dbname = check_operator_session(session)?;
+    let job = ImportJob {
+        id: Utc::now().timestamp_micros(),
+        target: T::TARGET.to_string(),
+        status: String::from("checking"),
+        operator: get_uname(session)?,
+        started_at: format_time(&setting.time_zone),
+        instance: jobs.instance.clone(),
+        ..Default::default()
+    };
+    insert_item(db, &job)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    let cancelled = jobs.register(&dbname, job.id);
+    let db = db.clone();
+    let started = job.clone();
+    let num_threads = setting.num_threads;
+    let time_zone = setting.time_zone.clone();
+    actix_rt::spawn(async move {
+        let id = started.id;
+        let job = run_import_job(
+            &db,
+            started,
+            check,
+            dry_run,
+            cancelled,
+            num_threads,
+            &time_zone,
+        )
+        .await;
+        finish_import_job(&db, job, &time_zone).await;
+        jobs.remove(&dbname, id);
+    });
+    Ok(job)
+}
+
+/// Reads the record again and writes only the fields of the file, so that
+/// the loans and returns since the import started are kept. Returns the
+/// record before and after the update.
+async fn update_row<T>(db: &Database, row: &ImportRow<T>) -> Result<(T, T), Box<dyn error::Error>>
+where
+    T: Importable + Entity,
+{
+    let id = row.item.id();
+    let mut item = T::default();
+    item.set_id(id);
+    let before = match search_item(db, &item).await {
+        Ok(before) => before,
+        Err(e) if is_not_found(e.as_ref()) => {
+            return Err(format!("ID{} is not registered", id).into());
+        }
+        Err(e) => return Err(e),
+    };
+    let mut after = before.clone();
+    let set = apply_changes(&mut after, &row.changes)?;
+    if !set.is_empty() {
+        update_item_fields(db, &after, id, doc! { "$set": set }).await?;
+    }
+    Ok((before, after))
+}
+
+/// Writes a row and logs the change. Only the failure to write the row is
+/// returned: a row written without its change log must not be imported
+/// again as a failed row.
+async fn write_row<T>(
+    db: &Database,
+    operator: &str,
+    time_zone: &str,
+    row: &ImportRow<T>,
+) -> Result<(), Box<dyn error::Error>>
+where
+    T: Importable + Tracked + Entity + Send + Sync,
+{
+    let (operation, before, after) = match row.before {
+        Some(_) => {
+            let (before, after) = update_row(db, row).await?;
+            ("update", Some(before), after)
+        }
+        None => {
+            insert_item(db, &row.item).await?;
+            ("insert", None, row.item.clone())
+        }
+    };
+    if let Err(e) = record_change_by(
+        db,
+        operator,
+        time_zone,
+        operation,
+        before.as_ref(),
+        Some(&after),
+    )
+    .await
+    {
+        error!("The change of row {} is not recorded: {}", row.row, e);
+    }
+    Ok(())
+}
+
+/// Returns the message of an error for the job record, as the response
+/// would show it.
+fn error_message(e: &BibErrorResponse) -> String {
+    let response = e.error_response();
+    let body = match response.body() {
+        ResponseBody::Body(Body::Bytes(bytes)) => serde_json::from_slice(bytes).ok(),
+        _ => None,
+    };
+    let text = |body: &Value, key: &str| body[key].as_str().unwrap_or("").to_string();
+    match body {
+        Some(body) => match text(&body, "reason").as_str() {
+            "" => text(&body, "message"),
+            reason => format!("{}: {}", text(&body, "message"), reason),
+        },
+        None => e.to_string(),
+    }
+}
+
+/// Checks the file and writes the rows unless the check finds errors or it
+/// is a dry run. Returns the job to save as finished.
+async fn run_import_job<T, F>(
+    db: &Database,
+    mut job: ImportJob,
+    check: F,
+    dry_run: bool,
+    cancelled: Arc<AtomicBool>,
+    num_threads: u32,
+    time_zone: &str,
+) -> ImportJob
+where
+    T: Importable + Tracked + Entity + Send + Sync,
+    F: Future<Output = Result<Upsert<T>, BibErrorResponse>>,
+{
+    info!("Import job {} started", job.id);
+    let upsert = match check.await {
+        Ok(upsert) => upsert,
+        Err(e) => {
+            job.status = String::from("failed");
+            job.message = error_message(&e);
+            return job;
+        }
+    };
+
+    let num_inserted = upsert
+        .rows
+        .iter()
+        .filter(|row| row.before.is_none())
+        .count();
+    job.num_rows = upsert.rows.len().try_into().unwrap();
+    job.num_inserted = num_inserted.try_into().unwrap();
+    job.num_updated = job.num_rows - job.num_inserted;
+    job.assigned_ids = upsert.assigned_ids;
+    job.errors = upsert.errors;
+    if dry_run {
+        job.status = String::from("checked");
+        return job;
+    }
+    if !job.errors.is_empty() {
+        job.status = String::from("failed");
+        job.message = error_message(&BibErrorResponse::ImportFailed(vec![]));
+        return job;
+    }
+
+    job.status = String::from("running");
+    if let Err(e) = update_item(db, &job).await {
+        error!("{:?}", e);
+    }
+    write_rows(
+        db,
+        &mut job,
+        &upsert.rows,
+        num_threads,
+        time_zone,
+        &cancelled,
+    )
+    .await;
+    job
+}
+
+/// Writes `num_threads` rows at a time and saves the progress after each
+/// batch. The rows that fail are reported and the others are kept.
+async fn write_rows<T>(
+    db: &Database,
+    job: &mut ImportJob,
+    rows: &[ImportRow<T>],
+    num_threads: u32,
+    time_zone: &str,
+    cancelled: &AtomicBool,
+) where
+    T: Importable + Tracked + Entity + Send + Sync,
+{
+    for chunk in rows.chunks(num_threads.max(1) as usize) {
+        if cancelled.load(Ordering::Relaxed) {
+            job.status = String::from("cancelled");
+            break;
+        }
+
+        let futures = chunk
+            .iter()
+            .map(|row| write_row(db, &job.operator, time_zone, row));
+        for (row, res) in chunk.iter().zip(join_all(futures).await) {
+            job.num_processed += 1;
+            if let Err(e) = res {
+                job.num_failed += 1;
+                job.errors.push(ImportError {
+                    row: row.row,
+                    field: String::new(),
+                    message: e.to_string(),
+                });
+            }
+        }
+        if let Err(e) = update_item(db, &*job).await {
+            error!("{:?}", e);
+        }
+    }
+    if job.status == "running" {
+        job.status = String::from("completed");
+    }
+}
+
+async fn finish_import_job(db: &Database, mut job: ImportJob, time_zone: &str) {
+    job.finished_at = format_time(time_zone);
+    if let Err(e) = update_item(db, &job).await {
+        error!("{:?}", e);
+    }
+    info!(
+        "Import job {} {}: {}/{} rows, {} failed",
+        job.id, job.status, job.num_processed, job.num_rows, job.num_failed
+    );
+}
+
+async fn get_job(db: &Database, id: i64) -> Result<ImportJob, BibErrorResponse> {
+    let job = ImportJob {
+        id,
+        ..Default::default()
+    };
+    search_item(db, &job)
+        .await
+        .map_err(|_| BibErrorResponse::DataNotFound(id.to_string()))
+}
+
+pub async fn get_import_job(
+    session: Session,
+    form: web::Query<ImportJobForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let reply = Reply {
+        import_job: get_job(&db, form.id).await?,
+        ..Default::default()
+    };
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+fn errors_csv(job: &ImportJob) -> Result<String, Box<dyn error::Error>> {
+    let mut wtr = WriterBuilder::new().from_writer(vec![]);
+    wtr.write_record(["行", "項目", "内容"])?;
+    for e in &job.errors {
+        wtr.write_record([e.row.to_string(), e.field.clone(), e.message.clone()])?;
+    }
+    Ok(String::from_utf8(wtr.into_inner()?)?)
+}
+
+/// Returns the rows that could not be written as a CSV file.
+pub async fn export_import_errors(
+    session: Session,
+    form: web::Query<ImportJobForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+    let job = get_job(&db, form.id).await?;
+
+    let csv = errors_csv(&job).map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"import_errors_{}.csv\"", job.id),
+        )
+        .body(TextEncoding::Utf8Bom.encode(&csv)))
+}
+
+pub async fn cancel_import_job(
+    session: Session,
+    form: web::Json<ImportJobForm>,
+    jobs: web::Data<ImportJobs>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    let dbname = check_operator_session(&session)?;
+    if !jobs.cancel(&dbname, form.id) {
+        return Err(BibErrorResponse::InvalidArgument(format!(
+            "Job {} is not running",
+            form.id
+        )));
+    }
+
+    let reply = Reply::default();
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_message() {
+        let e = BibErrorResponse::InvalidArgument("mode".to_string());
+        assert_eq!(
+            error_message(&e),
+            "指定されてパラメータが正しくありません: mode"
+        );
+        assert_eq!(
+            error_message(&BibErrorResponse::NotAuthorized),
+            "このアクセスは認証されていません"
+        );
+    }
+}
@@ -0,0 +1,455 @@
+use super::columns::{field_names, Column, Importable};
+use super::loans::{import_loans, LoanRows};
+use super::setting::{check_rows, import_error, save_file, start_import};
+use super::setting::{ImportListForm, ImportTable, UploadedFile, Upsert};
+use crate::error::{BibErrorResponse, ImportError};
+use crate::item::{Book, CustomField, Entity, SystemSetting, TransactionItem, User};
+use crate::views::cache::Cache;
+use crate::views::custom_field::get_custom_field_setting;
+use crate::views::db_helper::get_db;
+use crate::views::import_job::ImportJobs;
+use crate::views::session::check_operator_session;
+use crate::views::transaction::Transaction;
+use crate::views::utils::get_setting;
+use actix_multipart::Multipart;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use log::debug;
+use mongodb::Database;
+use serde::de::{self, DeserializeOwned, Deserializer, SeqAccess, Visitor};
+use serde::Deserialize;
+use serde_json::Value;
+use shared_mongodb::ClientHolder;
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+/// Hands each element of a JSON array to `f` as it is read, so that the
+/// array is never held as a whole.
+struct ArrayVisitor<T, F> {
+    f: F,
+    marker: PhantomData<T>,
+}
+
+impl<'de, T, F> Visitor<'de> for ArrayVisitor<T, F>
+where
+    T: DeserializeOwned,
+    F: FnMut(usize, Result<T, serde_json::Error>) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of items")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        let mut row = 0;
+        // An element is read as any JSON value first, so that an item in a
+        // wrong shape does not stop the rest from being read
+        while let Some(value) = seq.next_element::<serde_json::Value>()? {
+            row += 1;
+            (self.f)(row, serde_json::from_value(value)).map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a JSON array, or one JSON object per line, and hands each item to
+/// `f` with its row, which is its line or its position in the array. `f`
+/// stops the reading by returning an error.
+fn for_each_json_item<T, F>(file_path: &str, mut f: F) -> Result<(), Box<dyn error::Error>>
+where
+    T: DeserializeOwned,
+    F: FnMut(usize, Result<T, serde_json::Error>) -> Result<(), String>,
+{
+    let mut reader = BufReader::new(File::open(file_path)?);
+    let is_array = loop {
+        let buf = reader.fill_buf()?;
+        match buf.iter().position(|c| !c.is_ascii_whitespace()) {
+            Some(i) => {
+                let is_array = buf[i] == b'[';
+                break is_array;
+            }
+            None if buf.is_empty() => break false,
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    };
+
+    if is_array {
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        let visitor = ArrayVisitor {
+            f,
+            marker: PhantomData,
+        };
+        (&mut deserializer).deserialize_seq(visitor)?;
+        deserializer.end()?;
+        return Ok(());
+    }
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        f(i + 1, serde_json::from_str(&line))?;
+    }
+    Ok(())
+}
+
+/// Reads the items of a JSON file one by one, and turns each into a record
+/// by `to_record`. The items that cannot be read are reported and skipped.
+/// Fails with ExceedLimitInParallel as soon as there are more items than
+/// can be registered at once.
+fn read_json_records<T, R, F>(
+    file_path: &str,
+    max_items: u32,
+    errors: &mut Vec<ImportError>,
+    mut to_record: F,
+) -> Result<Vec<R>, BibErrorResponse>
+where
+    T: DeserializeOwned,
+    F: FnMut(usize, T, &mut Vec<ImportError>) -> Option<R>,
+{
+    let mut records = vec![];
+    let mut num_items: u32 = 0;
+    let res = for_each_json_item(file_path, |row, item: Result<T, _>| {
+        num_items += 1;
+        if num_items > max_items {
+            return Err(format!("More than {} items", max_items));
+        }
+        match item {
+            Ok(item) => records.extend(to_record(row, item, errors)),
+            Err(e) => errors.push(import_error(row, "", e)),
+        }
+        Ok(())
+    });
+    if num_items > max_items {
+        return Err(BibErrorResponse::ExceedLimitInParallel(max_items));
+    }
+    res.map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    Ok(records)
+}
+
+fn to_record(row: usize, values: Vec<String>) -> csv::StringRecord {
+    let mut record = csv::StringRecord::from(values);
+    let mut position = csv::Position::new();
+    position.set_line(row as u64);
+    record.set_position(Some(position));
+    record
+}
+
+/// A user or a book of a JSON import. Only the fields present are imported,
+/// so that the fields kept by the rental process are not needed and the
+/// custom fields left out are kept as they are.
+#[derive(Deserialize)]
+struct JsonItem {
+    #[serde(default)]
+    id: u32,
+    #[serde(default)]
+    custom_fields: BTreeMap<String, String>,
+    #[serde(flatten)]
+    fields: serde_json::Map<String, Value>,
+}
+
+/// Writes an item in the columns of the file import so that it is validated
+/// in the same way. An ID of 0 is assigned a new one. Returns the record and
+/// the positions of the columns absent from the item.
+fn item_record<T: Importable>(
+    row: usize,
+    item: JsonItem,
+    columns: &[Column<T>],
+    custom_field_names: &[String],
+    errors: &mut Vec<ImportError>,
+) -> Option<(csv::StringRecord, Vec<usize>)> {
+    let num_errors = errors.len();
+    for name in item.custom_fields.keys() {
+        if !custom_field_names.contains(name) {
+            errors.push(import_error(row, name, "Unknown column"));
+        }
+    }
+
+    let mut values = vec![match item.id {
+        0 => String::new(),
+        id => id.to_string(),
+    }];
+    let mut absent = vec![];
+    for column in columns {
+        match item.fields.get(column.field) {
+            Some(Value::String(value)) => values.push(value.clone()),
+            None | Some(Value::Null) => {
+                absent.push(values.len());
+                values.push(String::new());
+            }
+            Some(_) => {
+                errors.push(import_error(row, column.name, "Not a string"));
+                values.push(String::new());
+            }
+        }
+    }
+    for name in custom_field_names {
+        match item.custom_fields.get(name) {
+            Some(value) => values.push(value.clone()),
+            None => {
+                absent.push(values.len());
+                values.push(String::new());
+            }
+        }
+    }
+    (errors.len() == num_errors).then(|| (to_record(row, values), absent))
+}
+
+/// Reads the items of a JSON file as the records of the file import, with
+/// the header of all the columns.
+fn read_json_list<T: Importable>(
+    file_path: &str,
+    custom_fields: &[CustomField],
+    setting: &SystemSetting,
+) -> Result<ImportTable, BibErrorResponse> {
+    let names = field_names::<T>(custom_fields);
+    let custom_field_names = &names[names.len() - custom_fields.len()..];
+    let columns = T::columns();
+
+    let mut errors = vec![];
+    let items = read_json_records(
+        file_path,
+        setting.max_parallel_registrations,
+        &mut errors,
+        |row, item: JsonItem, errors| item_record(row, item, &columns, custom_field_names, errors),
+    )?;
+    let (records, absent) = items.into_iter().unzip();
+    Ok(ImportTable {
+        headers: csv::StringRecord::from(names),
+        records,
+        ignored: vec![],
+        absent,
+        errors,
+    })
+}
+
+/// Reads the items of a JSON file as the rows of the file import.
+async fn check_json<T>(
+    db: Database,
+    form: ImportListForm,
+    setting: SystemSetting,
+    file: UploadedFile,
+) -> Result<Upsert<T>, BibErrorResponse>
+where
+    T: Importable + Entity,
+{
+    let custom_field_setting = get_custom_field_setting(&db).await;
+    let custom_fields = T::custom_field_defs(&custom_field_setting);
+    let table = read_json_list::<T>(&file.path, custom_fields, &setting)?;
+    drop(file);
+    check_rows(&db, &form, &setting, custom_fields, table).await
+}
+
+/// Imports the users in their JSON representation, such as the file of the
+/// JSON export. The fields kept by the rental process are not imported.
+pub async fn import_user_json(
+    session: Session,
+    form: web::Query<ImportListForm>,
+    payload: Multipart,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+    jobs: web::Data<ImportJobs>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    start_import(
+        session,
+        form.into_inner(),
+        payload,
+        data,
+        setting_map,
+        jobs,
+        check_json::<User>,
+    )
+    .await
+}
+
+/// Imports the books in their JSON representation, such as the file of the
+/// JSON export. The fields kept by the rental process are not imported.
+pub async fn import_book_json(
+    session: Session,
+    form: web::Query<ImportListForm>,
+    payload: Multipart,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+    jobs: web::Data<ImportJobs>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    start_import(
+        session,
+        form.into_inner(),
+        payload,
+        data,
+        setting_map,
+        jobs,
+        check_json::<Book>,
+    )
+    .await
+}
+
+/// Imports the loans in the JSON representation of the transactions. The
+/// transaction IDs are assigned again as in the file import.
+pub async fn import_history_json(
+    session: Session,
+    form: web::Query<ImportListForm>,
+    payload: Multipart,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+    transaction_map: web::Data<Mutex<HashMap<String, Transaction>>>,
+    cache_map: web::Data<Mutex<HashMap<String, Cache>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    let dbname = check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+    let setting = get_setting(&dbname, &setting_map)?;
+
+    let file = save_file(payload)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    // The limit of the transactions is checked again with the valid rows
+    let mut errors = vec![];
+    let records = read_json_records(
+        &file.path,
+        setting.max_num_transactions,
+        &mut errors,
+        |row, item: TransactionItem, _| {
+            let values = vec![
+                item.id.to_string(),
+                item.user_id.to_string(),
+                item.user_name,
+                item.book_id.to_string(),
+                item.book_title,
+                item.borrowed_date,
+                item.returned_date,
+            ];
+            Some(to_record(row, values))
+        },
+    )
+    .map_err(|e| match e {
+        BibErrorResponse::ExceedLimitInParallel(n) => BibErrorResponse::ExceedLimit(n),
+        e => e,
+    })?;
+    import_loans(
+        &db,
+        &dbname,
+        &setting,
+        LoanRows { records, errors },
+        form.dry_run == "true",
+        transaction_map,
+        cache_map,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book_record(json: &str) -> (Option<(csv::StringRecord, Vec<usize>)>, Vec<ImportError>) {
+        let names = vec![String::from("寄贈者")];
+        let item: JsonItem = serde_json::from_str(json).unwrap();
+        let mut errors = vec![];
+        let record = item_record(3, item, &Book::columns(), &names, &mut errors);
+        (record, errors)
+    }
+
+    #[test]
+    fn item_record_leaves_out_the_absent_fields() {
+        // No runtime fields such as borrowed_count are needed
+        let (record, errors) = book_record(r#"{"id":5,"title":"坊っちゃん"}"#);
+        assert!(errors.is_empty());
+        let (record, absent) = record.unwrap();
+        assert_eq!(&record[0], "5");
+        assert_eq!(&record[1], "坊っちゃん");
+        assert_eq!(record.position().unwrap().line(), 3);
+        // All the columns but the ID and the title, the custom field too
+        let num_columns = Book::columns().len() + 2;
+        assert_eq!(record.len(), num_columns);
+        assert_eq!(absent, (2..num_columns).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn item_record_keeps_the_custom_fields_present() {
+        let (record, errors) = book_record(r#"{"custom_fields":{"寄贈者":""}}"#);
+        assert!(errors.is_empty());
+        let (record, absent) = record.unwrap();
+        assert_eq!(&record[0], "");
+        assert!(!absent.contains(&(record.len() - 1)));
+    }
+
+    #[test]
+    fn item_record_reports_unknown_and_wrong_fields() {
+        let (record, errors) = book_record(r#"{"page":120,"custom_fields":{"色":"赤"}}"#);
+        assert!(record.is_none());
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["色", "ページ"]);
+    }
+
+    fn read_ids(
+        name: &str,
+        json: &str,
+        max_items: u32,
+    ) -> (Result<Vec<u32>, BibErrorResponse>, Vec<ImportError>) {
+        let path = std::env::temp_dir().join(format!("bib-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, json).unwrap();
+        let mut errors = vec![];
+        let ids = read_json_records(
+            path.to_str().unwrap(),
+            max_items,
+            &mut errors,
+            |_, item: JsonItem, _| Some(item.id),
+        );
+        std::fs::remove_file(&path).unwrap();
+        (ids, errors)
+    }
+
+    #[test]
+    fn read_json_records_reads_lines() {
+        let json = "{\"id\":1}\n\n  \n{\"id\":\"x\"}\n{\"id\":3}\n";
+        let (ids, errors) = read_ids("lines.jsonl", json, 10);
+        assert_eq!(ids.unwrap(), vec![1, 3]);
+        // The blank lines are skipped but counted
+        let rows: Vec<usize> = errors.iter().map(|e| e.row).collect();
+        assert_eq!(rows, vec![4]);
+    }
+
+    #[test]
+    fn read_json_records_reads_an_array() {
+        let json = "\n [{\"id\":1},\n 5,\n {\"id\":3}]\n";
+        let (ids, errors) = read_ids("array.json", json, 10);
+        assert_eq!(ids.unwrap(), vec![1, 3]);
+        let rows: Vec<usize> = errors.iter().map(|e| e.row).collect();
+        assert_eq!(rows, vec![2]);
+
+        let (ids, _) = read_ids("empty.json", "", 10);
+        assert!(ids.unwrap().is_empty());
+        let (ids, _) = read_ids("broken.json", "[{\"id\":1}", 10);
+        assert!(matches!(ids, Err(BibErrorResponse::InvalidArgument(_))));
+        let (ids, _) = read_ids("trailing.json", "[] []", 10);
+        assert!(matches!(ids, Err(BibErrorResponse::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn read_json_records_stops_at_the_limit() {
+        let (ids, _) = read_ids("limit.json", "[{},{},{}]", 2);
+        assert!(matches!(
+            ids,
+            Err(BibErrorResponse::ExceedLimitInParallel(2))
+        ));
+        let (ids, _) = read_ids("limit.jsonl", "{}\n{}\n{}\n", 2);
+        assert!(matches!(
+            ids,
+            Err(BibErrorResponse::ExceedLimitInParallel(2))
+        ));
+        let (ids, _) = read_ids("fit.jsonl", "{}\n{}\n", 2);
+        assert_eq!(ids.unwrap().len(), 2);
+    }
+}
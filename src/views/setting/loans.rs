@@ -0,0 +1,527 @@
+use super::setting::{import_error, row_number, save_file};
+use crate::error::{BibErrorResponse, ImportError};
+use crate::item::Entity;
+use crate::item::{atoi, search_item, search_items_or_empty, update_item, update_item_fields};
+use crate::item::{Book, BorrowedBook, RentalSetting, SystemSetting, TransactionItem, User};
+use crate::views::cache::Cache;
+use crate::views::content_loader::read_table;
+use crate::views::db_helper::get_db;
+use crate::views::reply::Reply;
+use crate::views::session::check_operator_session;
+use crate::views::transaction::Transaction;
+use crate::views::utils::get_setting;
+use actix_multipart::Multipart;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use futures::future::join_all;
+use futures::Future;
+use log::{debug, error};
+use mongodb::bson::doc;
+use mongodb::Database;
+use serde::Deserialize;
+use shared_mongodb::ClientHolder;
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::sync::Mutex;
+
+// The columns of the history export, with the return deadline of the open
+// loans optionally following them
+const NUM_FIELDS: usize = 7;
+const DATE_FORMAT: &str = "%Y/%m/%d %H:%M";
+
+#[derive(Deserialize, Debug)]
+pub struct ImportLoanForm {
+    #[serde(default)]
+    pub dry_run: String,
+}
+
+/// The rows of the loans to import, with the problems of the rows that
+/// could not be read as such.
+pub struct LoanRows {
+    pub records: Vec<csv::StringRecord>,
+    pub errors: Vec<ImportError>,
+}
+
+/// A loan read from a row. The loan is open if it has not been returned.
+struct Loan {
+    user_id: u32,
+    book_id: u32,
+    borrowed_date: NaiveDateTime,
+    returned_date: Option<NaiveDateTime>,
+    return_deadline: NaiveDateTime,
+}
+
+/// Reads a date with or without the time, such as "2020/04/01 10:30" or
+/// "2020-04-01".
+fn parse_date(value: &str) -> Option<NaiveDateTime> {
+    let value = value.trim();
+    for format in [DATE_FORMAT, "%Y-%m-%d %H:%M", "%Y/%m/%d %H:%M:%S"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(datetime);
+        }
+    }
+    ["%Y/%m/%d", "%Y-%m-%d"]
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+}
+
+fn read_date(
+    value: &str,
+    row: usize,
+    field: &str,
+    errors: &mut Vec<ImportError>,
+) -> Option<NaiveDateTime> {
+    let datetime = parse_date(value);
+    if datetime.is_none() {
+        errors.push(import_error(row, field, format!("Invalid date {}", value)));
+    }
+    datetime
+}
+
+fn read_registered_id<T>(
+    value: &str,
+    row: usize,
+    field: &str,
+    registered: &HashMap<u32, T>,
+    errors: &mut Vec<ImportError>,
+) -> Option<u32> {
+    match atoi(value.trim()) {
+        Ok(id) if registered.contains_key(&id) => Some(id),
+        Ok(id) => {
+            errors.push(import_error(
+                row,
+                field,
+                format!("ID{} is not registered", id),
+            ));
+            None
+        }
+        Err(e) => {
+            errors.push(import_error(row, field, e));
+            None
+        }
+    }
+}
+
+/// Checks the rows against the registered users and books. A book can be
+/// lent by one open loan only, including the loans already registered.
+fn read_loans(
+    records: &[csv::StringRecord],
+    users: &HashMap<u32, User>,
+    books: &HashMap<u32, Book>,
+    num_days: u32,
+    errors: &mut Vec<ImportError>,
+) -> Vec<Loan> {
+    let mut lent_books: HashMap<u32, usize> = users
+        .values()
+        .flat_map(|user| user.borrowed_books.iter())
+        .map(|book| (book.book_id, 0))
+        .collect();
+
+    let mut loans = vec![];
+    for (i, record) in records.iter().enumerate() {
+        debug!("{:?}", record);
+        let row = row_number(record, i);
+        if record.len() != NUM_FIELDS && record.len() != NUM_FIELDS + 1 {
+            errors.push(import_error(
+                row,
+                "",
+                format!("The number of fields is {}", record.len()),
+            ));
+            continue;
+        }
+
+        let user_id = read_registered_id(&record[1], row, "利用者ID", users, errors);
+        let book_id = read_registered_id(&record[3], row, "図書ID", books, errors);
+        let borrowed_date = read_date(&record[5], row, "貸出日", errors);
+        let returned_date = match record[6].trim() {
+            "" => Some(None),
+            value => read_date(value, row, "返却日", errors).map(Some),
+        };
+        let return_deadline = match record.get(NUM_FIELDS).map(str::trim) {
+            None | Some("") => borrowed_date.map(|date| date + Duration::days(num_days as i64)),
+            Some(value) => read_date(value, row, "返却期限", errors),
+        };
+        let (user_id, book_id, borrowed_date, returned_date, return_deadline) = match (
+            user_id,
+            book_id,
+            borrowed_date,
+            returned_date,
+            return_deadline,
+        ) {
+            (Some(u), Some(b), Some(d), Some(r), Some(l)) => (u, b, d, r, l),
+            _ => continue,
+        };
+
+        if returned_date.is_some_and(|returned_date| returned_date < borrowed_date) {
+            errors.push(import_error(
+                row,
+                "返却日",
+                "The book is returned before it is borrowed",
+            ));
+            continue;
+        }
+        if returned_date.is_none() {
+            match lent_books.insert(book_id, row) {
+                Some(0) => {
+                    errors.push(import_error(
+                        row,
+                        "図書ID",
+                        format!("ID{} is already lent", book_id),
+                    ));
+                    continue;
+                }
+                Some(other_row) => {
+                    errors.push(import_error(
+                        row,
+                        "図書ID",
+                        format!("ID{} is also lent in row {}", book_id, other_row),
+                    ));
+                    continue;
+                }
+                None => {}
+            }
+        }
+
+        loans.push(Loan {
+            user_id,
+            book_id,
+            borrowed_date,
+            returned_date,
+            return_deadline,
+        });
+    }
+    loans
+}
+
+/// The borrowed books added to a user and the number of the loans counted.
+#[derive(Default)]
+struct UserChange {
+    borrowed_books: Vec<BorrowedBook>,
+    num_loans: u32,
+}
+
+/// The transactions of the loans, and the changes of the users and the books
+/// by the user IDs and the book IDs.
+struct LoanChanges {
+    items: Vec<TransactionItem>,
+    users: BTreeMap<u32, UserChange>,
+    books: BTreeMap<u32, u32>,
+}
+
+/// Makes the loans into transactions, adds the open ones to the users and
+/// counts all of them in the borrowed counts.
+fn apply_loans(
+    loans: &[Loan],
+    transaction_ids: &[u32],
+    users: &HashMap<u32, User>,
+    books: &HashMap<u32, Book>,
+) -> LoanChanges {
+    let mut changes = LoanChanges {
+        items: vec![],
+        users: BTreeMap::new(),
+        books: BTreeMap::new(),
+    };
+    for (loan, transaction_id) in loans.iter().zip(transaction_ids) {
+        let user = &users[&loan.user_id];
+        let book = &books[&loan.book_id];
+        let borrowed_date = format!("{}", loan.borrowed_date.format(DATE_FORMAT));
+
+        changes.items.push(TransactionItem {
+            id: *transaction_id,
+            user_id: user.id,
+            user_name: user.name.clone(),
+            book_id: book.id,
+            book_title: book.title.clone(),
+            borrowed_date: borrowed_date.clone(),
+            returned_date: loan
+                .returned_date
+                .map(|date| format!("{}", date.format(DATE_FORMAT)))
+                .unwrap_or_default(),
+        });
+        let user_change = changes.users.entry(user.id).or_default();
+        if loan.returned_date.is_none() {
+            user_change.borrowed_books.push(BorrowedBook {
+                book_id: book.id,
+                book_title: book.title.clone(),
+                borrowed_date,
+                return_deadline: format!("{}", loan.return_deadline.format(DATE_FORMAT)),
+                transaction_id: *transaction_id,
+                location: book.location.clone(),
+            });
+        }
+        user_change.num_loans += 1;
+        *changes.books.entry(book.id).or_default() += 1;
+    }
+    changes
+}
+
+/// The IDs of what has been written, so that it can be undone.
+#[derive(Default)]
+struct WrittenLoans {
+    transaction_ids: Vec<u32>,
+    user_ids: Vec<u32>,
+    book_ids: Vec<u32>,
+}
+
+/// Runs `write` for the IDs, `num_threads` at a time, and adds the IDs that
+/// are written to `written`. Stops at the chunk where a write fails.
+async fn write_in_chunks<F, Fut>(
+    ids: &[u32],
+    num_threads: u32,
+    write: F,
+    written: &mut Vec<u32>,
+) -> Result<(), Box<dyn error::Error>>
+where
+    F: Fn(u32) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn error::Error>>>,
+{
+    for chunk in ids.chunks(num_threads.max(1) as usize) {
+        let results = join_all(chunk.iter().map(|id| write(*id))).await;
+        let mut failure = None;
+        for (id, res) in chunk.iter().zip(results) {
+            match res {
+                Ok(()) => written.push(*id),
+                Err(e) => failure = Some(e),
+            }
+        }
+        if let Some(e) = failure {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Adds the transactions, then the borrowed books and the counts of the
+/// users and the books. The users and the books are changed by the update
+/// operators so that the loans and returns made meanwhile are kept.
+async fn write_loans(
+    db: &Database,
+    changes: &LoanChanges,
+    num_threads: u32,
+    written: &mut WrittenLoans,
+) -> Result<(), Box<dyn error::Error>> {
+    let items: HashMap<u32, &TransactionItem> =
+        changes.items.iter().map(|item| (item.id, item)).collect();
+    let transaction_ids: Vec<u32> = changes.items.iter().map(|item| item.id).collect();
+    write_in_chunks(
+        &transaction_ids,
+        num_threads,
+        |id| update_item(db, items[&id]),
+        &mut written.transaction_ids,
+    )
+    .await?;
+
+    let mut updates = HashMap::new();
+    for (user_id, change) in &changes.users {
+        let borrowed_books = bson::to_bson(&change.borrowed_books)?;
+        let update = doc! {
+            "$push": { "borrowed_books": { "$each": borrowed_books } },
+            "$inc": { "borrowed_count": change.num_loans },
+        };
+        updates.insert(*user_id, update);
+    }
+    let user = User::default();
+    let user_ids: Vec<u32> = changes.users.keys().copied().collect();
+    write_in_chunks(
+        &user_ids,
+        num_threads,
+        |id| update_item_fields(db, &user, id, updates[&id].clone()),
+        &mut written.user_ids,
+    )
+    .await?;
+
+    let book = Book::default();
+    let book_ids: Vec<u32> = changes.books.keys().copied().collect();
+    write_in_chunks(
+        &book_ids,
+        num_threads,
+        |id| {
+            let update = doc! { "$inc": { "borrowed_count": changes.books[&id] } };
+            update_item_fields(db, &book, id, update)
+        },
+        &mut written.book_ids,
+    )
+    .await
+}
+
+/// Takes back what `write_loans` has written before it failed. The failures
+/// are only logged, as the import has failed already.
+async fn undo_loans(db: &Database, changes: &LoanChanges, written: &WrittenLoans) {
+    for book_id in &written.book_ids {
+        let update = doc! { "$inc": { "borrowed_count": -(changes.books[book_id] as i64) } };
+        if let Err(e) = update_item_fields(db, &Book::default(), *book_id, update).await {
+            error!("Failed to undo the loans of the book {}: {}", book_id, e);
+        }
+    }
+    for user_id in &written.user_ids {
+        let change = &changes.users[user_id];
+        let transaction_ids: Vec<u32> = change
+            .borrowed_books
+            .iter()
+            .map(|book| book.transaction_id)
+            .collect();
+        let update = doc! {
+            "$pull": { "borrowed_books": { "transaction_id": { "$in": transaction_ids } } },
+            "$inc": { "borrowed_count": -(change.num_loans as i64) },
+        };
+        if let Err(e) = update_item_fields(db, &User::default(), *user_id, update).await {
+            error!("Failed to undo the loans of the user {}: {}", user_id, e);
+        }
+    }
+    if !written.transaction_ids.is_empty() {
+        let collection = TransactionItem::default().get_collection(db);
+        let query = doc! { "id": { "$in": &written.transaction_ids } };
+        if let Err(e) = collection.delete_many(query, None).await {
+            error!("Failed to undo the transactions: {}", e);
+        }
+    }
+}
+
+/// Imports the loans of a legacy system in the layout of the history export.
+pub async fn import_loan_list(
+    session: Session,
+    form: web::Query<ImportLoanForm>,
+    payload: Multipart,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+    transaction_map: web::Data<Mutex<HashMap<String, Transaction>>>,
+    cache_map: web::Data<Mutex<HashMap<String, Cache>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    let dbname = check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+
+    let setting = get_setting(&dbname, &setting_map)?;
+
+    let file = save_file(payload)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    let (_, records) =
+        read_table(&file.path).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let rows = LoanRows {
+        records,
+        errors: vec![],
+    };
+
+    let dry_run = form.dry_run == "true";
+    import_loans(
+        &db,
+        &dbname,
+        &setting,
+        rows,
+        dry_run,
+        transaction_map,
+        cache_map,
+    )
+    .await
+}
+
+/// Validates the loans in the columns of the history export and registers
+/// them unless `dry_run` is set. The loans take the transaction IDs that
+/// follow the current counter, so they are the last to be overwritten when
+/// the IDs go round, after the transactions made before the import.
+pub async fn import_loans(
+    db: &Database,
+    dbname: &str,
+    setting: &SystemSetting,
+    rows: LoanRows,
+    dry_run: bool,
+    transaction_map: web::Data<Mutex<HashMap<String, Transaction>>>,
+    cache_map: web::Data<Mutex<HashMap<String, Cache>>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    let LoanRows {
+        records,
+        mut errors,
+    } = rows;
+    // The loans must fit in the free transaction IDs, as the ring of the
+    // IDs would overwrite the oldest transactions otherwise
+    let nrecords: u32 = records.len().try_into().unwrap();
+    let num_transactions = TransactionItem::default()
+        .get_collection(db)
+        .count_documents(doc! { "id": { "$gt": 0 } }, None)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    if nrecords as u64 + num_transactions > setting.max_num_transactions as u64 {
+        return Err(BibErrorResponse::ExceedLimit(nrecords));
+    }
+
+    let rental_setting = RentalSetting {
+        id: 1,
+        ..Default::default()
+    };
+    let rental_setting = search_item(db, &rental_setting)
+        .await
+        .map_err(|e| BibErrorResponse::DataNotFound(e.to_string()))?;
+
+    let users: HashMap<u32, User> = search_items_or_empty(db, &User::default())
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?
+        .into_iter()
+        .map(|user| (user.id, user))
+        .collect();
+    let books: HashMap<u32, Book> = search_items_or_empty(db, &Book::default())
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?
+        .into_iter()
+        .map(|book| (book.id, book))
+        .collect();
+
+    let mut loans = read_loans(
+        &records,
+        &users,
+        &books,
+        rental_setting.num_days,
+        &mut errors,
+    );
+    // The transaction IDs follow the order of the dates as the ones lent here
+    loans.sort_by_key(|loan| loan.borrowed_date);
+
+    let mut reply = Reply {
+        num_inserted: loans.len(),
+        ..Default::default()
+    };
+    errors.sort_by_key(|error| error.row);
+    if dry_run {
+        reply.import_errors = errors;
+        return Ok(HttpResponse::Ok().json(reply));
+    }
+    if !errors.is_empty() {
+        return Err(BibErrorResponse::ImportFailed(errors));
+    }
+
+    let transaction_ids: Vec<u32> = {
+        let transaction_map = transaction_map.lock().unwrap();
+        let transaction = match transaction_map.get(dbname) {
+            Some(transaction) => transaction,
+            None => return Err(BibErrorResponse::NotAuthorized),
+        };
+        transaction.reserve_counters(loans.len())
+    };
+    let changes = apply_loans(&loans, &transaction_ids, &users, &books);
+
+    // Update the DB, or take back the loans written if it fails midway
+    let mut written = WrittenLoans::default();
+    if let Err(e) = write_loans(db, &changes, setting.num_threads, &mut written).await {
+        error!("Failed to import the loans: {}", e);
+        undo_loans(db, &changes, &written).await;
+        if let Some(transaction) = transaction_map.lock().unwrap().get(dbname) {
+            transaction.release_counters(&transaction_ids);
+        }
+        return Err(BibErrorResponse::SystemError(e.to_string()));
+    }
+
+    // Update the cache with the open loans
+    let locked_cache_map = cache_map.lock().unwrap();
+    if let Some(cache) = locked_cache_map.get(dbname) {
+        for (user_id, change) in &changes.users {
+            for book in &change.borrowed_books {
+                cache.borrow(book.book_id, *user_id, book.return_deadline.clone());
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(reply))
+}
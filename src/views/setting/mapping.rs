@@ -0,0 +1,263 @@
+use super::columns::field_names;
+use super::setting::save_file;
+use crate::error::BibErrorResponse;
+use crate::item::{delete_item, search_item, search_items, update_item};
+use crate::item::{Book, ImportMapping, User};
+use crate::views::change_log::Tracked;
+use crate::views::content_loader::read_table;
+use crate::views::custom_field::get_custom_field_setting;
+use crate::views::db_helper::get_db;
+use crate::views::reply::Reply;
+use crate::views::session::check_operator_session;
+use actix_multipart::Multipart;
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Result};
+use log::debug;
+use mongodb::Database;
+use serde::Deserialize;
+use shared_mongodb::ClientHolder;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+const NUM_PREVIEW_ROWS: usize = 5;
+
+#[derive(Deserialize, Debug)]
+pub struct GetMappingForm {
+    pub target: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SaveMappingForm {
+    pub target: String,
+    pub name: String,
+    pub has_header: bool,
+    pub columns: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteMappingForm {
+    pub target: String,
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PreviewForm {
+    pub target: String,
+    #[serde(default)]
+    pub has_header: String,
+}
+
+fn check_target(target: &str) -> Result<(), BibErrorResponse> {
+    if target == User::TARGET || target == Book::TARGET {
+        Ok(())
+    } else {
+        Err(BibErrorResponse::InvalidArgument(target.to_string()))
+    }
+}
+
+pub async fn get_mapping(
+    db: &Database,
+    target: &str,
+    name: &str,
+) -> Result<ImportMapping, BibErrorResponse> {
+    let mapping = ImportMapping {
+        target: target.to_string(),
+        name: name.to_string(),
+        ..Default::default()
+    };
+    search_item(db, &mapping)
+        .await
+        .map_err(|_| BibErrorResponse::DataNotFound(name.to_string()))
+}
+
+/// Returns the fields the columns of the target can be mapped to.
+async fn target_field_names(db: &Database, target: &str) -> Vec<String> {
+    let custom_field_setting = get_custom_field_setting(db).await;
+    if target == User::TARGET {
+        field_names::<User>(&custom_field_setting.user_fields)
+    } else {
+        field_names::<Book>(&custom_field_setting.book_fields)
+    }
+}
+
+/// Names the columns by their position for the files without a header.
+fn column_labels(num_columns: usize) -> csv::StringRecord {
+    (1..=num_columns).map(|i| format!("列{}", i)).collect()
+}
+
+/// Reads the first line as data if the file has no header, and gives the
+/// columns their positional labels instead.
+fn split_header(
+    has_header: bool,
+    headers: csv::StringRecord,
+    mut records: Vec<csv::StringRecord>,
+) -> (csv::StringRecord, Vec<csv::StringRecord>) {
+    if has_header {
+        return (headers, records);
+    }
+    let labels = column_labels(headers.len());
+    let mut first_row = headers;
+    let mut position = csv::Position::new();
+    position.set_line(1);
+    first_row.set_position(Some(position));
+    records.insert(0, first_row);
+    (labels, records)
+}
+
+/// Renames the columns of an imported file to the column names of the export
+/// layout. Also returns the positions of the columns not in the mapping,
+/// which are ignored.
+pub fn apply_mapping(
+    mapping: &ImportMapping,
+    headers: csv::StringRecord,
+    records: Vec<csv::StringRecord>,
+) -> (csv::StringRecord, Vec<csv::StringRecord>, Vec<usize>) {
+    let (headers, records) = split_header(mapping.has_header, headers, records);
+    let mut ignored = vec![];
+    let headers = headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| match mapping.columns.get(header.trim()) {
+            Some(name) => name.as_str(),
+            None => {
+                ignored.push(i);
+                header
+            }
+        })
+        .collect();
+    (headers, records, ignored)
+}
+
+pub async fn get_mappings(
+    session: Session,
+    form: web::Query<GetMappingForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+    check_target(&form.target)?;
+
+    let mapping = ImportMapping {
+        target: form.target.clone(),
+        ..Default::default()
+    };
+    let reply = Reply {
+        import_mappings: search_items(&db, &mapping).await.unwrap_or_default(),
+        ..Default::default()
+    };
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+pub async fn save_mapping(
+    session: Session,
+    form: web::Json<SaveMappingForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+    check_target(&form.target)?;
+
+    let name = form.name.trim();
+    if name.is_empty() {
+        return Err(BibErrorResponse::InvalidArgument(String::from("name")));
+    }
+
+    let field_names = target_field_names(&db, &form.target).await;
+    if let Some(field) = form
+        .columns
+        .values()
+        .find(|field| !field.is_empty() && !field_names.contains(field))
+    {
+        return Err(BibErrorResponse::InvalidArgument(format!(
+            "Unknown field {}",
+            field
+        )));
+    }
+
+    // The columns mapped to the same field cannot be told apart on import
+    let mut fields: Vec<&String> = form.columns.values().filter(|f| !f.is_empty()).collect();
+    let num_fields = fields.len();
+    fields.sort();
+    fields.dedup();
+    if fields.len() != num_fields {
+        return Err(BibErrorResponse::InvalidArgument(String::from(
+            "The same field is mapped twice",
+        )));
+    }
+
+    let mapping = ImportMapping {
+        target: form.target.clone(),
+        name: name.to_string(),
+        has_header: form.has_header,
+        columns: form
+            .columns
+            .iter()
+            .filter(|(_, field)| !field.is_empty())
+            .map(|(column, field)| (column.trim().to_string(), field.clone()))
+            .collect(),
+    };
+    update_item(&db, &mapping)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    let reply = Reply::default();
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+pub async fn delete_mapping(
+    session: Session,
+    form: web::Json<DeleteMappingForm>,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+    check_target(&form.target)?;
+
+    let mapping = get_mapping(&db, &form.target, &form.name).await?;
+    delete_item(&db, &mapping)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+
+    let reply = Reply::default();
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+/// Returns the columns and the first rows of a file with the fields they can
+/// be mapped to.
+pub async fn preview_import(
+    session: Session,
+    form: web::Query<PreviewForm>,
+    payload: Multipart,
+    data: web::Data<Mutex<ClientHolder>>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    debug!("{:?}", form);
+
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
+    check_target(&form.target)?;
+
+    let file = save_file(payload)
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    let (headers, records) =
+        read_table(&file.path).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let (headers, records) = split_header(form.has_header != "false", headers, records);
+
+    let reply = Reply {
+        import_fields: target_field_names(&db, &form.target).await,
+        preview_headers: headers.iter().map(String::from).collect(),
+        preview_rows: records
+            .iter()
+            .take(NUM_PREVIEW_ROWS)
+            .map(|record| record.iter().map(String::from).collect())
+            .collect(),
+        ..Default::default()
+    };
+    Ok(HttpResponse::Ok().json(reply))
+}
@@ -1,5 +1,10 @@
 use crate::views::path::Path;
 use actix_web::web;
+mod columns;
+mod job;
+mod json_lines;
+mod loans;
+mod mapping;
 mod setting;
 
 pub fn setting_factory(app: &mut web::ServiceConfig) {
@@ -22,6 +27,10 @@ pub fn setting_factory(app: &mut web::ServiceConfig) {
         &base_path.define(String::from("/setting/barcode")),
         web::put().to(setting::update_barcode_setting),
     )
+    .route(
+        &base_path.define(String::from("/setting/custom_field")),
+        web::put().to(setting::update_custom_field_setting),
+    )
     .route(
         &base_path.define(String::from("/user/profile/csv")),
         web::post().to(setting::import_user_list),
@@ -29,5 +38,49 @@ pub fn setting_factory(app: &mut web::ServiceConfig) {
     .route(
         &base_path.define(String::from("/book/profile/csv")),
         web::post().to(setting::import_book_list),
+    )
+    .route(
+        &base_path.define(String::from("/user/profile/json")),
+        web::post().to(json_lines::import_user_json),
+    )
+    .route(
+        &base_path.define(String::from("/book/profile/json")),
+        web::post().to(json_lines::import_book_json),
+    )
+    .route(
+        &base_path.define(String::from("/history/import")),
+        web::post().to(loans::import_loan_list),
+    )
+    .route(
+        &base_path.define(String::from("/history/import/json")),
+        web::post().to(json_lines::import_history_json),
+    )
+    .route(
+        &base_path.define(String::from("/import/mapping")),
+        web::get().to(mapping::get_mappings),
+    )
+    .route(
+        &base_path.define(String::from("/import/mapping")),
+        web::put().to(mapping::save_mapping),
+    )
+    .route(
+        &base_path.define(String::from("/import/mapping")),
+        web::delete().to(mapping::delete_mapping),
+    )
+    .route(
+        &base_path.define(String::from("/import/preview")),
+        web::post().to(mapping::preview_import),
+    )
+    .route(
+        &base_path.define(String::from("/import/job")),
+        web::get().to(job::get_import_job),
+    )
+    .route(
+        &base_path.define(String::from("/import/job/errors")),
+        web::get().to(job::export_import_errors),
+    )
+    .route(
+        &base_path.define(String::from("/import/job/cancel")),
+        web::post().to(job::cancel_import_job),
     );
 }
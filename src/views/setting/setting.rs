@@ -1,8 +1,12 @@
 use crate::error::*;
-use crate::item::{insert_item, search_item, search_items, update_item, Book, SystemSetting, User};
-use crate::item::{BarcodeSetting, RentalSetting};
-use crate::views::content_loader::read_csv;
+use crate::item::atoi;
+use crate::item::{search_items, search_items_or_empty, update_item};
+use crate::item::{BarcodeSetting, DuplicateIndex, RentalSetting};
+use crate::item::{Book, Entity, SystemSetting, User};
+use crate::item::{CustomField, CustomFieldSetting};
 use crate::views::content_loader::read_file;
+use crate::views::content_loader::read_table;
+use crate::views::custom_field::get_custom_field_setting;
 use crate::views::reply::Reply;
 use crate::views::session::check_operator_session;
 use actix_multipart::Multipart;
@@ -17,10 +21,18 @@ use std::collections::HashMap;
 use std::io::Write;
 use std::sync::Mutex;
 extern crate sanitize_filename;
+use crate::views::change_log::Tracked;
 use crate::views::db_helper::get_db;
-use futures::future::join_all;
+use crate::views::id_allocator::{get_barcode_setting, IdAllocator};
+use crate::views::import_job::ImportJobs;
+use crate::views::setting::columns::{field_names, map_headers, Change, Importable, Target};
+use crate::views::setting::job::{start_import_job, ImportRow};
+use crate::views::setting::mapping::{apply_mapping, get_mapping};
+use crate::views::utils;
+use crate::views::utils::generate_token;
 use std::error;
-use std::io::{Error, ErrorKind};
+use std::future::Future;
+use std::io::Error;
 
 pub async fn load() -> HttpResponse {
     let html_data = read_file("src/html/setting.html").unwrap();
@@ -41,6 +53,22 @@ pub struct UpdateBarcodeSettingForm {
     pub user_keta_max: String,
     pub book_keta_min: String,
     pub book_keta_max: String,
+    #[serde(default)]
+    pub check_digit: String,
+    #[serde(default)]
+    pub user_prefix: String,
+    #[serde(default)]
+    pub book_prefix: String,
+    #[serde(default)]
+    pub user_id_min: String,
+    #[serde(default)]
+    pub user_id_max: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UpdateCustomFieldSettingForm {
+    pub user_fields: String,
+    pub book_fields: String,
 }
 
 pub async fn update_rental_setting(
@@ -108,9 +136,12 @@ pub async fn get_setting(
     }
     let barcode_setting = barcode_setting.pop().unwrap();
 
+    let custom_field_setting = get_custom_field_setting(&db).await;
+
     let mut reply = Reply::default();
     reply.rental_setting = rental_setting;
     reply.barcode_setting = barcode_setting;
+    reply.custom_field_setting = custom_field_setting;
 
     Ok(HttpResponse::Ok().json(reply))
 }
@@ -125,17 +156,26 @@ pub async fn update_barcode_setting(
     check_operator_session(&session)?;
     let db = get_db(&data, &session).await?;
 
-    let setting = match BarcodeSetting::new(
+    let mut setting = match BarcodeSetting::new(
         &form.user_keta_min,
         &form.user_keta_max,
         &form.book_keta_min,
         &form.book_keta_max,
+        &form.check_digit,
     ) {
         Ok(setting) => setting,
         Err(e) => {
             return Err(BibErrorResponse::InvalidArgument(e.to_string()));
         }
     };
+    setting
+        .set_scan_rules(
+            &form.user_prefix,
+            &form.book_prefix,
+            &form.user_id_min,
+            &form.user_id_max,
+        )
+        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
 
     match update_item(&db, &setting).await {
         Ok(setting) => setting,
@@ -149,176 +189,375 @@ pub async fn update_barcode_setting(
     Ok(HttpResponse::Ok().json(reply))
 }
 
-async fn save_file(mut payload: Multipart) -> Result<String, Box<dyn error::Error>> {
-    if let Ok(Some(mut field)) = payload.try_next().await {
-        let content_type = field.content_disposition().ok_or("content_type error")?;
-        let filename = content_type.get_filename().ok_or("filename error")?;
-        let file_path = format!("/tmp/{}", sanitize_filename::sanitize(&filename));
-        info!("{}", file_path);
-        let returned_file_path = file_path.clone();
-
-        let mut f = web::block(|| std::fs::File::create(file_path)).await?;
-
-        while let Some(chunk) = field.next().await {
-            let data = chunk?;
-            f = web::block(move || f.write_all(&data).map(|_| f)).await?;
-        }
-        return Ok(returned_file_path);
-    }
-    Err(Box::new(Error::new(ErrorKind::Other, "Playload not found")))
-}
-
-pub async fn import_user_list(
+pub async fn update_custom_field_setting(
     session: Session,
-    payload: Multipart,
+    form: web::Json<UpdateCustomFieldSettingForm>,
     data: web::Data<Mutex<ClientHolder>>,
-    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
 ) -> Result<HttpResponse, BibErrorResponse> {
-    let dbname = check_operator_session(&session)?;
-    let db = get_db(&data, &session).await?;
+    debug!("{:?}", form);
 
-    let setting_map = setting_map.lock().unwrap();
-    let setting = setting_map.get(&dbname);
-    if setting.is_none() {
-        return Err(BibErrorResponse::NotAuthorized);
-    }
-    let setting = setting.unwrap().clone();
-    drop(setting_map);
+    check_operator_session(&session)?;
+    let db = get_db(&data, &session).await?;
 
-    let file_path = match save_file(payload).await {
-        Ok(file_path) => file_path,
+    let setting = match CustomFieldSetting::new(&form.user_fields, &form.book_fields) {
+        Ok(setting) => setting,
         Err(e) => {
-            return Err(BibErrorResponse::SystemError(e.to_string()));
+            return Err(BibErrorResponse::InvalidArgument(e.to_string()));
         }
     };
 
-    let records = match read_csv(&file_path) {
-        Ok(records) => records,
+    match update_item(&db, &setting).await {
+        Ok(setting) => setting,
         Err(e) => {
-            return Err(BibErrorResponse::SystemError(e.to_string()));
+            database::disconnect(&data);
+            return Err(BibErrorResponse::DataNotFound(e.to_string()));
         }
-    };
+    }
 
-    // Check number of items that can be registered at once
-    let nrecords: u32 = records.len().try_into().unwrap();
-    if nrecords > setting.max_parallel_registrations {
-        return Err(BibErrorResponse::ExceedLimitInParallel(
-            setting.max_parallel_registrations,
-        ));
+    let reply = Reply::default();
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImportListForm {
+    #[serde(default)]
+    pub allow_duplicate: String,
+    #[serde(default)]
+    pub dry_run: String,
+    #[serde(default)]
+    pub mode: String,
+    #[serde(default)]
+    pub mapping: String,
+}
+
+/// Returns the line number of a record in the file, counting the header.
+pub fn row_number(record: &csv::StringRecord, index: usize) -> usize {
+    match record.position() {
+        Some(position) => position.line() as usize,
+        None => index + 2,
     }
+}
 
-    // Check the number of items
-    let mut user = User::default();
-    user.id = 0;
-    let users = match search_items(&db, &user).await {
-        Ok(users) => users,
-        Err(_) => vec![],
-    };
-    let nusers: u32 = users.len().try_into().unwrap();
-    let nsize = nusers + nrecords;
-    if nsize > setting.max_registered_users {
-        return Err(BibErrorResponse::ExceedLimit(nsize));
+pub fn import_error(row: usize, field: &str, message: impl ToString) -> ImportError {
+    ImportError {
+        row,
+        field: field.to_string(),
+        message: message.to_string(),
     }
+}
 
-    // Check the paramters
-    let mut map: HashMap<u32, bool> = HashMap::new();
-    let mut users = vec![];
-    for i in 0..records.len() {
-        let record = &records[i];
-        let num_field = record.len();
-        if num_field != 7 {
-            return Err(BibErrorResponse::InvalidArgument(format!(
-                "The number of fields is {}",
-                num_field
-            )));
+/// Names the columns of a file in the fixed layout of the export by their
+/// positions. The custom fields at the end may be left out.
+fn positional_columns<T: Importable>(
+    custom_fields: &[CustomField],
+    records: Vec<csv::StringRecord>,
+) -> (csv::StringRecord, Vec<csv::StringRecord>) {
+    let names = field_names::<T>(custom_fields);
+    let num_fixed = names.len() - custom_fields.len();
+    let records = records
+        .into_iter()
+        .map(|mut record| {
+            if record.len() == num_fixed {
+                custom_fields.iter().for_each(|_| record.push_field(""));
+            }
+            record
+        })
+        .collect();
+    (names.into_iter().collect(), records)
+}
+
+/// The rows of a file imported by the column names.
+pub struct Upsert<T> {
+    pub rows: Vec<ImportRow<T>>,
+    pub assigned_ids: Vec<u32>,
+    pub errors: Vec<ImportError>,
+}
+
+/// Applies the columns present in the file to new records, or to the
+/// registered records if `allow_update` is set. The other fields are kept.
+pub fn prepare_upsert<T: Importable>(
+    table: &ImportTable,
+    custom_fields: &[CustomField],
+    registered: &[T],
+    allocator: &mut IdAllocator,
+    allow_update: bool,
+) -> Upsert<T> {
+    let mut upsert = Upsert {
+        rows: vec![],
+        assigned_ids: vec![],
+        errors: vec![],
+    };
+
+    let columns = T::columns();
+    let targets = match map_headers(&table.headers, &table.ignored, &columns, custom_fields) {
+        Ok(targets) => targets,
+        Err(unknown) => {
+            for name in unknown {
+                upsert.errors.push(import_error(1, &name, "Unknown column"));
+            }
+            return upsert;
         }
+    };
+    // New records are assigned IDs if the file has no ID column
+    let id_index = targets
+        .iter()
+        .position(|target| matches!(target, Target::Id));
+    if id_index.is_none() && allow_update {
+        upsert
+            .errors
+            .push(import_error(1, T::ID_COLUMN, "The column is missing"));
+        return upsert;
+    }
+    for record in &table.records {
+        let id = id_index.and_then(|id_index| record.get(id_index));
+        if let Some(id) = id.and_then(|id| atoi(id.trim()).ok()) {
+            allocator.reserve(id);
+        }
+    }
+
+    let mut registered: HashMap<u32, &T> =
+        registered.iter().map(|item| (item.id(), item)).collect();
+    let mut used_rows: HashMap<u32, usize> = HashMap::new();
+    for (i, record) in table.records.iter().enumerate() {
         debug!("{:?}", record);
-        let user = User::new(
-            &record[0], &record[1], &record[2], &record[3], &record[4], &record[5], &record[6],
-        )
-        .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
-        if map.insert(user.id, true).is_some() {
-            return Err(BibErrorResponse::DataDuplicated(user.id));
+        let row = row_number(record, i);
+        // Check all the fields present before skipping an invalid row
+        let num_errors = upsert.errors.len();
+        if record.len() != targets.len() {
+            upsert.errors.push(import_error(
+                row,
+                "",
+                format!("The number of fields is {}", record.len()),
+            ));
         }
-        match search_item(&db, &user).await {
-            Ok(user) => {
-                return Err(BibErrorResponse::DataDuplicated(user.id));
+
+        let id = id_index.and_then(|id_index| record.get(id_index));
+        let id = match id.unwrap_or("").trim() {
+            "" => allocator
+                .allocate()
+                .map(|id| (id, true))
+                .map_err(|e| e.to_string()),
+            id => atoi(id).map(|id| (id, false)).map_err(|e| e.to_string()),
+        };
+        let id = match id {
+            Ok(id) => Some(id),
+            Err(e) => {
+                upsert.errors.push(import_error(row, T::ID_COLUMN, e));
+                None
             }
-            Err(_) => {}
         };
-        users.push(user);
-    }
+        let mut before = None;
+        if let Some((id, _)) = id {
+            if let Some(other_row) = used_rows.insert(id, row) {
+                upsert.errors.push(import_error(
+                    row,
+                    T::ID_COLUMN,
+                    format!("ID{} is also used in row {}", id, other_row),
+                ));
+            }
+            before = registered.remove(&id).cloned();
+            if before.is_some() && !allow_update {
+                upsert.errors.push(import_error(
+                    row,
+                    T::ID_COLUMN,
+                    format!("ID{} is already registered", id),
+                ));
+            }
+        }
 
-    // Update the DB
-    let mut num_processed: usize = 0;
-    loop {
-        let mut futures = vec![];
-        loop {
-            if futures.len() as u32 == setting.num_threads || num_processed as u32 == nrecords {
-                break;
+        // The changes are applied to the snapshot to validate them, the job
+        // applies them again to the record read when it is written
+        let mut item = before.clone().unwrap_or_default();
+        let mut changes = vec![];
+        let absent = table.absent.get(i).map_or(&[][..], Vec::as_slice);
+        for (j, (target, value)) in targets.iter().zip(record.iter()).enumerate() {
+            if absent.contains(&j) {
+                continue;
+            }
+            match target {
+                Target::Field(column) => match (column.set)(&mut item, value) {
+                    Ok(()) => changes.push(Change::Field(**column, value.to_string())),
+                    Err(e) => upsert.errors.push(import_error(row, column.name, e)),
+                },
+                Target::Custom(field) => match field.validate(value) {
+                    Ok(value) => {
+                        if value.is_empty() {
+                            item.custom_fields_mut().remove(&field.name);
+                        } else {
+                            item.custom_fields_mut()
+                                .insert(field.name.clone(), value.clone());
+                        }
+                        changes.push(Change::Custom(field.name.clone(), value));
+                    }
+                    Err(e) => upsert.errors.push(import_error(row, &field.name, e)),
+                },
+                Target::Id | Target::Ignored => {}
             }
-            futures.push(insert_item(&db, &users[num_processed]));
-            num_processed += 1;
         }
-        let reses = join_all(futures).await;
-        for res in reses {
-            match res {
-                Err(e) => {
-                    database::disconnect(&data);
-                    return Err(BibErrorResponse::SystemError(e.to_string()));
-                }
-                Ok(_) => {}
+        if let (Some((id, _)), None) = (id, &before) {
+            item.set_id(id);
+            match item.validate_new() {
+                Ok(new_item) => item = new_item,
+                Err(e) => upsert.errors.push(import_error(row, "", e)),
             }
         }
-        if num_processed as u32 == nrecords {
-            break;
+        let (id, assigned) = match id {
+            Some(id) if upsert.errors.len() == num_errors => id,
+            _ => continue,
+        };
+
+        if assigned {
+            upsert.assigned_ids.push(id);
         }
+        upsert.rows.push(ImportRow {
+            row,
+            before,
+            item,
+            changes,
+        });
     }
+    upsert
+}
 
-    let reply = Reply::default();
-    Ok(HttpResponse::Ok().json(reply))
+/// Reports the new books that look like other copies of the registered books
+/// or of the preceding rows.
+pub fn find_duplicate_books(
+    registered: &[Book],
+    rows: &[ImportRow<Book>],
+    errors: &mut Vec<ImportError>,
+) {
+    let mut index = DuplicateIndex::new(registered);
+    for row in rows.iter().filter(|row| row.before.is_none()) {
+        let book = &row.item;
+        for (other_id, other_title) in index.find(book) {
+            errors.push(import_error(
+                row.row,
+                "",
+                format!("Same book as ID{} 「{}」", other_id, other_title),
+            ));
+        }
+        index.add(book);
+    }
 }
 
-async fn check_item(db: &Database, id: u32) -> Result<(), BibErrorResponse> {
-    let mut book = Book::default();
-    book.id = id;
+/// The columns and rows read from an imported file. `ignored` are the columns
+/// left unmapped. `absent` lists for each record the columns left out, which
+/// are kept as they are, and is empty for a table file. `errors` are the rows
+/// that could not be read.
+pub struct ImportTable {
+    pub headers: csv::StringRecord,
+    pub records: Vec<csv::StringRecord>,
+    pub ignored: Vec<usize>,
+    pub absent: Vec<Vec<usize>>,
+    pub errors: Vec<ImportError>,
+}
 
-    match search_item(&db, &book).await {
-        Ok(book) => Err(BibErrorResponse::DataDuplicated(book.id)),
-        Err(_) => Ok(()),
+/// Validates the rows against the registered records as the import job
+/// starts, so that the rows are written from what is registered then.
+pub async fn check_rows<T>(
+    db: &Database,
+    form: &ImportListForm,
+    setting: &SystemSetting,
+    custom_fields: &[CustomField],
+    table: ImportTable,
+) -> Result<Upsert<T>, BibErrorResponse>
+where
+    T: Importable + Entity,
+{
+    let registered = search_items_or_empty(db, &T::default())
+        .await
+        .map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    let (keta_min, keta_max) = T::id_digits(&get_barcode_setting(db).await?);
+    let mut allocator = IdAllocator::new(keta_min, keta_max, registered.iter().map(T::id));
+    let mut upsert = prepare_upsert(
+        &table,
+        custom_fields,
+        &registered,
+        &mut allocator,
+        form.mode == "upsert",
+    );
+    if form.allow_duplicate != "true" {
+        T::check_duplicates(&registered, &upsert.rows, &mut upsert.errors);
     }
+    upsert.errors.extend(table.errors);
+    upsert.errors.sort_by_key(|error| error.row);
+
+    let num_inserted = upsert
+        .rows
+        .iter()
+        .filter(|row| row.before.is_none())
+        .count();
+    let nsize: u32 = (registered.len() + num_inserted).try_into().unwrap();
+    if nsize > T::max_registered(setting) {
+        return Err(BibErrorResponse::ExceedLimit(nsize));
+    }
+    Ok(upsert)
 }
 
-pub async fn import_book_list(
+/// Saves the file and starts the job that checks it with `check` and writes
+/// the rows unless it is a dry run.
+pub async fn start_import<T, F, Fut>(
     session: Session,
+    form: ImportListForm,
     payload: Multipart,
     data: web::Data<Mutex<ClientHolder>>,
     setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
-) -> Result<HttpResponse, BibErrorResponse> {
+    jobs: web::Data<ImportJobs>,
+    check: F,
+) -> Result<HttpResponse, BibErrorResponse>
+where
+    T: Importable + Tracked + Entity + Send + Sync + 'static,
+    F: FnOnce(Database, ImportListForm, SystemSetting, UploadedFile) -> Fut,
+    Fut: Future<Output = Result<Upsert<T>, BibErrorResponse>> + 'static,
+{
+    debug!("{:?}", form);
+
     let dbname = check_operator_session(&session)?;
     let db = get_db(&data, &session).await?;
+    let setting = utils::get_setting(&dbname, &setting_map)?;
 
-    let setting_map = setting_map.lock().unwrap();
-    let setting = setting_map.get(&dbname);
-    if setting.is_none() {
-        return Err(BibErrorResponse::NotAuthorized);
-    }
-    let setting = setting.unwrap().clone();
-    drop(setting_map);
-
-    let file_path = match save_file(payload).await {
-        Ok(file_path) => file_path,
+    let file = match save_file(payload).await {
+        Ok(file) => file,
         Err(e) => {
             error!("{:?}", e);
             return Err(BibErrorResponse::SystemError(e.to_string()));
         }
     };
 
-    let records = match read_csv(&file_path) {
-        Ok(records) => records,
-        Err(e) => {
-            error!("{:?}", e);
-            return Err(BibErrorResponse::SystemError(e.to_string()));
+    let dry_run = form.dry_run == "true";
+    let check = check(db.clone(), form, setting.clone(), file);
+    let reply = Reply {
+        import_job: start_import_job(&db, jobs, &session, &setting, dry_run, check).await?,
+        ..Default::default()
+    };
+    Ok(HttpResponse::Ok().json(reply))
+}
+
+/// Reads a CSV or Excel file by the column names, by a saved mapping or by
+/// the positions of the columns. The file is deleted after the check.
+async fn check_list<T>(
+    db: Database,
+    form: ImportListForm,
+    setting: SystemSetting,
+    file: UploadedFile,
+) -> Result<Upsert<T>, BibErrorResponse>
+where
+    T: Importable + Tracked + Entity,
+{
+    let (headers, records) =
+        read_table(&file.path).map_err(|e| BibErrorResponse::SystemError(e.to_string()))?;
+    drop(file);
+    let custom_field_setting = get_custom_field_setting(&db).await;
+    let custom_fields = T::custom_field_defs(&custom_field_setting);
+    let (headers, records, ignored) = match form.mapping.as_str() {
+        "" if form.mode == "upsert" => (headers, records, vec![]),
+        "" => {
+            let (headers, records) = positional_columns::<T>(custom_fields, records);
+            (headers, records, vec![])
+        }
+        name => {
+            let mapping = get_mapping(&db, T::TARGET, name).await?;
+            apply_mapping(&mapping, headers, records)
         }
     };
 
@@ -330,106 +569,93 @@ pub async fn import_book_list(
         ));
     }
 
-    // Check the number of items
-    let mut book = Book::default();
-    book.id = 0;
-    let books = match search_items(&db, &book).await {
-        Ok(books) => books,
-        Err(_) => vec![],
+    let table = ImportTable {
+        headers,
+        records,
+        ignored,
+        absent: vec![],
+        errors: vec![],
     };
-    let nbooks: u32 = books.len().try_into().unwrap();
-    let nsize = nbooks + nrecords;
-    if nsize > setting.max_registered_books {
-        return Err(BibErrorResponse::ExceedLimit(nsize));
-    }
+    check_rows(&db, &form, &setting, custom_fields, table).await
+}
 
-    // Check the parameter
-    let mut map: HashMap<u32, bool> = HashMap::new();
-    let mut books = vec![];
-    let num_books = records.len();
-    let mut num_processed: usize = 0;
-
-    loop {
-        let mut futures = vec![];
-        loop {
-            if futures.len() as u32 == setting.num_threads || num_processed == num_books {
-                break;
-            }
-            let record = &records[num_processed];
-            let num_field = record.len();
-            if num_field != 20 {
-                return Err(BibErrorResponse::InvalidArgument(format!(
-                    "The number of fields is {}",
-                    num_field
-                )));
-            }
-            debug!("{:?}", record);
-            let book = Book::new(
-                &record[0],  // id
-                &record[1],  // title
-                &record[2],  // location
-                &record[3],  // category
-                &record[4],  // status
-                &record[5],  // author
-                &record[6],  // publisher
-                &record[7],  // published_date
-                &record[8],  // series
-                &record[9],  // volume
-                &record[10], // page
-                &record[11], // kana
-                &record[12], // category_symbol
-                &record[13], // library_symbol
-                &record[14], // volume_symbol
-                &record[15], // forbidden
-                &record[16], // remark
-                &record[17], // isbn
-                &record[18], // register_date
-                &record[19], // register_type
-            )
-            .map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
-            if map.insert(book.id, true).is_some() {
-                return Err(BibErrorResponse::DataDuplicated(book.id));
-            }
-            futures.push(check_item(&db, book.id));
-            num_processed += 1;
-            books.push(book);
-        }
-        let reses = join_all(futures).await;
-        for res in reses {
-            res?;
-        }
-        if num_processed == num_books {
-            break;
+/// An uploaded file saved under a name of its own, so that the uploads of
+/// the same file name do not overwrite each other. It is deleted when
+/// dropped.
+pub struct UploadedFile {
+    pub path: String,
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            error!("{}: {:?}", self.path, e);
         }
     }
+}
 
-    // Update the DB
-    num_processed = 0;
-    loop {
-        let mut futures = vec![];
-        loop {
-            if futures.len() as u32 == setting.num_threads || num_processed as u32 == nrecords {
-                break;
-            }
-            futures.push(insert_item(&db, &books[num_processed]));
-            num_processed += 1;
-        }
-        let reses = join_all(futures).await;
-        for res in reses {
-            match res {
-                Err(e) => {
-                    database::disconnect(&data);
-                    return Err(BibErrorResponse::SystemError(e.to_string()));
-                }
-                Ok(_) => {}
-            }
-        }
-        if num_processed as u32 == nrecords {
-            break;
+pub async fn save_file(mut payload: Multipart) -> Result<UploadedFile, Box<dyn error::Error>> {
+    if let Ok(Some(mut field)) = payload.try_next().await {
+        let content_type = field.content_disposition().ok_or("content_type error")?;
+        let filename = content_type.get_filename().ok_or("filename error")?;
+        // The extension tells the format of the file
+        let file_path = format!(
+            "/tmp/{}-{}",
+            generate_token(),
+            sanitize_filename::sanitize(filename)
+        );
+        info!("{}", file_path);
+        let file = UploadedFile {
+            path: file_path.clone(),
+        };
+
+        let mut f = web::block(|| std::fs::File::create(file_path)).await?;
+
+        while let Some(chunk) = field.next().await {
+            let data = chunk?;
+            f = web::block(move || f.write_all(&data).map(|_| f)).await?;
         }
+        return Ok(file);
     }
+    Err(Box::new(Error::other("Playload not found")))
+}
 
-    debug!("Done");
-    let reply = Reply::default();
-    Ok(HttpResponse::Ok().json(reply))
+pub async fn import_user_list(
+    session: Session,
+    form: web::Query<ImportListForm>,
+    payload: Multipart,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+    jobs: web::Data<ImportJobs>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    start_import(
+        session,
+        form.into_inner(),
+        payload,
+        data,
+        setting_map,
+        jobs,
+        check_list::<User>,
+    )
+    .await
+}
+
+pub async fn import_book_list(
+    session: Session,
+    form: web::Query<ImportListForm>,
+    payload: Multipart,
+    data: web::Data<Mutex<ClientHolder>>,
+    setting_map: web::Data<Mutex<HashMap<String, SystemSetting>>>,
+    jobs: web::Data<ImportJobs>,
+) -> Result<HttpResponse, BibErrorResponse> {
+    start_import(
+        session,
+        form.into_inner(),
+        payload,
+        data,
+        setting_map,
+        jobs,
+        check_list::<Book>,
+    )
+    .await
 }
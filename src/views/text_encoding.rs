@@ -0,0 +1,92 @@
+use encoding_rs::SHIFT_JIS;
+use std::error;
+use std::str::FromStr;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// The encodings of the exported text files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextEncoding {
+    // Shift_JIS with the Windows extensions, as saved by Excel
+    Cp932,
+    Utf8Bom,
+    Utf8,
+}
+
+impl FromStr for TextEncoding {
+    type Err = Box<dyn error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cp932" | "sjis" | "shift_jis" => Ok(TextEncoding::Cp932),
+            "utf8bom" => Ok(TextEncoding::Utf8Bom),
+            "" | "utf8" => Ok(TextEncoding::Utf8),
+            _ => Err(format!("Unsupported encoding: {}", s).into()),
+        }
+    }
+}
+
+impl TextEncoding {
+    /// Encodes the text. The characters missing in CP932 are written as
+    /// numeric character references.
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Cp932 => SHIFT_JIS.encode(text).0.into_owned(),
+            TextEncoding::Utf8Bom => [UTF8_BOM, text.as_bytes()].concat(),
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+        }
+    }
+
+    /// Encodes a part of the text that follows the beginning, which does not
+    /// take a BOM.
+    pub fn encode_continued(&self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8Bom => text.as_bytes().to_vec(),
+            _ => self.encode(text),
+        }
+    }
+}
+
+/// Decodes a text file in UTF-8, with or without a BOM, or in CP932.
+pub fn decode_text(data: &[u8]) -> Result<String, Box<dyn error::Error>> {
+    let data = data.strip_prefix(UTF8_BOM).unwrap_or(data);
+    if let Ok(text) = std::str::from_utf8(data) {
+        return Ok(text.to_string());
+    }
+
+    let (text, had_errors) = SHIFT_JIS.decode_without_bom_handling(data);
+    if had_errors {
+        return Err("The file is neither UTF-8 nor Shift_JIS".into());
+    }
+    Ok(text.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_text() {
+        assert_eq!(decode_text("氏名".as_bytes()).unwrap(), "氏名");
+        assert_eq!(
+            decode_text(&TextEncoding::Utf8Bom.encode("氏名")).unwrap(),
+            "氏名"
+        );
+        // The Windows extensions of CP932
+        let text = "髙橋①";
+        assert_eq!(
+            decode_text(&TextEncoding::Cp932.encode(text)).unwrap(),
+            text
+        );
+        assert!(decode_text(b"\x82").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(TextEncoding::Cp932.encode("A😀"), b"A&#128512;");
+        assert_eq!(TextEncoding::Utf8Bom.encode("A"), b"\xEF\xBB\xBFA");
+        assert_eq!(TextEncoding::Utf8Bom.encode_continued("A"), b"A");
+        assert_eq!(TextEncoding::from_str("").unwrap(), TextEncoding::Utf8);
+        assert!(TextEncoding::from_str("euc-jp").is_err());
+    }
+}
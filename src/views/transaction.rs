@@ -19,6 +19,47 @@ impl Transaction {
         }
     }
 
+    /// Advances the counter and returns the ID of a new transaction. The IDs
+    /// go round within `1..=max_counter` overwriting the oldest ones.
+    pub fn next_counter(&self) -> u32 {
+        let mut counter = self.counter.lock().unwrap();
+        self.advance(&mut counter)
+    }
+
+    fn advance(&self, counter: &mut u32) -> u32 {
+        *counter += 1;
+        let mut transaction_id = *counter % (self.max_counter + 1);
+        if transaction_id == 0 {
+            transaction_id = 1;
+        }
+        *counter = transaction_id;
+        transaction_id
+    }
+
+    /// Returns the IDs of `num` new transactions in a row.
+    pub fn reserve_counters(&self, num: usize) -> Vec<u32> {
+        let mut counter = self.counter.lock().unwrap();
+        (0..num).map(|_| self.advance(&mut counter)).collect()
+    }
+
+    /// Gives back the IDs taken by `reserve_counters` if no transaction has
+    /// been made since, so that the IDs are used again.
+    pub fn release_counters(&self, ids: &[u32]) {
+        let mut counter = self.counter.lock().unwrap();
+        if let (Some(first), Some(last)) = (ids.first(), ids.last()) {
+            if *counter == *last {
+                *counter = first - 1;
+            }
+        }
+    }
+
+    /// Returns the ID of the last transaction, from which the counter goes on.
+    pub async fn last_counter(db: &Database) -> u32 {
+        let item = TransactionItem::default();
+        let transaction_items = Transaction::search(db, &item).await;
+        transaction_items.last().map_or(0, |item| item.id)
+    }
+
     pub async fn search(db: &Database, item: &TransactionItem) -> Vec<TransactionItem> {
         debug!("{:?}", item);
         let items = match search_items(db, item).await {
@@ -74,3 +115,23 @@ impl Transaction {
         update_item(db, &item).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_counters() {
+        let transaction = Transaction::new(5, 3);
+        assert_eq!(transaction.reserve_counters(3), vec![4, 5, 1]);
+        transaction.release_counters(&[4, 5, 1]);
+        assert_eq!(transaction.next_counter(), 4);
+
+        // Not given back once another transaction has taken an ID
+        let ids = transaction.reserve_counters(2);
+        assert_eq!(ids, vec![5, 1]);
+        assert_eq!(transaction.next_counter(), 2);
+        transaction.release_counters(&ids);
+        assert_eq!(transaction.next_counter(), 3);
+    }
+}
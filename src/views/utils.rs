@@ -1,4 +1,4 @@
-use std::{env, sync::Mutex};
+use std::{collections::HashMap, env, sync::Mutex};
 
 use actix_web::web;
 use chrono::{DateTime, TimeZone, Utc};
@@ -103,6 +103,17 @@ pub fn generate_token() -> String {
     Uuid::new_v4().to_string()
 }
 
+/// Returns a copy of the setting of the tenant kept in memory.
+pub fn get_setting(
+    dbname: &str,
+    setting_map: &web::Data<Mutex<HashMap<String, SystemSetting>>>,
+) -> Result<SystemSetting, BibErrorResponse> {
+    match setting_map.lock().unwrap().get(dbname) {
+        Some(setting) => Ok(setting.clone()),
+        None => Err(BibErrorResponse::NotAuthorized),
+    }
+}
+
 pub fn get_nowtime(time_zone: &str) -> DateTime<Tz> {
     let utc = Utc::now().naive_utc();
 
@@ -1,9 +1,9 @@
 use crate::error::*;
 use crate::item::atoi;
-use crate::item::BarcodeSetting;
 use crate::item::RentalSetting;
 use crate::item::SystemSetting;
 use crate::item::{search_item, search_items, update_item};
+use crate::item::{BarcodeKind, BarcodeSetting};
 use crate::item::{Book, BorrowedBook, User};
 use crate::views::cache::*;
 use crate::views::db_helper::get_db;
@@ -21,11 +21,18 @@ use shared_mongodb::{database, ClientHolder};
 use std::collections::HashMap;
 use std::sync::Mutex;
 
+/// Either the barcodes in the separate fields, or a barcode in `scan` with
+/// the ID of the current user in `user_id`.
 #[derive(Deserialize, Debug)]
 pub struct ProcessWorkForm {
+    #[serde(default)]
     pub user_id: String,
+    #[serde(default)]
     pub borrowed_book_id: String,
+    #[serde(default)]
     pub returned_book_id: String,
+    #[serde(default)]
+    pub scan: String,
 }
 
 pub async fn process(
@@ -63,19 +70,44 @@ pub async fn process(
     }
     let barcode_setting = barcode_setting.pop().unwrap();
 
-    check_digits_of_user_barcodes(&barcode_setting, &form.user_id)?;
-    check_digits_of_book_barcodes(&barcode_setting, &form.borrowed_book_id)?;
-    check_digits_of_book_barcodes(&barcode_setting, &form.returned_book_id)?;
+    // Verify and remove the check digits before the IDs are looked up
+    let (user_id, borrowed_book_id, returned_book_id) = if form.scan.is_empty() {
+        (
+            strip_barcode(&barcode_setting, BarcodeKind::User, &form.user_id)?,
+            strip_barcode(&barcode_setting, BarcodeKind::Book, &form.borrowed_book_id)?,
+            strip_barcode(&barcode_setting, BarcodeKind::Book, &form.returned_book_id)?,
+        )
+    } else {
+        resolve_scan(
+            &barcode_setting,
+            &dbname,
+            &cache_map,
+            &form.scan,
+            form.user_id.trim(),
+        )?
+    };
+
+    check_digits_of_user_barcodes(&barcode_setting, &user_id)?;
+    check_digits_of_book_barcodes(&barcode_setting, &borrowed_book_id)?;
+    check_digits_of_book_barcodes(&barcode_setting, &returned_book_id)?;
+
+    // Tells the scanning page what the barcode was taken as
+    let scan_action = match (form.scan.as_str(), &borrowed_book_id, &returned_book_id) {
+        ("", _, _) => "",
+        (_, _, returned) if !returned.is_empty() => "return",
+        (_, borrowed, _) if !borrowed.is_empty() => "borrow",
+        _ => "user",
+    };
 
     let mut user = User::default();
-    if form.user_id == "" && form.borrowed_book_id == "" && form.returned_book_id != "" {
+    if user_id.is_empty() && borrowed_book_id.is_empty() && !returned_book_id.is_empty() {
         let (book_title, book_id) = unborrow_book(
             &db,
             &dbname,
             &cache_map,
             &transaction_map,
             &mut user,
-            &form.returned_book_id,
+            &returned_book_id,
             &system_setting.time_zone,
         )
         .await?;
@@ -83,10 +115,11 @@ pub async fn process(
         reply.returned_book_title = book_title;
         reply.returned_book_id = book_id;
         reply.user = user;
+        reply.scan_action = scan_action.to_string();
         return Ok(HttpResponse::Ok().json(reply));
     }
 
-    user.id = atoi(&form.user_id).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    user.id = atoi(&user_id).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
     let mut user = match search_item(&db, &user).await {
         Ok(user) => user,
         Err(_) => {
@@ -108,7 +141,7 @@ pub async fn process(
     }
     let rental_setting = rental_setting.pop().unwrap();
 
-    if form.borrowed_book_id != "" {
+    if !borrowed_book_id.is_empty() {
         // Create a DB session
         let mut session = start_transaction(&data)
             .await
@@ -120,7 +153,7 @@ pub async fn process(
             &cache_map,
             &transaction_map,
             &mut user,
-            &form.borrowed_book_id,
+            &borrowed_book_id,
             &system_setting.time_zone,
             rental_setting.num_books,
             rental_setting.num_days.into(),
@@ -146,7 +179,7 @@ pub async fn process(
         }
     }
 
-    if form.returned_book_id != "" {
+    if !returned_book_id.is_empty() {
         // Create a DB session
         let mut session = start_transaction(&data)
             .await
@@ -157,7 +190,7 @@ pub async fn process(
             &cache_map,
             &transaction_map,
             &mut user,
-            &form.returned_book_id,
+            &returned_book_id,
             &system_setting.time_zone,
         )
         .await;
@@ -183,6 +216,7 @@ pub async fn process(
 
     let mut reply = Reply::default();
     reply.user = user.clone();
+    reply.scan_action = scan_action.to_string();
     for book in user.borrowed_books {
         // Insert the new item at the front to sort in the order of the date
         reply.borrowed_books.insert(0, book.clone());
@@ -245,22 +279,14 @@ async fn borrow_book(
     }
 
     // Increment the transaction counter
-    let mut transaction_id;
+    let transaction_id;
     {
         let transaction_map = transaction_map.lock().unwrap();
         let transaction = transaction_map.get(dbname);
         if transaction.is_none() {
             return Err(BibErrorResponse::NotAuthorized);
         }
-        let transaction = transaction.unwrap();
-
-        let mut counter = transaction.counter.lock().unwrap();
-        *counter += 1;
-        transaction_id = *counter % (transaction.max_counter + 1);
-        if transaction_id == 0 {
-            transaction_id = 1;
-        }
-        *counter = transaction_id;
+        transaction_id = transaction.unwrap().next_counter();
     }
 
     let borrowed_book = BorrowedBook::new(
@@ -382,6 +408,75 @@ async fn unborrow_book(
     Ok((book.title, book.id))
 }
 
+fn strip_check_digit(setting: &BarcodeSetting, barcode: &str) -> Result<String, BibErrorResponse> {
+    setting
+        .check_digit
+        .strip(barcode)
+        .map(str::to_string)
+        .map_err(|e| BibErrorResponse::CheckDigitMismatch(e.to_string()))
+}
+
+/// Removes the prefix and the check digit of a barcode scanned into the field
+/// of the kind.
+fn strip_barcode(
+    setting: &BarcodeSetting,
+    kind: BarcodeKind,
+    barcode: &str,
+) -> Result<String, BibErrorResponse> {
+    match setting.split_prefix(barcode) {
+        (Some(prefixed), _) if prefixed != kind => {
+            Err(BibErrorResponse::InvalidArgument(barcode.to_string()))
+        }
+        (_, digits) => strip_check_digit(setting, digits),
+    }
+}
+
+/// Turns a scanned barcode into the user ID, the borrowed book ID and the
+/// returned book ID. A user card switches the current user, and a book label
+/// is returned if it is borrowed, or borrowed by the current user otherwise.
+fn resolve_scan(
+    setting: &BarcodeSetting,
+    dbname: &String,
+    cache_map: &web::Data<Mutex<HashMap<String, Cache>>>,
+    scan: &str,
+    current_user_id: &str,
+) -> Result<(String, String, String), BibErrorResponse> {
+    let (kind, barcode) = setting.split_prefix(scan.trim());
+    let digits = strip_check_digit(setting, barcode)?;
+    let id = atoi(&digits).map_err(|e| BibErrorResponse::InvalidArgument(e.to_string()))?;
+    let kind = match kind.or_else(|| setting.kind_of_id(id)) {
+        Some(kind) => kind,
+        None => {
+            return Err(BibErrorResponse::InvalidArgument(format!(
+                "{} cannot be told to be a user or a book",
+                scan
+            )))
+        }
+    };
+    if kind == BarcodeKind::User {
+        return Ok((digits, String::new(), String::new()));
+    }
+
+    let owner_id = {
+        let locked_cache_map = cache_map.lock().unwrap();
+        let cache = locked_cache_map.get(dbname);
+        if cache.is_none() {
+            return Err(BibErrorResponse::NotAuthorized);
+        }
+        cache.unwrap().get(id).map(|info| info.owner_id)
+    };
+    match owner_id {
+        // The book is returned with the current user kept
+        Some(owner_id) if atoi(current_user_id).is_ok_and(|user_id| user_id == owner_id) => {
+            Ok((current_user_id.to_string(), String::new(), digits))
+        }
+        // The book borrowed by another user is returned without the user
+        Some(_) => Ok((String::new(), String::new(), digits)),
+        None if current_user_id.is_empty() => Err(BibErrorResponse::BookNotBorrowed),
+        None => Ok((current_user_id.to_string(), digits, String::new())),
+    }
+}
+
 fn check_digits_of_user_barcodes(
     setting: &BarcodeSetting,
     data: &str,